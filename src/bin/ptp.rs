@@ -0,0 +1,622 @@
+//! `ptp`: poke at a camera from the command line.
+//!
+//! Run `ptp help` for usage. With `--json`, every command prints a single JSON document on
+//! stdout, except `events`, which prints one JSON object per line as events arrive.
+
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process;
+use std::time::{Duration, Instant};
+
+use ptp::fs::{DirEntry, PtpFs};
+use ptp::transfer::TransferMonitor;
+use ptp::{Error, ObjectTreeOptions, PtpCamera, PtpDataType, PtpEvent, PtpFormData, PtpIpTransport, PtpTransport,
+          StandardCommandCode, StandardDevicePropCode, StandardEventCode, StandardObjectFormatCode, UsbDeviceInfo,
+          UsbDeviceSelector};
+
+const USAGE: &str = "\
+usage: ptp [options] <command> [args]
+
+commands:
+  list-devices                  list attached USB PTP devices
+  info                          show the device info dataset
+  storages                      list stores and their capacity
+  ls [PATH]                     list a folder
+  tree                          list every object on every store
+  get REMOTE [LOCAL]            download an object
+  put LOCAL [REMOTE]            upload a file
+  rm REMOTE                     delete an object
+  props                         list device properties
+  props get PROP                show a device property
+  props set PROP VALUE          change a device property
+  capture                       take a picture and list the new objects
+  events [SECONDS]              print events as they arrive, until interrupted or SECONDS pass
+
+options:
+  --json                        machine-readable output
+  --device BUS:ADDRESS          USB device to use, instead of the first PTP device found
+  --serial SERIAL               USB device with this serial number
+  --usb VID:PID                 first USB device with this vendor and product ID, in hex
+  --ip HOST[:PORT]              connect over PTP/IP instead of USB
+  --storage ID                  store for ls, get, put, rm and capture, instead of the first one
+  --timeout SECONDS             timeout for each transaction, default 10
+
+PROP is a property name such as FNumber, or a code such as 0x5007.
+";
+
+struct Options {
+    json: bool,
+    device: UsbDeviceSelector,
+    ip: Option<String>,
+    storage: Option<u32>,
+    timeout: Option<Duration>,
+    command: String,
+    args: Vec<String>,
+}
+
+fn main() {
+    let options = match parse_args(env::args().skip(1).collect()) {
+        Ok(options) => options,
+        Err(msg) => {
+            eprintln!("ptp: {}\n\n{}", msg, USAGE);
+            process::exit(2);
+        }
+    };
+
+    if let Err(e) = run(&options) {
+        if options.json {
+            println!("{}", Json::obj(vec![("error", Json::Str(e.to_string()))]));
+        } else {
+            eprintln!("ptp: {}", e);
+        }
+        process::exit(1);
+    }
+}
+
+fn parse_args(args: Vec<String>) -> Result<Options, String> {
+    let mut options = Options {
+        json: false,
+        device: UsbDeviceSelector::Any,
+        ip: None,
+        storage: None,
+        timeout: Some(Duration::from_secs(10)),
+        command: String::new(),
+        args: vec![],
+    };
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or_else(|| format!("{} needs a value", name));
+        match &arg[..] {
+            "--json" => options.json = true,
+            "--device" => {
+                let v = value("--device")?;
+                let mut parts = v.splitn(2, ':').map(|p| p.parse::<u8>());
+                match (parts.next(), parts.next()) {
+                    (Some(Ok(bus)), Some(Ok(address))) => options.device = UsbDeviceSelector::BusAddress(bus, address),
+                    _ => return Err(format!("invalid device {:?}, expected BUS:ADDRESS", v)),
+                }
+            }
+            "--serial" => options.device = UsbDeviceSelector::Serial(value("--serial")?),
+            "--usb" => {
+                let v = value("--usb")?;
+                let mut parts = v.splitn(2, ':').map(|p| u16::from_str_radix(p, 16));
+                match (parts.next(), parts.next()) {
+                    (Some(Ok(vid)), Some(Ok(pid))) => options.device = UsbDeviceSelector::VidPid(vid, pid),
+                    _ => return Err(format!("invalid USB ID {:?}, expected VID:PID", v)),
+                }
+            }
+            "--ip" => options.ip = Some(value("--ip")?),
+            "--storage" => {
+                let v = value("--storage")?;
+                options.storage = Some(parse_u32(&v).ok_or_else(|| format!("invalid storage ID {:?}", v))?);
+            }
+            "--timeout" => {
+                let v = value("--timeout")?;
+                let secs = v.parse::<f64>().map_err(|_| format!("invalid timeout {:?}", v))?;
+                options.timeout = Some(Duration::from_millis((secs * 1000.0) as u64));
+            }
+            "-h" | "--help" => {
+                options.command = "help".to_owned();
+                return Ok(options);
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => {
+                options.command = arg;
+                options.args = args.collect();
+                break;
+            }
+        }
+    }
+
+    if options.command.is_empty() {
+        return Err("no command given".to_owned());
+    }
+    Ok(options)
+}
+
+fn run(options: &Options) -> Result<(), Error> {
+    match &options.command[..] {
+        "help" => {
+            print!("{}", USAGE);
+            return Ok(());
+        }
+        "list-devices" => return list_devices(options),
+        "info" | "storages" | "ls" | "tree" | "get" | "put" | "rm" | "props" | "capture" | "events" => {}
+        command => return Err(usage_error(&format!("unknown command {}", command))),
+    }
+
+    if let Some(ref addr) = options.ip {
+        let addr = if addr.contains(':') { addr.clone() } else { format!("{}:{}", addr, ptp::ptpip::PTPIP_PORT) };
+        let transport = PtpIpTransport::connect(&addr[..], [0; 16], "ptp", options.timeout)?;
+        return session(PtpCamera::with_transport(transport), options);
+    }
+
+    let context = rusb::Context::new()?;
+    let camera = PtpCamera::open(&context, &options.device)?;
+    session(camera, options)
+}
+
+fn session<T: PtpTransport>(mut camera: PtpCamera<T>, options: &Options) -> Result<(), Error> {
+    camera.open_session(options.timeout)?;
+
+    let result = match &options.command[..] {
+        "ls" | "get" | "put" | "rm" => {
+            let storage = match options.storage {
+                Some(id) => id,
+                None => *camera.get_storageids(options.timeout)?.first()
+                    .ok_or_else(|| Error::Malformed("The device has no stores".to_owned()))?,
+            };
+            let mut fs = PtpFs::new(camera, storage).timeout(options.timeout);
+            let result = path_command(&mut fs, options);
+            camera = fs.into_camera();
+            result
+        }
+        _ => command(&mut camera, options),
+    };
+
+    let closed = camera.close_session(options.timeout);
+    result.and(closed)
+}
+
+fn command<T: PtpTransport>(camera: &mut PtpCamera<T>, options: &Options) -> Result<(), Error> {
+    let timeout = options.timeout;
+    let out = Output { json: options.json };
+
+    match (&options.command[..], &options.args.iter().map(|s| &s[..]).collect::<Vec<_>>()[..]) {
+        ("info", []) => {
+            let info = camera.get_device_info(timeout)?;
+            let ops = |codes: &[u16], name: fn(u16) -> Option<&'static str>| {
+                Json::Arr(codes.iter().map(|&c| Json::Str(code_name(c, name))).collect())
+            };
+            out.doc(Json::obj(vec![
+                ("manufacturer", Json::Str(info.Manufacturer.clone())),
+                ("model", Json::Str(info.Model.clone())),
+                ("device_version", Json::Str(info.DeviceVersion.clone())),
+                ("serial_number", Json::Str(info.SerialNumber.clone())),
+                ("standard_version", Json::Int(info.Version as i128)),
+                ("vendor_extension_id", Json::Int(info.VendorExID as i128)),
+                ("vendor_extension_desc", Json::Str(info.VendorExtensionDesc.clone())),
+                ("operations", ops(&info.OperationsSupported, StandardCommandCode::name)),
+                ("events", ops(&info.EventsSupported, StandardEventCode::name)),
+                ("properties", ops(&info.DevicePropertiesSupported, StandardDevicePropCode::name)),
+                ("capture_formats", ops(&info.CaptureFormats, StandardObjectFormatCode::name)),
+                ("image_formats", ops(&info.ImageFormats, StandardObjectFormatCode::name)),
+            ]));
+        }
+        ("storages", []) => {
+            let mut stores = vec![];
+            for id in camera.get_storageids(timeout)? {
+                let info = camera.get_storage_info(id, timeout)?;
+                stores.push(Json::obj(vec![
+                    ("id", Json::Str(format!("0x{:08x}", id))),
+                    ("description", Json::Str(info.StorageDescription)),
+                    ("volume_label", Json::Str(info.VolumeLabel)),
+                    ("capacity", Json::Int(info.MaxCapacity as i128)),
+                    ("free", Json::Int(info.FreeSpaceInBytes as i128)),
+                    ("free_images", Json::Int(info.FreeSpaceInImages as i128)),
+                ]));
+            }
+            out.doc(Json::Arr(stores));
+        }
+        ("tree", []) => {
+            let mut objects = vec![];
+            for (storage, trees) in camera.object_trees(&ObjectTreeOptions::new(), timeout)? {
+                for (path, object) in trees.iter().flat_map(|t| t.walk()) {
+                    let entry = DirEntry { handle: object.handle, info: object.info };
+                    objects.push(entry_json(&format!("0x{:08x}/{}", storage, path), &entry));
+                }
+            }
+            out.doc(Json::Arr(objects));
+        }
+        ("props", []) => {
+            let mut props = vec![];
+            for code in camera.get_device_info(timeout)?.DevicePropertiesSupported {
+                match camera.get_device_prop_desc(code, timeout) {
+                    Ok(desc) => props.push(prop_json(&desc)),
+                    Err(e) => warn(&out, &format!("{}: {}", code_name(code, StandardDevicePropCode::name), e)),
+                }
+            }
+            out.doc(Json::Arr(props));
+        }
+        ("props", ["get", prop]) => {
+            let desc = camera.get_device_prop_desc(parse_prop(prop)?, timeout)?;
+            out.doc(prop_json(&desc));
+        }
+        ("props", ["set", prop, value]) => {
+            let code = parse_prop(prop)?;
+            let desc = camera.get_device_prop_desc(code, timeout)?;
+            let value = parse_value(desc.DataType, value)?;
+            camera.set_device_prop_value(code, &value, timeout)?;
+            out.doc(prop_json(&camera.get_device_prop_desc(code, timeout)?));
+        }
+        ("capture", []) => {
+            let mut capture = camera.initiate_capture(options.storage.unwrap_or(0), 0, timeout)?;
+            let handles = capture.wait(camera, Some(timeout.unwrap_or(Duration::from_secs(30))))?;
+            out.doc(Json::obj(vec![
+                ("objects", Json::Arr(handles.into_iter().map(|h| Json::Int(h as i128)).collect())),
+            ]));
+        }
+        ("events", args) if args.len() <= 1 => {
+            let deadline = match args.first() {
+                Some(secs) => {
+                    let secs = secs.parse::<u64>().map_err(|_| usage_error(&format!("invalid duration {:?}", secs)))?;
+                    Some(Instant::now() + Duration::from_secs(secs))
+                }
+                None => None,
+            };
+            while deadline.map_or(true, |d| Instant::now() < d) {
+                if let Some(event) = camera.poll_event(Some(Duration::from_millis(500)))? {
+                    out.line(event_json(&event));
+                }
+            }
+        }
+        (command, _) => return Err(usage_error(&format!("invalid use of {}", command))),
+    }
+    Ok(())
+}
+
+fn path_command<T: PtpTransport>(fs: &mut PtpFs<T>, options: &Options) -> Result<(), Error> {
+    let out = Output { json: options.json };
+
+    match (&options.command[..], &options.args.iter().map(|s| &s[..]).collect::<Vec<_>>()[..]) {
+        ("ls", args) if args.len() <= 1 => {
+            let path = args.first().cloned().unwrap_or("");
+            let entries = fs.read_dir(path)?;
+            out.doc(Json::Arr(entries.iter().map(|e| entry_json(e.name(), e)).collect()));
+        }
+        ("get", args) if !args.is_empty() && args.len() <= 2 => {
+            let remote = args[0];
+            let local = args.get(1).cloned().unwrap_or_else(|| remote.rsplit('/').next().unwrap_or(remote));
+            let entry = fs.stat(remote)?;
+            let mut file = File::create(local)?;
+            {
+                let mut monitor = progress_monitor(&out);
+                fs.camera().get_object_monitored(entry.handle, &mut file, &mut monitor, options.timeout)?;
+            }
+            out.done();
+            out.doc(Json::obj(vec![
+                ("handle", Json::Int(entry.handle as i128)),
+                ("path", Json::Str(local.to_owned())),
+                ("size", Json::Int(file.metadata()?.len() as i128)),
+            ]));
+        }
+        ("put", args) if !args.is_empty() && args.len() <= 2 => {
+            let local = args[0];
+            let name = Path::new(local).file_name().and_then(|n| n.to_str()).unwrap_or(local);
+            let remote = args.get(1).cloned().unwrap_or(name);
+            let mut file = File::open(local)?;
+            let len = file.metadata()?.len();
+            let handle = fs.create(remote, &mut file, len)?;
+            out.doc(Json::obj(vec![
+                ("handle", Json::Int(handle as i128)),
+                ("size", Json::Int(len as i128)),
+            ]));
+        }
+        ("rm", [remote]) => {
+            let handle = fs.resolve(remote)?;
+            fs.camera().delete_object(handle, options.timeout)?;
+            out.doc(Json::obj(vec![("handle", Json::Int(handle as i128))]));
+        }
+        (command, _) => return Err(usage_error(&format!("invalid use of {}", command))),
+    }
+    Ok(())
+}
+
+fn list_devices(options: &Options) -> Result<(), Error> {
+    let context = rusb::Context::new()?;
+    let devices = UsbDeviceInfo::list(&context)?.into_iter().map(|d| {
+        let string = |s: Option<String>| s.map(Json::Str).unwrap_or(Json::Null);
+        Json::obj(vec![
+            ("bus", Json::Int(d.bus as i128)),
+            ("address", Json::Int(d.address as i128)),
+            ("vendor_id", Json::Str(format!("{:04x}", d.vendor_id))),
+            ("product_id", Json::Str(format!("{:04x}", d.product_id))),
+            ("manufacturer", string(d.manufacturer)),
+            ("product", string(d.product)),
+            ("serial", string(d.serial)),
+            ("interface", Json::Int(d.interface as i128)),
+            ("mtp", Json::Bool(d.mtp)),
+        ])
+    }).collect();
+
+    Output { json: options.json }.doc(Json::Arr(devices));
+    Ok(())
+}
+
+fn progress_monitor<'a>(out: &Output) -> TransferMonitor<'a> {
+    if out.json {
+        return TransferMonitor::new();
+    }
+    TransferMonitor::new().on_progress(|p| {
+        let percent = p.fraction().map(|f| format!("{:5.1}%", f * 100.0)).unwrap_or_default();
+        eprint!("\r{} {} bytes, {:.1} MB/s", percent, p.done, p.bytes_per_second() / 1e6);
+    })
+}
+
+fn usage_error(msg: &str) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::InvalidInput, format!("{}, see ptp help", msg)))
+}
+
+fn parse_u32(s: &str) -> Option<u32> {
+    if let Some(hex) = s.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+fn parse_prop(s: &str) -> Result<u16, Error> {
+    if let Some(code) = parse_u32(s) {
+        return Ok(code as u16);
+    }
+    (0x5000..=0x5FFF).find(|&c| StandardDevicePropCode::name(c) == Some(s))
+        .ok_or_else(|| usage_error(&format!("unknown property {:?}", s)))
+}
+
+fn parse_value(data_type: u16, s: &str) -> Result<PtpDataType, Error> {
+    let invalid = || usage_error(&format!("invalid value {:?} for a property of type 0x{:04x}", s, data_type));
+    fn int<N: std::str::FromStr>(s: &str) -> Option<N> {
+        match s.strip_prefix("0x") {
+            // hex values go through u64 so they can be given for any integer type
+            Some(hex) => u64::from_str_radix(hex, 16).ok().and_then(|v| v.to_string().parse().ok()),
+            None => s.parse().ok(),
+        }
+    }
+    Ok(match data_type {
+        0x0001 => PtpDataType::INT8(int(s).ok_or_else(invalid)?),
+        0x0002 => PtpDataType::UINT8(int(s).ok_or_else(invalid)?),
+        0x0003 => PtpDataType::INT16(int(s).ok_or_else(invalid)?),
+        0x0004 => PtpDataType::UINT16(int(s).ok_or_else(invalid)?),
+        0x0005 => PtpDataType::INT32(int(s).ok_or_else(invalid)?),
+        0x0006 => PtpDataType::UINT32(int(s).ok_or_else(invalid)?),
+        0x0007 => PtpDataType::INT64(int(s).ok_or_else(invalid)?),
+        0x0008 => PtpDataType::UINT64(int(s).ok_or_else(invalid)?),
+        0xFFFF => PtpDataType::STR(s.to_owned()),
+        _ => return Err(invalid()),
+    })
+}
+
+fn code_name(code: u16, name: fn(u16) -> Option<&'static str>) -> String {
+    match name(code) {
+        Some(name) => name.to_owned(),
+        None => format!("0x{:04x}", code),
+    }
+}
+
+fn entry_json(path: &str, entry: &DirEntry) -> Json {
+    let info = &entry.info;
+    Json::obj(vec![
+        ("path", Json::Str(path.to_owned())),
+        ("handle", Json::Int(entry.handle as i128)),
+        ("format", Json::Str(code_name(info.ObjectFormat, StandardObjectFormatCode::name))),
+        ("size", Json::Int(info.ObjectCompressedSize as i128)),
+        ("capture_date", Json::Str(info.CaptureDate.clone())),
+        ("folder", Json::Bool(entry.is_dir())),
+    ])
+}
+
+fn prop_json(desc: &ptp::PtpPropInfo) -> Json {
+    let form = match desc.Form {
+        PtpFormData::None => Json::Null,
+        PtpFormData::Range { ref minValue, ref maxValue, ref step } => Json::obj(vec![
+            ("min", value_json(minValue)),
+            ("max", value_json(maxValue)),
+            ("step", value_json(step)),
+        ]),
+        PtpFormData::Enumeration { ref array } => Json::Arr(array.iter().map(value_json).collect()),
+    };
+    Json::obj(vec![
+        ("name", Json::Str(code_name(desc.PropertyCode, StandardDevicePropCode::name))),
+        ("code", Json::Str(format!("0x{:04x}", desc.PropertyCode))),
+        ("writable", Json::Bool(desc.GetSet == 1)),
+        ("current", value_json(&desc.Current)),
+        ("default", value_json(&desc.FactoryDefault)),
+        ("allowed", form),
+    ])
+}
+
+fn value_json(value: &PtpDataType) -> Json {
+    use ptp::PtpDataType::*;
+    fn arr<V: Copy + Into<i128>>(v: &[V]) -> Json {
+        Json::Arr(v.iter().map(|&x| Json::Int(x.into())).collect())
+    }
+    fn wide(v: (u64, u64)) -> Json {
+        Json::Str(format!("0x{:016x}{:016x}", v.1, v.0))
+    }
+    match *value {
+        UNDEF => Json::Null,
+        INT8(v) => Json::Int(v.into()),
+        UINT8(v) => Json::Int(v.into()),
+        INT16(v) => Json::Int(v.into()),
+        UINT16(v) => Json::Int(v.into()),
+        INT32(v) => Json::Int(v.into()),
+        UINT32(v) => Json::Int(v.into()),
+        INT64(v) => Json::Int(v.into()),
+        UINT64(v) => Json::Int(v.into()),
+        INT128(v) | UINT128(v) => wide(v),
+        AINT8(ref v) => arr(v),
+        AUINT8(ref v) => arr(v),
+        AINT16(ref v) => arr(v),
+        AUINT16(ref v) => arr(v),
+        AINT32(ref v) => arr(v),
+        AUINT32(ref v) => arr(v),
+        AINT64(ref v) => arr(v),
+        AUINT64(ref v) => arr(v),
+        AINT128(ref v) | AUINT128(ref v) => Json::Arr(v.iter().map(|&x| wide(x)).collect()),
+        STR(ref s) => Json::Str(s.clone()),
+    }
+}
+
+fn event_json(event: &PtpEvent) -> Json {
+    Json::obj(vec![
+        ("event", Json::Str(code_name(event.code, StandardEventCode::name))),
+        ("code", Json::Str(format!("0x{:04x}", event.code))),
+        ("transaction", Json::Int(event.tid as i128)),
+        ("params", Json::Arr(event.params.iter().map(|&p| Json::Int(p as i128)).collect())),
+    ])
+}
+
+fn warn(out: &Output, msg: &str) {
+    if !out.json {
+        eprintln!("ptp: {}", msg);
+    }
+}
+
+// prints documents either as JSON or as indented text
+struct Output {
+    json: bool,
+}
+
+impl Output {
+    fn doc(&self, doc: Json) {
+        if self.json {
+            println!("{}", doc);
+        } else {
+            let mut text = String::new();
+            doc.text(&mut text, 0);
+            print!("{}", text);
+        }
+    }
+
+    // one record of a stream, on a single line either way
+    fn line(&self, doc: Json) {
+        if self.json {
+            println!("{}", doc);
+        } else {
+            println!("{}", doc.plain());
+        }
+        io::stdout().flush().ok();
+    }
+
+    // end a line of progress output
+    fn done(&self) {
+        if !self.json {
+            eprintln!();
+        }
+    }
+}
+
+enum Json {
+    Null,
+    Bool(bool),
+    Int(i128),
+    Str(String),
+    Arr(Vec<Json>),
+    Obj(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn obj(fields: Vec<(&str, Json)>) -> Json {
+        Json::Obj(fields.into_iter().map(|(k, v)| (k.to_owned(), v)).collect())
+    }
+
+    // a value as a single line of text, without the JSON quoting of strings
+    fn plain(&self) -> String {
+        match *self {
+            Json::Str(ref s) => s.clone(),
+            Json::Obj(ref fields) => {
+                fields.iter().map(|(k, v)| format!("{}: {}", k, v.plain())).collect::<Vec<_>>().join(", ")
+            }
+            ref v => v.to_string(),
+        }
+    }
+
+    fn text(&self, out: &mut String, indent: usize) {
+        let pad = "  ".repeat(indent);
+        match *self {
+            Json::Arr(ref items) if items.iter().any(|i| matches!(*i, Json::Arr(_) | Json::Obj(_))) => {
+                for (n, item) in items.iter().enumerate() {
+                    if n > 0 && matches!(*item, Json::Obj(_)) && indent == 0 {
+                        out.push('\n');
+                    }
+                    item.text(out, indent);
+                }
+            }
+            Json::Obj(ref fields) => {
+                for (key, value) in fields {
+                    match *value {
+                        Json::Obj(_) => {
+                            out.push_str(&format!("{}{}:\n", pad, key));
+                            value.text(out, indent + 1);
+                        }
+                        _ => {
+                            out.push_str(&format!("{}{}: ", pad, key));
+                            value.text(out, 0);
+                        }
+                    }
+                }
+            }
+            Json::Str(ref s) => out.push_str(&format!("{}{}\n", pad, s)),
+            ref v => out.push_str(&format!("{}{}\n", pad, v)),
+        }
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Int(n) => write!(f, "{}", n),
+            Json::Str(ref s) => {
+                write!(f, "\"")?;
+                for c in s.chars() {
+                    match c {
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '\n' => write!(f, "\\n")?,
+                        '\r' => write!(f, "\\r")?,
+                        '\t' => write!(f, "\\t")?,
+                        c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                        c => write!(f, "{}", c)?,
+                    }
+                }
+                write!(f, "\"")
+            }
+            Json::Arr(ref items) => {
+                write!(f, "[")?;
+                for (n, item) in items.iter().enumerate() {
+                    if n > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Obj(ref fields) => {
+                write!(f, "{{")?;
+                for (n, (key, value)) in fields.iter().enumerate() {
+                    if n > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}:{}", Json::Str(key.clone()), value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
@@ -0,0 +1,521 @@
+//! Capturing new objects with InitiateCapture.
+//!
+//! `PtpCamera::initiate_capture` starts a capture and returns a `Capture`, which finds out what
+//! the capture produced. A responder that supports events announces each new object with
+//! ObjectAdded and ends the capture with CaptureComplete, both carrying the transaction ID of the
+//! InitiateCapture. For a responder that stays silent, the store is listed again until the
+//! objects that weren't there before stop changing:
+//!
+//! ```no_run
+//! # fn example<T: ptp::PtpTransport>(camera: &mut ptp::PtpCamera<T>) -> Result<(), ptp::Error> {
+//! use std::time::Duration;
+//!
+//! let mut capture = camera.initiate_capture(0, 0, None)?;
+//! for handle in capture.wait(camera, Some(Duration::from_secs(30)))? {
+//!     println!("captured {}", camera.get_objectinfo(handle, None)?.Filename);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! `PtpCamera::initiate_open_capture` does the same for captures that last until they are
+//! terminated, such as bulb exposures and video, returning a guard that terminates the capture
+//! when stopped or dropped:
+//!
+//! ```no_run
+//! # fn example<T: ptp::PtpTransport>(camera: &mut ptp::PtpCamera<T>) -> Result<(), ptp::Error> {
+//! use std::time::{Duration, Instant};
+//!
+//! let mut exposure = camera.initiate_open_capture(0, 0, None)?;
+//! let end = Instant::now() + Duration::from_secs(30);
+//! while Instant::now() < end && exposure.poll(Duration::from_millis(100))? {}
+//! let objects = exposure.stop(Some(Duration::from_secs(60)))?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! To be able to fall back on listing, the handles on the store are listed before the capture
+//! starts, which takes a while on a store holding many objects. That is skipped for responders
+//! whose DeviceInfo lists both ObjectAdded and CaptureComplete among the events they support; if
+//! one of those stays silent regardless, the store is listed when falling back, and objects the
+//! capture had already stored by then are missed.
+
+use std::collections::HashSet;
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{Error, PtpCamera, PtpEvent, PtpTransport, CommandCode, ObjectFormatCode, StandardCommandCode,
+            StandardEventCode, StandardResponseCode};
+
+// how long each wait for an event lasts, between checks of the deadline and the fallback
+const EVENT_POLL_MS: u64 = 100;
+
+// how often the store is listed once falling back
+const LIST_INTERVAL_MS: u64 = 500;
+
+/// A capture in progress, see `PtpCamera::initiate_capture`
+#[derive(Debug)]
+pub struct Capture {
+    tid: u32,
+    storage_id: u32,
+    // handles on the store before the capture started, or once falling back to listing for
+    // responders expected to announce the capture
+    before: Option<HashSet<u32>>,
+    objects: Vec<u32>,
+    complete: bool,
+    store_full: bool,
+    // whether the responder has sent any event for this capture
+    announced: bool,
+    fallback_after: Duration,
+    started: Instant,
+    // events for other transactions seen while waiting
+    other_events: Vec<PtpEvent>,
+}
+
+impl<T: PtpTransport> PtpCamera<T> {
+    /// Capture a new object of `format` on `storage_id`; either may be 0 to let the responder
+    /// choose. Returns once the responder has accepted the capture, which usually finishes later.
+    pub fn initiate_capture(&mut self,
+                            storage_id: u32,
+                            format: ObjectFormatCode,
+                            timeout: Option<Duration>)
+                            -> Result<Capture, Error> {
+        self.start_capture(StandardCommandCode::InitiateCapture, storage_id, format, timeout)
+    }
+
+    /// Start a capture that lasts until it is terminated, such as a bulb exposure or a video
+    /// recording. The capture ends when the returned guard is stopped or dropped.
+    pub fn initiate_open_capture(&mut self,
+                                 storage_id: u32,
+                                 format: ObjectFormatCode,
+                                 timeout: Option<Duration>)
+                                 -> Result<OpenCapture<'_, T>, Error> {
+        let capture = self.start_capture(StandardCommandCode::InitiateOpenCapture, storage_id, format, timeout)?;
+        Ok(OpenCapture {
+            camera: self,
+            capture,
+            timeout,
+            terminated: false,
+        })
+    }
+
+    fn start_capture(&mut self,
+                     code: CommandCode,
+                     storage_id: u32,
+                     format: ObjectFormatCode,
+                     timeout: Option<Duration>)
+                     -> Result<Capture, Error> {
+        // a responder that announces captures shouldn't need the store listed
+        let announces = self.supports_event(StandardEventCode::ObjectAdded, timeout)? &&
+                        self.supports_event(StandardEventCode::CaptureComplete, timeout)?;
+        let before = if announces {
+            None
+        } else {
+            Some(self.get_objecthandles_all(all_stores(storage_id), None, timeout)?.into_iter().collect())
+        };
+
+        let t = timeout.unwrap_or(Duration::new(0, 0));
+        let tid = self.begin_transaction(code, &[storage_id, format as u32], t)?;
+        self.finish_transaction(tid, t)?.check()?;
+        debug!("{} started, tid:{}", StandardCommandCode::name(code).unwrap_or("capture"), tid);
+
+        Ok(Capture {
+            tid,
+            storage_id,
+            before,
+            objects: vec![],
+            complete: false,
+            store_full: false,
+            announced: false,
+            fallback_after: Duration::from_secs(2),
+            started: Instant::now(),
+            other_events: vec![],
+        })
+    }
+}
+
+impl Capture {
+    /// ID of the InitiateCapture transaction, which the capture's events refer to
+    pub fn transaction_id(&self) -> u32 {
+        self.tid
+    }
+
+    /// handles of the objects the capture has produced so far
+    pub fn objects(&self) -> &[u32] {
+        &self.objects
+    }
+
+    /// whether the responder has reported the capture complete
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// how long `wait` waits for a first event before falling back to listing the store,
+    /// 2s by default
+    pub fn fallback_after(mut self, delay: Duration) -> Capture {
+        self.fallback_after = delay;
+        self
+    }
+
+    /// Take note of an event, returning whether it belonged to this capture. For callers that
+    /// read the event stream themselves rather than calling `wait`.
+    pub fn handle_event(&mut self, event: &PtpEvent) -> bool {
+        if event.tid != self.tid {
+            return false;
+        }
+
+        match event.code {
+            StandardEventCode::ObjectAdded => {
+                if let Some(&handle) = event.params.first() {
+                    if !self.objects.contains(&handle) {
+                        self.objects.push(handle);
+                    }
+                }
+            }
+            StandardEventCode::CaptureComplete => self.complete = true,
+            StandardEventCode::StoreFull => {
+                self.complete = true;
+                self.store_full = true;
+            }
+            _ => return false,
+        }
+        self.announced = true;
+        true
+    }
+
+    /// events unrelated to the capture that `wait` read from the camera, so they aren't lost
+    pub fn take_events(&mut self) -> Vec<PtpEvent> {
+        self.other_events.split_off(0)
+    }
+
+    /// Wait for the capture to finish, returning the handles of the new objects.
+    ///
+    /// If the responder sends no event for the capture within the fallback delay, the store is
+    /// listed instead, and the capture counts as finished once new objects have appeared and
+    /// a further listing shows no change. Without a `timeout` this waits indefinitely.
+    pub fn wait<T: PtpTransport>(&mut self, camera: &mut PtpCamera<T>, timeout: Option<Duration>) -> Result<Vec<u32>, Error> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let expired = || deadline.is_some_and(|d| Instant::now() >= d);
+
+        while !self.complete && (self.announced || self.started.elapsed() < self.fallback_after) {
+            if expired() {
+                return self.timed_out();
+            }
+            if let Some(event) = camera.poll_event(Some(Duration::from_millis(EVENT_POLL_MS)))? {
+                if !self.handle_event(&event) {
+                    self.other_events.push(event);
+                }
+            }
+        }
+
+        if !self.complete {
+            debug!("no events for capture tid:{}, listing the store instead", self.tid);
+            if self.before.is_none() {
+                let handles = camera.get_objecthandles_all(all_stores(self.storage_id), None, timeout)?;
+                self.before = Some(handles.into_iter().collect());
+            }
+            let mut last = None;
+            loop {
+                let new = self.listed_objects(camera, timeout)?;
+                if !new.is_empty() && last.as_ref() == Some(&new) {
+                    self.complete = true;
+                    break;
+                }
+                self.objects = new.clone();
+                last = Some(new);
+                if expired() {
+                    return self.timed_out();
+                }
+                thread::sleep(Duration::from_millis(LIST_INTERVAL_MS));
+            }
+        }
+
+        if self.store_full && self.objects.is_empty() {
+            return Err(Error::Response(StandardResponseCode::StoreFull));
+        }
+        Ok(self.objects.clone())
+    }
+
+    // handles on the store now that weren't before, in the order the responder lists them
+    fn listed_objects<T: PtpTransport>(&self, camera: &mut PtpCamera<T>, timeout: Option<Duration>) -> Result<Vec<u32>, Error> {
+        let handles = camera.get_objecthandles_all(all_stores(self.storage_id), None, timeout)?;
+        let before = self.before.as_ref();
+        Ok(handles.into_iter().filter(|h| !before.is_some_and(|before| before.contains(h))).collect())
+    }
+
+    // some responders announce objects but never complete; what they announced is still useful
+    fn timed_out(&self) -> Result<Vec<u32>, Error> {
+        if self.objects.is_empty() {
+            Err(Error::Io(io::Error::new(io::ErrorKind::TimedOut, "capture did not complete")))
+        } else {
+            warn!("capture tid:{} did not complete, returning the {} object(s) announced", self.tid, self.objects.len());
+            Ok(self.objects.clone())
+        }
+    }
+}
+
+/// An open capture in progress, see `PtpCamera::initiate_open_capture`
+///
+/// The capture is terminated by `stop`, or failing that when the guard is dropped. Either way a
+/// capture the responder has already ended, eg. because the store filled up, is not an error.
+pub struct OpenCapture<'c, T: PtpTransport> {
+    camera: &'c mut PtpCamera<T>,
+    capture: Capture,
+    timeout: Option<Duration>,
+    terminated: bool,
+}
+
+impl<'c, T: PtpTransport> OpenCapture<'c, T> {
+    /// ID of the InitiateOpenCapture transaction, which the capture's events refer to
+    pub fn transaction_id(&self) -> u32 {
+        self.capture.tid
+    }
+
+    /// handles of the objects the capture has produced so far
+    pub fn objects(&self) -> &[u32] {
+        &self.capture.objects
+    }
+
+    /// the camera, eg. to change properties while the capture runs
+    pub fn camera(&mut self) -> &mut PtpCamera<T> {
+        self.camera
+    }
+
+    /// Wait up to `timeout` for an event, taking note of any object the capture has created.
+    /// Returns false once the responder has ended the capture by itself.
+    pub fn poll(&mut self, timeout: Duration) -> Result<bool, Error> {
+        if let Some(event) = self.camera.poll_event(Some(timeout))? {
+            if !self.capture.handle_event(&event) {
+                self.capture.other_events.push(event);
+            }
+        }
+        Ok(!self.capture.complete)
+    }
+
+    /// events unrelated to the capture read while it ran, so they aren't lost
+    pub fn take_events(&mut self) -> Vec<PtpEvent> {
+        self.capture.take_events()
+    }
+
+    /// End the capture and wait up to `wait` for the responder to finish writing, returning
+    /// the handles of every object the capture created. See `Capture::wait`.
+    pub fn stop(mut self, wait: Option<Duration>) -> Result<Vec<u32>, Error> {
+        self.terminate()?;
+        // the fallback delay runs from the end of the capture, not the start
+        self.capture.started = Instant::now();
+        self.capture.wait(self.camera, wait)
+    }
+
+    fn terminate(&mut self) -> Result<(), Error> {
+        if self.terminated {
+            return Ok(());
+        }
+        self.terminated = true;
+
+        let response = self.camera.command_full(StandardCommandCode::TerminateOpenCapture, &[self.capture.tid], None, self.timeout)?;
+        match response.code {
+            StandardResponseCode::Ok => Ok(()),
+            StandardResponseCode::CaptureAlreadyTerminated => {
+                debug!("open capture tid:{} had already ended", self.capture.tid);
+                Ok(())
+            }
+            code => Err(Error::Response(code)),
+        }
+    }
+}
+
+impl<'c, T: PtpTransport> Drop for OpenCapture<'c, T> {
+    fn drop(&mut self) {
+        if let Err(e) = self.terminate() {
+            warn!("failed to terminate open capture tid:{}: {}", self.capture.tid, e);
+        }
+    }
+}
+
+// the store to list for a capture on `storage_id`: where the responder chooses, all of them
+fn all_stores(storage_id: u32) -> u32 {
+    if storage_id == 0 { 0xFFFFFFFF } else { storage_id }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::mock::MockTransport;
+
+    const EVENTS: &[u16] = &[StandardEventCode::ObjectAdded, StandardEventCode::CaptureComplete];
+
+    // a GetObjectHandles reply
+    fn handles(handles: &[u32]) -> Vec<u8> {
+        let mut data = (handles.len() as u32).to_le_bytes().to_vec();
+        for handle in handles {
+            data.extend_from_slice(&handle.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn events_matched_by_transaction() {
+        let mut mock = MockTransport::new();
+        mock.expect_device_info_events(&[], EVENTS).tid(0);
+        // no listing before the capture
+        mock.expect(StandardCommandCode::InitiateCapture, &[0, 0]).tid(1);
+        mock.push_event(StandardEventCode::ObjectAdded, 7, &[40]);
+        mock.push_event(StandardEventCode::ObjectAdded, 1, &[41]);
+        mock.push_event(StandardEventCode::CaptureComplete, 7, &[]);
+        mock.push_event(StandardEventCode::ObjectAdded, 1, &[42]);
+        mock.push_event(StandardEventCode::CaptureComplete, 1, &[]);
+        let mut camera = PtpCamera::with_transport(mock);
+
+        let mut capture = camera.initiate_capture(0, 0, None).unwrap();
+        assert_eq!(capture.transaction_id(), 1);
+        assert_eq!(capture.wait(&mut camera, Some(Duration::from_secs(5))).unwrap(), vec![41, 42]);
+        assert!(capture.is_complete());
+
+        // the other transaction's events are kept for the caller
+        let others = capture.take_events();
+        assert_eq!(others.iter().map(|e| (e.code, e.tid)).collect::<Vec<_>>(),
+                   vec![(StandardEventCode::ObjectAdded, 7), (StandardEventCode::CaptureComplete, 7)]);
+        camera.transport().verify();
+    }
+
+    #[test]
+    fn store_full_without_objects() {
+        let mut mock = MockTransport::new();
+        mock.expect_device_info_events(&[], EVENTS);
+        mock.expect(StandardCommandCode::InitiateCapture, &[0x00010001, 0]);
+        mock.push_event(StandardEventCode::StoreFull, 1, &[0x00010001]);
+        let mut camera = PtpCamera::with_transport(mock);
+
+        let mut capture = camera.initiate_capture(0x00010001, 0, None).unwrap();
+        assert!(matches!(capture.wait(&mut camera, Some(Duration::from_secs(5))),
+                         Err(Error::Response(StandardResponseCode::StoreFull))));
+    }
+
+    #[test]
+    fn listing_fallback() {
+        let mut mock = MockTransport::new();
+        // no events advertised, so the store is listed first
+        mock.expect_device_info_events(&[], &[]);
+        mock.expect(StandardCommandCode::GetObjectHandles, &[0xFFFFFFFF, 0, 0]).reply_data(&handles(&[1, 2]));
+        mock.expect(StandardCommandCode::InitiateCapture, &[0, 0]);
+        // the new object shows up, then the listing settles
+        mock.expect(StandardCommandCode::GetObjectHandles, &[0xFFFFFFFF, 0, 0]).reply_data(&handles(&[1, 2]));
+        mock.expect(StandardCommandCode::GetObjectHandles, &[0xFFFFFFFF, 0, 0]).reply_data(&handles(&[1, 2, 3]));
+        mock.expect(StandardCommandCode::GetObjectHandles, &[0xFFFFFFFF, 0, 0]).reply_data(&handles(&[1, 2, 3]));
+        let mut camera = PtpCamera::with_transport(mock);
+
+        let mut capture = camera.initiate_capture(0, 0, None).unwrap().fallback_after(Duration::new(0, 0));
+        assert_eq!(capture.wait(&mut camera, Some(Duration::from_secs(10))).unwrap(), vec![3]);
+        camera.transport().verify();
+    }
+
+    #[test]
+    fn listing_fallback_for_silent_announcer() {
+        let mut mock = MockTransport::new();
+        mock.expect_device_info_events(&[], EVENTS);
+        mock.expect(StandardCommandCode::InitiateCapture, &[0x00010001, 0]);
+        // only listed once the events fail to come
+        mock.expect(StandardCommandCode::GetObjectHandles, &[0x00010001, 0, 0]).reply_data(&handles(&[1]));
+        mock.expect(StandardCommandCode::GetObjectHandles, &[0x00010001, 0, 0]).reply_data(&handles(&[1, 5]));
+        mock.expect(StandardCommandCode::GetObjectHandles, &[0x00010001, 0, 0]).reply_data(&handles(&[1, 5]));
+        let mut camera = PtpCamera::with_transport(mock);
+
+        let mut capture = camera.initiate_capture(0x00010001, 0, None).unwrap().fallback_after(Duration::from_millis(100));
+        assert_eq!(capture.wait(&mut camera, Some(Duration::from_secs(10))).unwrap(), vec![5]);
+        camera.transport().verify();
+    }
+
+    #[test]
+    fn listing_times_out() {
+        let mut mock = MockTransport::new();
+        mock.expect_device_info_events(&[], &[]);
+        mock.expect(StandardCommandCode::GetObjectHandles, &[0xFFFFFFFF, 0, 0]).reply_data(&handles(&[1]));
+        mock.expect(StandardCommandCode::InitiateCapture, &[0, 0]);
+        mock.expect(StandardCommandCode::GetObjectHandles, &[0xFFFFFFFF, 0, 0]).reply_data(&handles(&[1]));
+        let mut camera = PtpCamera::with_transport(mock);
+
+        let mut capture = camera.initiate_capture(0, 0, None).unwrap().fallback_after(Duration::new(0, 0));
+        match capture.wait(&mut camera, Some(Duration::new(0, 0))) {
+            Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::TimedOut => {}
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    // a camera with an open capture started as transaction 1, announced with events
+    fn open_capture(mock: &mut MockTransport) {
+        mock.expect_device_info_events(&[], EVENTS);
+        mock.expect(StandardCommandCode::InitiateOpenCapture, &[0, 0]).tid(1);
+    }
+
+    #[test]
+    fn open_capture_stop() {
+        let mut mock = MockTransport::new();
+        open_capture(&mut mock);
+        mock.push_event(StandardEventCode::ObjectAdded, 1, &[20]);
+        mock.expect(StandardCommandCode::TerminateOpenCapture, &[1]);
+        let mut camera = PtpCamera::with_transport(mock);
+
+        let mut exposure = camera.initiate_open_capture(0, 0, None).unwrap();
+        assert!(exposure.poll(Duration::new(0, 0)).unwrap());
+        assert_eq!(exposure.objects(), &[20]);
+
+        // the last object and the end of the capture come after it is terminated
+        exposure.camera().transport().push_event(StandardEventCode::ObjectAdded, 1, &[21]);
+        exposure.camera().transport().push_event(StandardEventCode::CaptureComplete, 1, &[]);
+        assert_eq!(exposure.stop(Some(Duration::from_secs(5))).unwrap(), vec![20, 21]);
+        // terminated once, not again on drop
+        camera.transport().verify();
+    }
+
+    #[test]
+    fn open_capture_already_terminated() {
+        let mut mock = MockTransport::new();
+        open_capture(&mut mock);
+        // the store filled up and the responder ended the capture itself
+        mock.push_event(StandardEventCode::ObjectAdded, 1, &[20]);
+        mock.push_event(StandardEventCode::StoreFull, 1, &[0x00010001]);
+        mock.expect(StandardCommandCode::TerminateOpenCapture, &[1])
+            .respond(StandardResponseCode::CaptureAlreadyTerminated, &[]);
+        let mut camera = PtpCamera::with_transport(mock);
+
+        let mut exposure = camera.initiate_open_capture(0, 0, None).unwrap();
+        assert!(exposure.poll(Duration::new(0, 0)).unwrap());
+        assert!(!exposure.poll(Duration::new(0, 0)).unwrap());
+        assert_eq!(exposure.stop(Some(Duration::from_secs(5))).unwrap(), vec![20]);
+        camera.transport().verify();
+    }
+
+    #[test]
+    fn open_capture_terminated_on_drop() {
+        let mut mock = MockTransport::new();
+        open_capture(&mut mock);
+        mock.expect(StandardCommandCode::TerminateOpenCapture, &[1]);
+        let mut camera = PtpCamera::with_transport(mock);
+
+        {
+            let _exposure = camera.initiate_open_capture(0, 0, None).unwrap();
+        }
+        camera.transport().verify();
+
+        // a capture that already ended is not an error on drop either
+        camera.transport().expect(StandardCommandCode::InitiateOpenCapture, &[0, 0]).tid(3);
+        camera.transport().expect(StandardCommandCode::TerminateOpenCapture, &[3])
+            .respond(StandardResponseCode::CaptureAlreadyTerminated, &[]);
+        drop(camera.initiate_open_capture(0, 0, None).unwrap());
+        camera.transport().verify();
+    }
+
+    #[test]
+    fn open_capture_terminate_fails() {
+        let mut mock = MockTransport::new();
+        open_capture(&mut mock);
+        mock.expect(StandardCommandCode::TerminateOpenCapture, &[1]).respond(StandardResponseCode::GeneralError, &[]);
+        let mut camera = PtpCamera::with_transport(mock);
+
+        let exposure = camera.initiate_open_capture(0, 0, None).unwrap();
+        assert!(matches!(exposure.stop(None), Err(Error::Response(StandardResponseCode::GeneralError))));
+        // and isn't tried again on drop
+        camera.transport().verify();
+    }
+}
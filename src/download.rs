@@ -0,0 +1,363 @@
+//! Resumable downloads using GetPartialObject.
+//!
+//! `Downloader` fetches an object in chunks, recording how much has safely reached the
+//! destination in a small state file after every chunk. If the transfer is interrupted, by a
+//! USB disconnect or a timeout, calling `download` again with the same destination and state
+//! file (on a fresh `PtpCamera` if need be) continues from the last confirmed offset.
+//!
+//! Each chunk is synced to disk before the state file records it, so after a crash the state
+//! never claims more than the destination holds.
+//!
+//! Devices that don't support GetPartialObject get a plain GetObject, which can't be resumed.
+
+use std::cmp::min;
+use std::ffi::OsString;
+use std::fs;
+use std::io::prelude::*;
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::{Error, PtpCamera, PtpTransport, StandardCommandCode};
+
+/// How far a download got, as persisted in the state file
+#[derive(Debug, Clone, PartialEq)]
+pub struct DownloadState {
+    pub handle: u32,
+    /// object size, from ObjectCompressedSize
+    pub size: u64,
+    /// bytes confirmed written to the destination
+    pub offset: u64,
+    /// object filename, to recognise the object if handles were reassigned in the meantime
+    pub filename: String,
+}
+
+impl DownloadState {
+    /// Read a state file, returning `None` if there is none.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Option<DownloadState>, Error> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        // "<handle> <size> <offset> <filename>"
+        let mut fields = text.trim_end_matches('\n').splitn(4, ' ');
+        let mut number = || fields.next().and_then(|f| f.parse::<u64>().ok());
+        match (number(), number(), number()) {
+            (Some(handle), Some(size), Some(offset)) => Ok(Some(DownloadState {
+                handle: handle as u32,
+                size,
+                offset,
+                filename: fields.next().unwrap_or("").to_owned(),
+            })),
+            _ => Err(Error::Malformed(format!("Invalid download state {:?}", text))),
+        }
+    }
+
+    /// Write the state file, atomically replacing any previous one.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        // appended rather than replacing the extension, which may itself be "tmp"
+        let mut tmp = OsString::from(path.as_os_str());
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(format!("{} {} {} {}\n", self.handle, self.size, self.offset, self.filename).as_bytes())?;
+        // the new state must be on disk before it replaces the old
+        file.sync_data()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// Where a download is written: besides writing and seeking, what has been written can be made
+/// durable before the state file records it, and anything past the resume offset discarded.
+pub trait Destination: Write + Seek {
+    /// make everything written so far durable; by default this only flushes
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+
+    /// truncate or extend the destination to `len` bytes
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+}
+
+impl Destination for fs::File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        fs::File::set_len(self, len)
+    }
+}
+
+impl Destination for io::Cursor<Vec<u8>> {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.get_mut().resize(len as usize, 0);
+        Ok(())
+    }
+}
+
+/// Chunked, resumable object download
+#[derive(Debug, Clone)]
+pub struct Downloader {
+    chunk_size: u32,
+    state_file: Option<PathBuf>,
+}
+
+impl Default for Downloader {
+    fn default() -> Downloader {
+        Downloader::new()
+    }
+}
+
+impl Downloader {
+    pub fn new() -> Downloader {
+        Downloader {
+            chunk_size: 1024 * 1024,
+            state_file: None,
+        }
+    }
+
+    /// bytes to request per GetPartialObject, 1MB by default
+    pub fn chunk_size(mut self, chunk_size: u32) -> Downloader {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// where to persist progress. without one, a download can only be resumed within
+    /// the same `Downloader` call, ie. not at all.
+    pub fn state_file<P: Into<PathBuf>>(mut self, path: P) -> Downloader {
+        self.state_file = Some(path.into());
+        self
+    }
+
+    /// Download object `handle` into `dest`, resuming a previous attempt recorded in the state
+    /// file if it was for the same object. Returns the number of bytes in `dest`, which is cut
+    /// to that length. The state file is removed once the download completes.
+    pub fn download<T: PtpTransport, W: Destination>(&self,
+                                                      camera: &mut PtpCamera<T>,
+                                                      handle: u32,
+                                                      dest: &mut W,
+                                                      timeout: Option<Duration>)
+                                                      -> Result<u64, Error> {
+        let info = camera.get_objectinfo(handle, timeout)?;
+        let size = info.ObjectCompressedSize as u64;
+
+        // GetPartialObject offsets are 32 bits, so objects of 4GB and over (reported with a
+        // size of 0xFFFFFFFF) can't be fetched in pieces
+        let partial = size != 0xFFFFFFFF && camera.supports(StandardCommandCode::GetPartialObject, timeout)?;
+        if !partial {
+            debug!("downloading object {} without GetPartialObject", handle);
+            dest.set_len(0)?;
+            dest.seek(SeekFrom::Start(0))?;
+            let mut counter = Counter { inner: dest, count: 0 };
+            camera.get_object_to(handle, &mut counter, timeout)?;
+            counter.inner.sync()?;
+            self.clear_state()?;
+            return Ok(counter.count);
+        }
+
+        let mut state = DownloadState {
+            handle,
+            size,
+            offset: 0,
+            filename: info.Filename,
+        };
+        if let Some(ref path) = self.state_file {
+            if let Some(saved) = DownloadState::load(path)? {
+                // the destination may have been truncated, replaced or deleted since
+                let len = dest.seek(SeekFrom::End(0))?;
+                if saved.handle == state.handle && saved.size == state.size &&
+                   saved.filename == state.filename && saved.offset <= size && saved.offset <= len {
+                    debug!("resuming download of object {} at {}/{}", handle, saved.offset, size);
+                    state.offset = saved.offset;
+                } else {
+                    debug!("not resuming download of object {}: state {:?}, destination {} bytes", handle, saved, len);
+                }
+            }
+        }
+
+        // whatever lies past the offset is left over from an earlier attempt or another file
+        dest.set_len(state.offset)?;
+        dest.seek(SeekFrom::Start(state.offset))?;
+        while state.offset < size {
+            let max = min(size - state.offset, self.chunk_size as u64) as u32;
+            let data = camera.get_partialobject(handle, state.offset as u32, max, timeout)?;
+            if data.is_empty() {
+                return Err(Error::Malformed(format!("GetPartialObject returned no data at {}/{}", state.offset, size)));
+            }
+
+            dest.write_all(&data)?;
+            dest.sync()?;
+            state.offset += data.len() as u64;
+            if let Some(ref path) = self.state_file {
+                state.save(path)?;
+            }
+        }
+
+        self.clear_state()?;
+        Ok(state.offset)
+    }
+
+    fn clear_state(&self) -> Result<(), Error> {
+        if let Some(ref path) = self.state_file {
+            match fs::remove_file(path) {
+                Ok(()) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+// counts the bytes passing through to `inner`
+struct Counter<'w, W: Write> {
+    inner: &'w mut W,
+    count: u64,
+}
+
+impl<'w, W: Write> Write for Counter<'w, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{PtpObjectInfo, StandardResponseCode};
+    use super::super::mock::MockTransport;
+    use std::env;
+    use std::io::Cursor;
+
+    const OBJECT: &[u8] = b"0123456789";
+
+    fn camera(size: u32, operations: &[u16]) -> PtpCamera<MockTransport> {
+        let info = PtpObjectInfo { Filename: "IMG_0001.JPG".into(), ObjectCompressedSize: size, ..Default::default() };
+        let mut mock = MockTransport::new();
+        mock.expect(StandardCommandCode::GetObjectInfo, &[7]).reply_data(&info.encode());
+        mock.expect_device_info(operations);
+        PtpCamera::with_transport(mock)
+    }
+
+    fn expect_chunks(camera: &mut PtpCamera<MockTransport>, from: usize) {
+        for start in (from..OBJECT.len()).step_by(4) {
+            let end = min(start + 4, OBJECT.len());
+            camera.transport().expect(StandardCommandCode::GetPartialObject, &[7, start as u32, (end - start) as u32])
+                .reply_data(&OBJECT[start..end]);
+        }
+    }
+
+    // a state file path unique to the test, with no file at it yet
+    fn state_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("ptp-download-{}-{}.state", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn saved(offset: u64) -> DownloadState {
+        DownloadState { handle: 7, size: 10, offset, filename: "IMG_0001.JPG".into() }
+    }
+
+    #[test]
+    fn chunked() {
+        let state = state_path("chunked");
+        let mut camera = camera(10, &[StandardCommandCode::GetPartialObject]);
+        expect_chunks(&mut camera, 0);
+
+        // stale bytes past the end are cut off
+        let mut dest = Cursor::new(b"xxxxxxxxxxxxxxx".to_vec());
+        let n = Downloader::new().chunk_size(4).state_file(&state).download(&mut camera, 7, &mut dest, None).unwrap();
+        assert_eq!(n, 10);
+        assert_eq!(dest.into_inner(), OBJECT);
+        assert!(!state.exists());
+        camera.transport().verify();
+    }
+
+    #[test]
+    fn resume() {
+        let state = state_path("resume");
+        saved(4).save(&state).unwrap();
+        let mut camera = camera(10, &[StandardCommandCode::GetPartialObject]);
+        expect_chunks(&mut camera, 4);
+
+        let mut dest = Cursor::new(b"0123xx".to_vec());
+        Downloader::new().chunk_size(4).state_file(&state).download(&mut camera, 7, &mut dest, None).unwrap();
+        assert_eq!(dest.into_inner(), OBJECT);
+        assert!(!state.exists());
+        camera.transport().verify();
+    }
+
+    #[test]
+    fn mismatched_state_restarts() {
+        let state = state_path("mismatched");
+        DownloadState { filename: "IMG_0002.JPG".into(), ..saved(4) }.save(&state).unwrap();
+        let mut camera = camera(10, &[StandardCommandCode::GetPartialObject]);
+        expect_chunks(&mut camera, 0);
+
+        let mut dest = Cursor::new(b"abcdef".to_vec());
+        Downloader::new().chunk_size(4).state_file(&state).download(&mut camera, 7, &mut dest, None).unwrap();
+        assert_eq!(dest.into_inner(), OBJECT);
+        camera.transport().verify();
+    }
+
+    #[test]
+    fn short_destination_restarts() {
+        let state = state_path("short");
+        saved(8).save(&state).unwrap();
+        let mut camera = camera(10, &[StandardCommandCode::GetPartialObject]);
+        expect_chunks(&mut camera, 0);
+
+        // the partial file was truncated, or deleted and recreated, since the state was saved
+        let mut dest = Cursor::new(b"0123".to_vec());
+        Downloader::new().chunk_size(4).state_file(&state).download(&mut camera, 7, &mut dest, None).unwrap();
+        assert_eq!(dest.into_inner(), OBJECT);
+        camera.transport().verify();
+    }
+
+    #[test]
+    fn interrupted_keeps_state() {
+        let state = state_path("interrupted");
+        let mut camera = camera(10, &[StandardCommandCode::GetPartialObject]);
+        camera.transport().expect(StandardCommandCode::GetPartialObject, &[7, 0, 4]).reply_data(b"0123");
+        camera.transport().expect(StandardCommandCode::GetPartialObject, &[7, 4, 4])
+            .respond(StandardResponseCode::IncompleteTransfer, &[]);
+
+        let mut dest = Cursor::new(vec![]);
+        assert!(Downloader::new().chunk_size(4).state_file(&state).download(&mut camera, 7, &mut dest, None).is_err());
+        assert_eq!(DownloadState::load(&state).unwrap(), Some(saved(4)));
+        fs::remove_file(&state).unwrap();
+    }
+
+    #[test]
+    fn get_object_fallback() {
+        // without GetPartialObject
+        let mut camera = camera(10, &[StandardCommandCode::GetObject]);
+        camera.transport().expect(StandardCommandCode::GetObject, &[7]).reply_data(OBJECT);
+        let mut dest = Cursor::new(b"stale contents".to_vec());
+        assert_eq!(Downloader::new().download(&mut camera, 7, &mut dest, None).unwrap(), 10);
+        assert_eq!(dest.into_inner(), OBJECT);
+        camera.transport().verify();
+
+        // or with it, for an object too large for its 32-bit offsets
+        let info = PtpObjectInfo { Filename: "MVI_0001.MP4".into(), ObjectCompressedSize: 0xFFFFFFFF, ..Default::default() };
+        let mut mock = MockTransport::new();
+        mock.expect(StandardCommandCode::GetObjectInfo, &[7]).reply_data(&info.encode());
+        mock.expect(StandardCommandCode::GetObject, &[7]).reply_data(OBJECT);
+        let mut camera = PtpCamera::with_transport(mock);
+        let mut dest = Cursor::new(vec![]);
+        assert_eq!(Downloader::new().download(&mut camera, 7, &mut dest, None).unwrap(), 10);
+        assert_eq!(dest.into_inner(), OBJECT);
+        camera.transport().verify();
+    }
+}
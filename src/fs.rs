@@ -0,0 +1,567 @@
+//! Path-based access to the objects on a store.
+//!
+//! `PtpFs` resolves slash-separated paths such as `DCIM/100MSDCF/DSC00042.ARW` to object
+//! handles by listing associations with GetObjectHandles and matching `PtpObjectInfo::Filename`.
+//! Listings and object infos are cached, so repeated lookups under the same folder cost nothing.
+//! Before every lookup the pending events are read, and ObjectAdded, ObjectRemoved and the like
+//! drop the parts of the cache they make stale. Events read this way are kept for the caller,
+//! see `PtpFs::take_events`.
+//!
+//! Filenames are matched exactly; PTP doesn't define whether a store is case sensitive.
+
+use std::cmp::min;
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::io::{self, SeekFrom};
+use std::time::Duration;
+
+use super::{Error, PtpCamera, PtpTransport, PtpEvent, PtpObjectInfo, ObjectFormatCode, StandardCommandCode,
+            StandardEventCode, StandardObjectFormatCode};
+
+// GetObjectHandles and SendObjectInfo spell the root of a store as all ones,
+// while ObjectInfo.ParentObject uses zero
+const ROOT: u32 = 0xFFFFFFFF;
+
+// largest GetPartialObject request made by `PtpFile`
+const READ_CHUNK_SIZE: u32 = 1024 * 1024;
+
+/// An object found by path
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub handle: u32,
+    pub info: PtpObjectInfo,
+}
+
+impl DirEntry {
+    pub fn name(&self) -> &str {
+        &self.info.Filename
+    }
+
+    /// whether the object is an association, ie. a folder
+    pub fn is_dir(&self) -> bool {
+        self.info.ObjectFormat == StandardObjectFormatCode::Association
+    }
+}
+
+/// A camera store addressed by paths
+pub struct PtpFs<T: PtpTransport> {
+    camera: PtpCamera<T>,
+    storage_id: u32,
+    timeout: Option<Duration>,
+    event_timeout: Duration,
+    // children of each association, by name. the root is keyed as ROOT.
+    listings: HashMap<u32, Vec<(String, u32)>>,
+    infos: HashMap<u32, PtpObjectInfo>,
+    events: Vec<PtpEvent>,
+}
+
+impl<T: PtpTransport> PtpFs<T> {
+    /// Address the store `storage_id` of a camera with an open session.
+    /// 0xFFFFFFFF merges the roots of all stores, which only works for reading.
+    pub fn new(camera: PtpCamera<T>, storage_id: u32) -> PtpFs<T> {
+        PtpFs {
+            camera,
+            storage_id,
+            timeout: None,
+            event_timeout: Duration::from_millis(1),
+            listings: HashMap::new(),
+            infos: HashMap::new(),
+            events: vec![],
+        }
+    }
+
+    /// timeout for every transaction, unlimited by default
+    pub fn timeout(mut self, timeout: Option<Duration>) -> PtpFs<T> {
+        self.timeout = timeout;
+        self
+    }
+
+    /// how long to wait for events before each lookup, 1ms by default
+    pub fn event_timeout(mut self, timeout: Duration) -> PtpFs<T> {
+        self.event_timeout = timeout;
+        self
+    }
+
+    pub fn camera(&mut self) -> &mut PtpCamera<T> {
+        &mut self.camera
+    }
+
+    pub fn into_camera(self) -> PtpCamera<T> {
+        self.camera
+    }
+
+    /// events read while checking for cache invalidations, oldest first
+    pub fn take_events(&mut self) -> Vec<PtpEvent> {
+        ::std::mem::take(&mut self.events)
+    }
+
+    /// Drop everything cached.
+    pub fn invalidate(&mut self) {
+        self.listings.clear();
+        self.infos.clear();
+    }
+
+    /// Update the cache for an event read elsewhere, eg. by a caller using `camera().poll_event`.
+    pub fn handle_event(&mut self, event: &PtpEvent) {
+        let handle = event.params.first().cloned().unwrap_or(0);
+        match event.code {
+            StandardEventCode::ObjectAdded => {
+                // the new object's parent is unknown without another round trip
+                self.listings.clear();
+            }
+            StandardEventCode::ObjectRemoved | StandardEventCode::ObjectInfoChanged => {
+                self.listings.remove(&handle);
+                match self.infos.remove(&handle) {
+                    Some(info) => {
+                        self.listings.remove(&parent_key(info.ParentObject));
+                    }
+                    None => self.listings.retain(|_, children| children.iter().all(|&(_, h)| h != handle)),
+                }
+            }
+            StandardEventCode::StoreAdded | StandardEventCode::StoreRemoved |
+            StandardEventCode::DeviceReset | StandardEventCode::StorageInfoChanged => self.invalidate(),
+            _ => {}
+        }
+    }
+
+    /// Read pending events and apply them to the cache.
+    pub fn sync_events(&mut self) -> Result<(), Error> {
+        while let Some(event) = self.camera.poll_event(Some(self.event_timeout))? {
+            self.handle_event(&event);
+            self.events.push(event);
+        }
+        Ok(())
+    }
+
+    /// the handle of the object at `path`
+    pub fn resolve(&mut self, path: &str) -> Result<u32, Error> {
+        self.sync_events()?;
+
+        let mut handle = ROOT;
+        let mut walked = String::new();
+        for name in components(path) {
+            if handle != ROOT && self.info(handle)?.ObjectFormat != StandardObjectFormatCode::Association {
+                return Err(not_a_directory(&walked));
+            }
+            if !walked.is_empty() {
+                walked.push('/');
+            }
+            walked.push_str(name);
+
+            handle = self.listing(handle)?.iter()
+                .find(|(n, _)| n == name)
+                .map(|&(_, h)| h)
+                .ok_or_else(|| not_found(&walked))?;
+        }
+        Ok(handle)
+    }
+
+    /// the object at `path`. the root itself has no object, so `path` must name one.
+    pub fn stat(&mut self, path: &str) -> Result<DirEntry, Error> {
+        let handle = self.resolve(path)?;
+        if handle == ROOT {
+            return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidInput, "the root of a store has no object info")));
+        }
+        Ok(DirEntry { handle, info: self.info(handle)?.clone() })
+    }
+
+    /// the objects in the association at `path`, or at the root of the store for ""
+    pub fn read_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, Error> {
+        let handle = self.resolve(path)?;
+        if handle != ROOT && self.info(handle)?.ObjectFormat != StandardObjectFormatCode::Association {
+            return Err(not_a_directory(path));
+        }
+
+        let children = self.listing(handle)?.clone();
+        children.into_iter()
+            .map(|(_, h)| Ok(DirEntry { handle: h, info: self.info(h)?.clone() }))
+            .collect()
+    }
+
+    /// Open the object at `path` for reading. Reads and seeks are served with
+    /// GetPartialObject where the device supports it; otherwise, and for objects of 4GB
+    /// and over, the whole object is downloaded into memory on open. To copy such objects
+    /// without holding them in memory, stream them with `PtpCamera::get_object_to` instead,
+    /// using the handle from `stat`.
+    pub fn open(&mut self, path: &str) -> Result<PtpFile<'_, T>, Error> {
+        let entry = self.stat(path)?;
+        if entry.is_dir() {
+            return Err(Error::Io(io::Error::other(format!("{}: is a directory", path))));
+        }
+
+        let size = entry.info.ObjectCompressedSize;
+        let buffer = if size != 0xFFFFFFFF && self.camera.supports(StandardCommandCode::GetPartialObject, self.timeout)? {
+            None
+        } else {
+            Some(self.camera.get_object(entry.handle, self.timeout)?)
+        };
+        let size = buffer.as_ref().map(|b| b.len() as u64).unwrap_or(size as u64);
+
+        Ok(PtpFile { fs: self, handle: entry.handle, size, pos: 0, buffer })
+    }
+
+    /// Upload `len` bytes from `source` as a new object at `path`, whose parent association
+    /// must already exist. Returns the new object's handle.
+    pub fn create<R: Read>(&mut self, path: &str, source: &mut R, len: u64) -> Result<u32, Error> {
+        let (dir, name) = match path.trim_end_matches('/').rfind('/') {
+            Some(i) => (&path[..i], &path[i + 1..]),
+            None => ("", path),
+        };
+        let name = name.trim_end_matches('/');
+        if name.is_empty() {
+            return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidInput, format!("{}: no file name", path))));
+        }
+
+        let parent = self.resolve(dir)?;
+        if parent != ROOT && self.info(parent)?.ObjectFormat != StandardObjectFormatCode::Association {
+            return Err(not_a_directory(dir));
+        }
+        if self.listing(parent)?.iter().any(|(n, _)| n == name) {
+            return Err(Error::Io(io::Error::new(io::ErrorKind::AlreadyExists, format!("{}: already exists", path))));
+        }
+
+        let info = PtpObjectInfo {
+            ObjectFormat: format_for(name),
+            Filename: name.to_owned(),
+            ..Default::default()
+        };
+        // with all stores merged, let the responder pick one
+        let storage_id = if self.storage_id == 0xFFFFFFFF { 0 } else { self.storage_id };
+        let handle = self.camera.send_object_from(storage_id, parent, &info, source, len, self.timeout)?;
+
+        self.listings.remove(&parent);
+        Ok(handle)
+    }
+
+    /// Delete the object at `path`. Deleting an association deletes what it holds too.
+    pub fn remove(&mut self, path: &str) -> Result<(), Error> {
+        let entry = self.stat(path)?;
+        self.camera.delete_object(entry.handle, self.timeout)?;
+
+        if entry.is_dir() {
+            // whatever it held is gone too
+            self.invalidate();
+        } else {
+            self.infos.remove(&entry.handle);
+            self.listings.remove(&parent_key(entry.info.ParentObject));
+        }
+        Ok(())
+    }
+
+    fn info(&mut self, handle: u32) -> Result<&PtpObjectInfo, Error> {
+        if !self.infos.contains_key(&handle) {
+            let info = self.camera.get_objectinfo(handle, self.timeout)?;
+            self.infos.insert(handle, info);
+        }
+        Ok(&self.infos[&handle])
+    }
+
+    fn listing(&mut self, parent: u32) -> Result<&Vec<(String, u32)>, Error> {
+        if !self.listings.contains_key(&parent) {
+            let handles = self.camera.get_objecthandles(self.storage_id, parent, None, self.timeout)?;
+            let mut children = Vec::with_capacity(handles.len());
+            for handle in handles {
+                children.push((self.info(handle)?.Filename.clone(), handle));
+            }
+            self.listings.insert(parent, children);
+        }
+        Ok(&self.listings[&parent])
+    }
+}
+
+/// An object opened for reading with `PtpFs::open`
+pub struct PtpFile<'f, T: PtpTransport> {
+    fs: &'f mut PtpFs<T>,
+    handle: u32,
+    size: u64,
+    pos: u64,
+    // the whole object, if it had to be downloaded in one go
+    buffer: Option<Vec<u8>>,
+}
+
+impl<'f, T: PtpTransport> PtpFile<'f, T> {
+    pub fn handle(&self) -> u32 {
+        self.handle
+    }
+
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+}
+
+impl<'f, T: PtpTransport> Read for PtpFile<'f, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let n = match self.buffer {
+            Some(ref data) => {
+                let n = min(buf.len() as u64, self.size - self.pos) as usize;
+                buf[..n].copy_from_slice(&data[self.pos as usize..self.pos as usize + n]);
+                n
+            }
+            None => {
+                let max = min(min(buf.len() as u64, self.size - self.pos), READ_CHUNK_SIZE as u64) as u32;
+                let data = self.fs.camera.get_partialobject(self.handle, self.pos as u32, max, self.fs.timeout)
+                    .map_err(into_io)?;
+                let n = min(data.len(), buf.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                n
+            }
+        };
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<'f, T: PtpTransport> Seek for PtpFile<'f, T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(p) => p as i64,
+            SeekFrom::End(d) => self.size as i64 + d,
+            SeekFrom::Current(d) => self.pos as i64 + d,
+        };
+        if pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the object"));
+        }
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
+
+fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|c| !c.is_empty() && *c != ".")
+}
+
+fn parent_key(parent: u32) -> u32 {
+    if parent == 0 { ROOT } else { parent }
+}
+
+// a plausible ObjectFormat for a new object, from its extension
+fn format_for(name: &str) -> ObjectFormatCode {
+    let ext = name.rsplit('.').next().unwrap_or("").to_ascii_lowercase();
+    match &ext[..] {
+        "jpg" | "jpeg" => StandardObjectFormatCode::EXIF_JPEG,
+        "tif" | "tiff" => StandardObjectFormatCode::TIFF,
+        "png" => StandardObjectFormatCode::PNG,
+        "bmp" => StandardObjectFormatCode::BMP,
+        "gif" => StandardObjectFormatCode::GIF,
+        "txt" => StandardObjectFormatCode::Text,
+        "htm" | "html" => StandardObjectFormatCode::HTML,
+        "wav" => StandardObjectFormatCode::WAV,
+        "mp3" => StandardObjectFormatCode::MP3,
+        "avi" => StandardObjectFormatCode::AVI,
+        "mpg" | "mpeg" => StandardObjectFormatCode::MPEG,
+        _ => StandardObjectFormatCode::Undefined,
+    }
+}
+
+fn not_found(path: &str) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::NotFound, format!("{}: no such object", path)))
+}
+
+fn not_a_directory(path: &str) -> Error {
+    Error::Io(io::Error::other(format!("{}: not an association", path)))
+}
+
+fn into_io(e: Error) -> io::Error {
+    match e {
+        Error::Io(e) => e,
+        e => io::Error::other(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::StandardResponseCode;
+    use super::super::mock::MockTransport;
+
+    const STORE: u32 = 0x00010001;
+
+    fn handles(handles: &[u32]) -> Vec<u8> {
+        let mut data = (handles.len() as u32).to_le_bytes().to_vec();
+        for handle in handles {
+            data.extend_from_slice(&handle.to_le_bytes());
+        }
+        data
+    }
+
+    fn info(name: &str, format: ObjectFormatCode, parent: u32, size: u32) -> PtpObjectInfo {
+        PtpObjectInfo {
+            StorageID: STORE,
+            ObjectFormat: format,
+            ObjectCompressedSize: size,
+            ParentObject: parent,
+            Filename: name.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    // DCIM (1) holding A.JPG (2) at the root, next to README.TXT (3)
+    fn expect_root(mock: &mut MockTransport) {
+        mock.expect(StandardCommandCode::GetObjectHandles, &[STORE, 0, ROOT]).reply_data(&handles(&[1, 3]));
+        mock.expect(StandardCommandCode::GetObjectInfo, &[1])
+            .reply_data(&info("DCIM", StandardObjectFormatCode::Association, 0, 0).encode());
+        mock.expect(StandardCommandCode::GetObjectInfo, &[3])
+            .reply_data(&info("README.TXT", StandardObjectFormatCode::Text, 0, 5).encode());
+    }
+
+    fn expect_dcim(mock: &mut MockTransport, children: &[(u32, &str)]) {
+        let list: Vec<u32> = children.iter().map(|&(h, _)| h).collect();
+        mock.expect(StandardCommandCode::GetObjectHandles, &[STORE, 0, 1]).reply_data(&handles(&list));
+        for &(handle, name) in children {
+            mock.expect(StandardCommandCode::GetObjectInfo, &[handle])
+                .reply_data(&info(name, StandardObjectFormatCode::EXIF_JPEG, 1, 6).encode());
+        }
+    }
+
+    fn kind(result: Result<impl std::fmt::Debug, Error>) -> io::ErrorKind {
+        match result {
+            Err(Error::Io(e)) => e.kind(),
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn resolves_paths() {
+        let mut mock = MockTransport::new();
+        expect_root(&mut mock);
+        expect_dcim(&mut mock, &[(2, "A.JPG")]);
+        let mut fs = PtpFs::new(PtpCamera::with_transport(mock), STORE);
+
+        assert_eq!(fs.resolve("").unwrap(), ROOT);
+        assert_eq!(fs.resolve("/DCIM/./A.JPG").unwrap(), 2);
+        // from the cache from here on
+        assert_eq!(fs.resolve("DCIM//A.JPG/").unwrap(), 2);
+        assert!(fs.stat("DCIM").unwrap().is_dir());
+        assert_eq!(kind(fs.resolve("DCIM/B.JPG")), io::ErrorKind::NotFound);
+        assert_eq!(kind(fs.resolve("dcim/A.JPG")), io::ErrorKind::NotFound);
+        assert_eq!(kind(fs.resolve("README.TXT/A.JPG")), io::ErrorKind::Other);
+        assert_eq!(kind(fs.stat("/")), io::ErrorKind::InvalidInput);
+        fs.camera().transport().verify();
+    }
+
+    #[test]
+    fn lists_directories() {
+        let mut mock = MockTransport::new();
+        expect_root(&mut mock);
+        expect_dcim(&mut mock, &[(2, "A.JPG"), (4, "B.JPG")]);
+        let mut fs = PtpFs::new(PtpCamera::with_transport(mock), STORE);
+
+        let names = |entries: Vec<DirEntry>| entries.iter().map(|e| e.name().to_owned()).collect::<Vec<_>>();
+        assert_eq!(names(fs.read_dir("").unwrap()), vec!["DCIM", "README.TXT"]);
+        assert_eq!(names(fs.read_dir("DCIM").unwrap()), vec!["A.JPG", "B.JPG"]);
+        assert_eq!(kind(fs.read_dir("README.TXT")), io::ErrorKind::Other);
+        fs.camera().transport().verify();
+    }
+
+    #[test]
+    fn opens_with_partial_reads() {
+        let mut mock = MockTransport::new();
+        expect_root(&mut mock);
+        expect_dcim(&mut mock, &[(2, "A.JPG")]);
+        mock.expect_device_info(&[StandardCommandCode::GetPartialObject]);
+        mock.expect(StandardCommandCode::GetPartialObject, &[2, 0, 4]).reply_data(b"abcd");
+        mock.expect(StandardCommandCode::GetPartialObject, &[2, 5, 1]).reply_data(b"f");
+        let mut fs = PtpFs::new(PtpCamera::with_transport(mock), STORE);
+
+        {
+            let mut file = fs.open("DCIM/A.JPG").unwrap();
+            assert_eq!(file.len(), 6);
+            let mut buf = [0; 4];
+            file.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"abcd");
+            file.seek(SeekFrom::End(-1)).unwrap();
+            let mut rest = vec![];
+            file.read_to_end(&mut rest).unwrap();
+            assert_eq!(rest, b"f");
+        }
+        assert_eq!(kind(fs.open("DCIM").map(|_| ())), io::ErrorKind::Other);
+        fs.camera().transport().verify();
+    }
+
+    #[test]
+    fn opens_with_whole_download() {
+        let mut mock = MockTransport::new();
+        expect_root(&mut mock);
+        mock.expect_device_info(&[]);
+        mock.expect(StandardCommandCode::GetObject, &[3]).reply_data(b"hello");
+        let mut fs = PtpFs::new(PtpCamera::with_transport(mock), STORE);
+
+        let mut file = fs.open("README.TXT").unwrap();
+        file.seek(SeekFrom::Start(1)).unwrap();
+        let mut data = String::new();
+        file.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "ello");
+    }
+
+    #[test]
+    fn creates_and_removes() {
+        let mut mock = MockTransport::new();
+        expect_root(&mut mock);
+        expect_dcim(&mut mock, &[(2, "A.JPG")]);
+        let new = PtpObjectInfo {
+            ObjectFormat: StandardObjectFormatCode::EXIF_JPEG,
+            ObjectCompressedSize: 2,
+            Filename: "B.JPG".to_owned(),
+            ..Default::default()
+        };
+        mock.expect(StandardCommandCode::SendObjectInfo, &[STORE, 1])
+            .expect_data(&new.encode())
+            .respond(StandardResponseCode::Ok, &[STORE, 1, 4]);
+        mock.expect(StandardCommandCode::SendObject, &[]).expect_data(b"hi");
+        // the folder is listed again after the upload, and after the removal
+        mock.expect(StandardCommandCode::GetObjectHandles, &[STORE, 0, 1]).reply_data(&handles(&[2, 4]));
+        mock.expect(StandardCommandCode::GetObjectInfo, &[4])
+            .reply_data(&info("B.JPG", StandardObjectFormatCode::EXIF_JPEG, 1, 2).encode());
+        mock.expect(StandardCommandCode::DeleteObject, &[2]);
+        mock.expect(StandardCommandCode::GetObjectHandles, &[STORE, 0, 1]).reply_data(&handles(&[4]));
+        let mut fs = PtpFs::new(PtpCamera::with_transport(mock), STORE);
+
+        assert_eq!(fs.create("DCIM/B.JPG", &mut &b"hi"[..], 2).unwrap(), 4);
+        assert_eq!(kind(fs.create("DCIM/A.JPG", &mut &b"hi"[..], 2)), io::ErrorKind::AlreadyExists);
+        assert_eq!(kind(fs.create("/", &mut &b"hi"[..], 2)), io::ErrorKind::InvalidInput);
+        assert_eq!(kind(fs.create("NONE/B.JPG", &mut &b"hi"[..], 2)), io::ErrorKind::NotFound);
+        assert_eq!(fs.stat("DCIM/B.JPG").unwrap().handle, 4);
+
+        fs.remove("DCIM/A.JPG").unwrap();
+        assert_eq!(kind(fs.stat("DCIM/A.JPG")), io::ErrorKind::NotFound);
+        fs.camera().transport().verify();
+    }
+
+    #[test]
+    fn events_invalidate_the_cache() {
+        let mut mock = MockTransport::new();
+        expect_root(&mut mock);
+        expect_dcim(&mut mock, &[(2, "A.JPG")]);
+        // every listing is read again after ObjectAdded, the known infos aren't
+        mock.expect(StandardCommandCode::GetObjectHandles, &[STORE, 0, ROOT]).reply_data(&handles(&[1, 3]));
+        mock.expect(StandardCommandCode::GetObjectHandles, &[STORE, 0, 1]).reply_data(&handles(&[2, 4]));
+        mock.expect(StandardCommandCode::GetObjectInfo, &[4])
+            .reply_data(&info("B.JPG", StandardObjectFormatCode::EXIF_JPEG, 1, 6).encode());
+        // re-listed after ObjectRemoved, without it
+        mock.expect(StandardCommandCode::GetObjectHandles, &[STORE, 0, 1]).reply_data(&handles(&[4]));
+        let mut fs = PtpFs::new(PtpCamera::with_transport(mock), STORE);
+
+        assert_eq!(fs.resolve("DCIM/A.JPG").unwrap(), 2);
+        assert_eq!(kind(fs.resolve("DCIM/B.JPG")), io::ErrorKind::NotFound);
+
+        fs.camera().transport().push_event(StandardEventCode::ObjectAdded, 0, &[4]);
+        assert_eq!(fs.resolve("DCIM/B.JPG").unwrap(), 4);
+
+        fs.camera().transport().push_event(StandardEventCode::ObjectRemoved, 0, &[2]);
+        assert_eq!(kind(fs.resolve("DCIM/A.JPG")), io::ErrorKind::NotFound);
+        assert_eq!(fs.resolve("DCIM/B.JPG").unwrap(), 4);
+
+        let events = fs.take_events();
+        assert_eq!(events.iter().map(|e| e.code).collect::<Vec<_>>(),
+                   vec![StandardEventCode::ObjectAdded, StandardEventCode::ObjectRemoved]);
+        fs.camera().transport().verify();
+    }
+}
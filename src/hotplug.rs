@@ -0,0 +1,454 @@
+//! Notifications of PTP devices arriving and leaving.
+//!
+//! Arrival and removal are detected by enumerating the bus and comparing with the previous scan.
+//! `DeviceMonitor` does a single comparison whenever asked; `HotplugWatcher` runs one on a
+//! background thread and delivers the changes over a channel. Where libusb supports hotplug
+//! notifications, the watcher registers for them and scans as soon as a device comes or goes;
+//! elsewhere, such as on Windows, it falls back to scanning periodically:
+//!
+//! ```no_run
+//! # fn example() -> Result<(), ptp::Error> {
+//! use std::time::Duration;
+//! use ptp::hotplug::{HotplugEvent, HotplugWatcher};
+//!
+//! let watcher = HotplugWatcher::start(Duration::from_secs(1))?;
+//! for event in watcher.iter() {
+//!     if let HotplugEvent::Arrived(info) = event {
+//!         println!("camera {:?} is back", info.serial);
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! A device that is unplugged and reconnected gets a new address, so it is reported as leaving
+//! and arriving even if both happen between two scans.
+//!
+//! A device that can't be opened yet, for instance until a udev rule has granted access, is
+//! reported with what its descriptors show and opened again on every scan; once it opens, its
+//! full description follows as `HotplugEvent::Identified`.
+
+use std::collections::HashMap;
+use std::os::raw::{c_int, c_long, c_void};
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use rusb::UsbContext;
+
+use super::{Error, UsbDeviceInfo};
+use super::usb::Identity;
+
+/// A change in the set of attached PTP devices
+#[derive(Debug, Clone, PartialEq)]
+pub enum HotplugEvent {
+    Arrived(UsbDeviceInfo),
+    /// a device that arrived before it could be opened, described again now that it has been,
+    /// with its strings
+    Identified(UsbDeviceInfo),
+    Left(UsbDeviceInfo),
+}
+
+/// Detects PTP devices arriving and leaving by comparing successive enumerations
+#[derive(Debug, Default)]
+pub struct DeviceMonitor {
+    // every device seen on the last scan, by bus and address
+    known: HashMap<(u8, u8), Known>,
+}
+
+#[derive(Debug)]
+struct Known {
+    // as last reported, None for devices without a PTP interface
+    info: Option<UsbDeviceInfo>,
+    // whether the device could be opened to identify it. only complete identifications are
+    // kept, the others are tried again on the next scan.
+    complete: bool,
+}
+
+impl DeviceMonitor {
+    /// A monitor that considers no device attached yet, so the first `poll` reports every
+    /// attached device as arrived.
+    pub fn new() -> DeviceMonitor {
+        DeviceMonitor::default()
+    }
+
+    /// Enumerate the bus, returning the devices that arrived or left since the last call.
+    pub fn poll(&mut self, context: &rusb::Context) -> Result<Vec<HotplugEvent>, Error> {
+        let devices = context.devices()?;
+        let attached = devices.iter().map(|device| ((device.bus_number(), device.address()), device)).collect();
+        Ok(self.update(attached, UsbDeviceInfo::probe))
+    }
+
+    /// the PTP devices attached as of the last `poll`
+    pub fn devices(&self) -> Vec<UsbDeviceInfo> {
+        self.known.values().filter_map(|known| known.info.clone()).collect()
+    }
+
+    // Compare the devices now `attached`, by bus and address, with the last scan, identifying
+    // those that weren't fully identified then.
+    fn update<D>(&mut self,
+                 attached: Vec<((u8, u8), D)>,
+                 mut identify: impl FnMut(&D) -> Result<Identity, Error>)
+                 -> Vec<HotplugEvent> {
+        let mut events = vec![];
+        let mut seen = HashMap::with_capacity(self.known.len());
+
+        for (key, device) in attached {
+            let previous = match self.known.remove(&key) {
+                Some(known) if known.complete => {
+                    seen.insert(key, known);
+                    continue;
+                }
+                previous => previous,
+            };
+
+            let identity = match identify(&device) {
+                Ok(identity) => identity,
+                Err(e) => {
+                    // a device that has only just arrived may not answer yet; try again next time
+                    debug!("can't identify device {}:{} yet: {}", key.0, key.1, e);
+                    if let Some(previous) = previous {
+                        seen.insert(key, previous);
+                    }
+                    continue;
+                }
+            };
+            if let Some(ref e) = identity.open_error {
+                // tried again on every scan, so only worth a warning the first time
+                if previous.is_none() {
+                    warn!("can't open device {}:{} to identify it: {}", key.0, key.1, e);
+                }
+            }
+
+            match (previous.and_then(|known| known.info), &identity.info) {
+                (None, Some(info)) => events.push(HotplugEvent::Arrived(info.clone())),
+                (Some(ref old), Some(info)) if old != info => events.push(HotplugEvent::Identified(info.clone())),
+                (Some(old), None) => events.push(HotplugEvent::Left(old)),
+                _ => {}
+            }
+            seen.insert(key, Known { info: identity.info, complete: identity.open_error.is_none() });
+        }
+
+        // whatever wasn't seen this time has gone
+        events.extend(self.known.drain().filter_map(|(_, known)| known.info.map(HotplugEvent::Left)));
+        self.known = seen;
+        events
+    }
+}
+
+/// Background thread that reports PTP devices arriving and leaving
+///
+/// Devices already attached when the watcher starts are reported as arrived first. The thread
+/// stops when the watcher is dropped.
+pub struct HotplugWatcher {
+    events: Receiver<HotplugEvent>,
+    notified: bool,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl HotplugWatcher {
+    /// Start watching the bus. With hotplug notifications the bus is scanned whenever one
+    /// arrives, and every `interval` regardless in case a device wasn't ready to be identified
+    /// when it was announced; without, it is scanned every `interval`.
+    pub fn start(interval: Duration) -> Result<HotplugWatcher, Error> {
+        let (tx, rx) = mpsc::channel();
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+
+        let thread = thread::Builder::new().name("ptp-hotplug".to_owned()).spawn(move || {
+            // the thread has a context of its own, independent of any the caller uses
+            let context = match rusb::Context::new() {
+                Ok(context) => context,
+                Err(e) => {
+                    ready_tx.send(Err(e)).ok();
+                    return;
+                }
+            };
+            let notifier = if rusb::has_hotplug() { Notifier::register() } else { None };
+            match notifier {
+                Some(_) => debug!("watching for devices with libusb hotplug notifications"),
+                None => debug!("libusb hotplug notifications unavailable, scanning every {:?}", interval),
+            }
+            ready_tx.send(Ok(notifier.is_some())).ok();
+
+            let mut monitor = DeviceMonitor::new();
+            while !thread_stop.load(Ordering::SeqCst) {
+                let started = Instant::now();
+                match monitor.poll(&context) {
+                    Ok(events) => {
+                        for event in events {
+                            if tx.send(event).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    Err(e) => warn!("USB enumeration failed: {}", e),
+                }
+
+                // wait in short steps so stopping doesn't wait for a whole interval
+                let step = ::std::cmp::min(interval, Duration::from_millis(50));
+                while !thread_stop.load(Ordering::SeqCst) && started.elapsed() < interval {
+                    match notifier {
+                        Some(ref notifier) => {
+                            if notifier.wait(step) {
+                                break;
+                            }
+                        }
+                        None => thread::sleep(step),
+                    }
+                }
+            }
+        })?;
+
+        let notified = match ready_rx.recv() {
+            Ok(Ok(notified)) => notified,
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) => return Err(Error::Malformed("hotplug thread failed to start".to_owned())),
+        };
+
+        Ok(HotplugWatcher {
+            events: rx,
+            notified,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// whether libusb hotplug notifications are in use, rather than periodic scans only
+    pub fn uses_notifications(&self) -> bool {
+        self.notified
+    }
+
+    /// Wait for the next change.
+    pub fn recv(&self) -> Option<HotplugEvent> {
+        self.events.recv().ok()
+    }
+
+    /// Wait up to `timeout` for the next change.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<HotplugEvent> {
+        match self.events.recv_timeout(timeout) {
+            Ok(event) => Some(event),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
+
+    /// the next change, if one has already been detected
+    pub fn try_recv(&self) -> Option<HotplugEvent> {
+        match self.events.try_recv() {
+            Ok(event) => Some(event),
+            Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => None,
+        }
+    }
+
+    /// Iterate over changes as they happen, blocking between them.
+    pub fn iter(&self) -> mpsc::Iter<'_, HotplugEvent> {
+        self.events.iter()
+    }
+
+    /// Stop the thread and wait for it to finish.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+impl Drop for HotplugWatcher {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+// libusb's hotplug API, called directly so the callback can be a plain function that only
+// raises a flag. the library itself is already linked by the bindings.
+const LIBUSB_HOTPLUG_EVENT_DEVICE_ARRIVED: c_int = 0x01;
+const LIBUSB_HOTPLUG_EVENT_DEVICE_LEFT: c_int = 0x02;
+const LIBUSB_HOTPLUG_MATCH_ANY: c_int = -1;
+const LIBUSB_SUCCESS: c_int = 0;
+
+#[repr(C)]
+struct Timeval {
+    tv_sec: c_long,
+    tv_usec: c_long,
+}
+
+type HotplugCallback = extern "C" fn(*mut c_void, *mut c_void, c_int, *mut c_void) -> c_int;
+
+extern "C" {
+    fn libusb_init(context: *mut *mut c_void) -> c_int;
+    fn libusb_exit(context: *mut c_void);
+    fn libusb_hotplug_register_callback(context: *mut c_void,
+                                        events: c_int,
+                                        flags: c_int,
+                                        vendor_id: c_int,
+                                        product_id: c_int,
+                                        dev_class: c_int,
+                                        callback: HotplugCallback,
+                                        user_data: *mut c_void,
+                                        handle: *mut c_int)
+                                        -> c_int;
+    fn libusb_hotplug_deregister_callback(context: *mut c_void, handle: c_int);
+    fn libusb_handle_events_timeout_completed(context: *mut c_void, tv: *const Timeval, completed: *mut c_int) -> c_int;
+}
+
+// A libusb context of its own with a hotplug callback registered on it. The callback only
+// raises a flag: libusb doesn't allow devices to be opened from within it, so identifying them
+// is left to the scan it triggers.
+struct Notifier {
+    context: *mut c_void,
+    handle: c_int,
+    // boxed so its address, given to libusb, stays put
+    changed: Box<AtomicBool>,
+}
+
+impl Notifier {
+    // None if the context can't be made or the callback registered
+    fn register() -> Option<Notifier> {
+        let mut context = ptr::null_mut();
+        if unsafe { libusb_init(&mut context) } != LIBUSB_SUCCESS {
+            return None;
+        }
+
+        let mut notifier = Notifier { context, handle: 0, changed: Box::new(AtomicBool::new(false)) };
+        let user_data = &*notifier.changed as *const AtomicBool as *mut c_void;
+        let result = unsafe {
+            libusb_hotplug_register_callback(context,
+                                             LIBUSB_HOTPLUG_EVENT_DEVICE_ARRIVED | LIBUSB_HOTPLUG_EVENT_DEVICE_LEFT,
+                                             0,
+                                             LIBUSB_HOTPLUG_MATCH_ANY,
+                                             LIBUSB_HOTPLUG_MATCH_ANY,
+                                             LIBUSB_HOTPLUG_MATCH_ANY,
+                                             on_hotplug,
+                                             user_data,
+                                             &mut notifier.handle)
+        };
+        if result != LIBUSB_SUCCESS {
+            debug!("libusb hotplug registration failed: {}", result);
+            // nothing to deregister, but the context still needs closing
+            notifier.handle = -1;
+            return None;
+        }
+        Some(notifier)
+    }
+
+    // handle libusb events for up to `timeout`, returning whether a device arrived or left
+    fn wait(&self, timeout: Duration) -> bool {
+        let tv = Timeval { tv_sec: timeout.as_secs() as c_long, tv_usec: timeout.subsec_micros() as c_long };
+        let result = unsafe { libusb_handle_events_timeout_completed(self.context, &tv, ptr::null_mut()) };
+        if result != LIBUSB_SUCCESS {
+            debug!("libusb event handling failed: {}", result);
+            // don't spin on a persistent failure
+            thread::sleep(timeout);
+        }
+        self.changed.swap(false, Ordering::SeqCst)
+    }
+}
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        unsafe {
+            if self.handle >= 0 {
+                libusb_hotplug_deregister_callback(self.context, self.handle);
+            }
+            libusb_exit(self.context);
+        }
+    }
+}
+
+extern "C" fn on_hotplug(_context: *mut c_void, _device: *mut c_void, _event: c_int, user_data: *mut c_void) -> c_int {
+    let changed = unsafe { &*(user_data as *const AtomicBool) };
+    changed.store(true, Ordering::SeqCst);
+    // stay registered
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn camera(address: u8, serial: Option<&str>) -> UsbDeviceInfo {
+        UsbDeviceInfo {
+            bus: 1,
+            address,
+            vendor_id: 0x04a9,
+            product_id: 0x3218,
+            manufacturer: None,
+            product: None,
+            serial: serial.map(|s| s.to_owned()),
+            interface: 0,
+            mtp: false,
+        }
+    }
+
+    fn opened(info: Option<UsbDeviceInfo>) -> Result<Identity, Error> {
+        Ok(Identity { info, open_error: None })
+    }
+
+    fn unopened(info: Option<UsbDeviceInfo>) -> Result<Identity, Error> {
+        Ok(Identity { info, open_error: Some(rusb::Error::Access) })
+    }
+
+    #[test]
+    fn arrivals_and_departures() {
+        let mut monitor = DeviceMonitor::new();
+        let mut probes = 0;
+
+        // a camera, and a device without a PTP interface
+        let events = monitor.update(vec![((1, 2), Some(camera(2, Some("A")))), ((1, 3), None)], |info: &Option<UsbDeviceInfo>| {
+            probes += 1;
+            opened(info.clone())
+        });
+        assert_eq!(events, vec![HotplugEvent::Arrived(camera(2, Some("A")))]);
+        assert_eq!(monitor.devices(), vec![camera(2, Some("A"))]);
+
+        // both are remembered, neither is opened again
+        let events = monitor.update(vec![((1, 2), ()), ((1, 3), ())], |_| -> Result<Identity, Error> {
+            panic!("identified again")
+        });
+        assert!(events.is_empty());
+        assert_eq!(probes, 2);
+
+        // the camera is replugged at a new address
+        let events = monitor.update(vec![((1, 3), None), ((1, 4), Some(camera(4, Some("A"))))], |info: &Option<UsbDeviceInfo>| opened(info.clone()));
+        assert_eq!(events, vec![HotplugEvent::Arrived(camera(4, Some("A"))), HotplugEvent::Left(camera(2, Some("A")))]);
+    }
+
+    #[test]
+    fn unopened_devices_are_retried() {
+        let mut monitor = DeviceMonitor::new();
+
+        // a PTP camera that can't be opened yet is reported without its serial number, and an
+        // MTP one isn't recognised at all
+        let events = monitor.update(vec![((1, 2), 2), ((1, 3), 3)], |&address| {
+            unopened(if address == 2 { Some(camera(2, None)) } else { None })
+        });
+        assert_eq!(events, vec![HotplugEvent::Arrived(camera(2, None))]);
+
+        // still no access: nothing new to report
+        let events = monitor.update(vec![((1, 2), 2), ((1, 3), 3)], |&address| {
+            unopened(if address == 2 { Some(camera(2, None)) } else { None })
+        });
+        assert!(events.is_empty());
+
+        // a failure to read the descriptors keeps what was known
+        let events = monitor.update(vec![((1, 2), 2), ((1, 3), 3)], |_| Err(Error::Usb(rusb::Error::Io)));
+        assert!(events.is_empty());
+        assert_eq!(monitor.devices(), vec![camera(2, None)]);
+
+        // with access, both are identified in full, and then left alone
+        let mut identified = monitor.update(vec![((1, 2), 2), ((1, 3), 3)], |&address| opened(Some(camera(address, Some("A")))));
+        identified.sort_by_key(|event| format!("{:?}", event));
+        assert_eq!(identified, vec![HotplugEvent::Arrived(camera(3, Some("A"))), HotplugEvent::Identified(camera(2, Some("A")))]);
+        let events = monitor.update(vec![((1, 2), 2), ((1, 3), 3)], |_| -> Result<Identity, Error> { panic!("identified again") });
+        assert!(events.is_empty());
+    }
+}
@@ -1,7 +1,7 @@
 #![allow(non_snake_case)]
 #[macro_use] extern crate log;
 
-extern crate libusb;
+extern crate rusb;
 extern crate byteorder;
 extern crate time;
 
@@ -11,10 +11,27 @@ use std::io::Cursor;
 use std::io;
 use std::fmt;
 use std::time::Duration;
-use std::slice;
 use std::cmp::min;
 
-#[derive(Debug, PartialEq)]
+mod usb;
+pub mod ptpip;
+pub mod mock;
+pub mod record;
+pub mod props;
+pub mod download;
+pub mod transfer;
+pub mod fs;
+pub mod sync;
+pub mod hotplug;
+pub mod resilient;
+pub mod capture;
+pub mod thumb;
+pub mod mtp;
+
+pub use usb::{UsbTransport, UsbDeviceInfo, UsbDeviceSelector};
+pub use ptpip::PtpIpTransport;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 #[repr(u16)]
 pub enum PtpContainerType {
     Command = 1,
@@ -24,7 +41,7 @@ pub enum PtpContainerType {
 }
 
 impl PtpContainerType {
-    fn from_u16(v: u16) -> Option<PtpContainerType> {
+    pub fn from_u16(v: u16) -> Option<PtpContainerType> {
         use self::PtpContainerType::*;
         match v {
             1 => Some(Command),
@@ -188,8 +205,209 @@ pub mod StandardCommandCode {
     }
 }
 
+pub type DevicePropCode = u16;
+
+#[allow(non_upper_case_globals)]
+pub mod StandardDevicePropCode {
+    use super::DevicePropCode;
+
+    pub const Undefined: DevicePropCode = 0x5000;
+    pub const BatteryLevel: DevicePropCode = 0x5001;
+    pub const FunctionalMode: DevicePropCode = 0x5002;
+    pub const ImageSize: DevicePropCode = 0x5003;
+    pub const CompressionSetting: DevicePropCode = 0x5004;
+    pub const WhiteBalance: DevicePropCode = 0x5005;
+    pub const RGBGain: DevicePropCode = 0x5006;
+    pub const FNumber: DevicePropCode = 0x5007;
+    pub const FocalLength: DevicePropCode = 0x5008;
+    pub const FocusDistance: DevicePropCode = 0x5009;
+    pub const FocusMode: DevicePropCode = 0x500A;
+    pub const ExposureMeteringMode: DevicePropCode = 0x500B;
+    pub const FlashMode: DevicePropCode = 0x500C;
+    pub const ExposureTime: DevicePropCode = 0x500D;
+    pub const ExposureProgramMode: DevicePropCode = 0x500E;
+    pub const ExposureIndex: DevicePropCode = 0x500F;
+    pub const ExposureBiasCompensation: DevicePropCode = 0x5010;
+    pub const DateTime: DevicePropCode = 0x5011;
+    pub const CaptureDelay: DevicePropCode = 0x5012;
+    pub const StillCaptureMode: DevicePropCode = 0x5013;
+    pub const Contrast: DevicePropCode = 0x5014;
+    pub const Sharpness: DevicePropCode = 0x5015;
+    pub const DigitalZoom: DevicePropCode = 0x5016;
+    pub const EffectMode: DevicePropCode = 0x5017;
+    pub const BurstNumber: DevicePropCode = 0x5018;
+    pub const BurstInterval: DevicePropCode = 0x5019;
+    pub const TimelapseNumber: DevicePropCode = 0x501A;
+    pub const TimelapseInterval: DevicePropCode = 0x501B;
+    pub const FocusMeteringMode: DevicePropCode = 0x501C;
+    pub const UploadURL: DevicePropCode = 0x501D;
+    pub const Artist: DevicePropCode = 0x501E;
+    pub const CopyrightInfo: DevicePropCode = 0x501F;
+
+    pub fn name(v: DevicePropCode) -> Option<&'static str> {
+        match v {
+            Undefined => Some("Undefined"),
+            BatteryLevel => Some("BatteryLevel"),
+            FunctionalMode => Some("FunctionalMode"),
+            ImageSize => Some("ImageSize"),
+            CompressionSetting => Some("CompressionSetting"),
+            WhiteBalance => Some("WhiteBalance"),
+            RGBGain => Some("RGBGain"),
+            FNumber => Some("FNumber"),
+            FocalLength => Some("FocalLength"),
+            FocusDistance => Some("FocusDistance"),
+            FocusMode => Some("FocusMode"),
+            ExposureMeteringMode => Some("ExposureMeteringMode"),
+            FlashMode => Some("FlashMode"),
+            ExposureTime => Some("ExposureTime"),
+            ExposureProgramMode => Some("ExposureProgramMode"),
+            ExposureIndex => Some("ExposureIndex"),
+            ExposureBiasCompensation => Some("ExposureBiasCompensation"),
+            DateTime => Some("DateTime"),
+            CaptureDelay => Some("CaptureDelay"),
+            StillCaptureMode => Some("StillCaptureMode"),
+            Contrast => Some("Contrast"),
+            Sharpness => Some("Sharpness"),
+            DigitalZoom => Some("DigitalZoom"),
+            EffectMode => Some("EffectMode"),
+            BurstNumber => Some("BurstNumber"),
+            BurstInterval => Some("BurstInterval"),
+            TimelapseNumber => Some("TimelapseNumber"),
+            TimelapseInterval => Some("TimelapseInterval"),
+            FocusMeteringMode => Some("FocusMeteringMode"),
+            UploadURL => Some("UploadURL"),
+            Artist => Some("Artist"),
+            CopyrightInfo => Some("CopyrightInfo"),
+            _ => None,
+        }
+    }
+}
+
+pub type EventCode = u16;
+
+#[allow(non_upper_case_globals)]
+pub mod StandardEventCode {
+    use super::EventCode;
+
+    pub const Undefined: EventCode = 0x4000;
+    pub const CancelTransaction: EventCode = 0x4001;
+    pub const ObjectAdded: EventCode = 0x4002;
+    pub const ObjectRemoved: EventCode = 0x4003;
+    pub const StoreAdded: EventCode = 0x4004;
+    pub const StoreRemoved: EventCode = 0x4005;
+    pub const DevicePropChanged: EventCode = 0x4006;
+    pub const ObjectInfoChanged: EventCode = 0x4007;
+    pub const DeviceInfoChanged: EventCode = 0x4008;
+    pub const RequestObjectTransfer: EventCode = 0x4009;
+    pub const StoreFull: EventCode = 0x400A;
+    pub const DeviceReset: EventCode = 0x400B;
+    pub const StorageInfoChanged: EventCode = 0x400C;
+    pub const CaptureComplete: EventCode = 0x400D;
+    pub const UnreportedStatus: EventCode = 0x400E;
+
+    pub fn name(v: EventCode) -> Option<&'static str> {
+        match v {
+            Undefined => Some("Undefined"),
+            CancelTransaction => Some("CancelTransaction"),
+            ObjectAdded => Some("ObjectAdded"),
+            ObjectRemoved => Some("ObjectRemoved"),
+            StoreAdded => Some("StoreAdded"),
+            StoreRemoved => Some("StoreRemoved"),
+            DevicePropChanged => Some("DevicePropChanged"),
+            ObjectInfoChanged => Some("ObjectInfoChanged"),
+            DeviceInfoChanged => Some("DeviceInfoChanged"),
+            RequestObjectTransfer => Some("RequestObjectTransfer"),
+            StoreFull => Some("StoreFull"),
+            DeviceReset => Some("DeviceReset"),
+            StorageInfoChanged => Some("StorageInfoChanged"),
+            CaptureComplete => Some("CaptureComplete"),
+            UnreportedStatus => Some("UnreportedStatus"),
+            _ => None,
+        }
+    }
+}
+
+pub type ObjectFormatCode = u16;
+
+#[allow(non_upper_case_globals)]
+pub mod StandardObjectFormatCode {
+    use super::ObjectFormatCode;
+
+    pub const Undefined: ObjectFormatCode = 0x3000;
+    pub const Association: ObjectFormatCode = 0x3001;
+    pub const Script: ObjectFormatCode = 0x3002;
+    pub const Executable: ObjectFormatCode = 0x3003;
+    pub const Text: ObjectFormatCode = 0x3004;
+    pub const HTML: ObjectFormatCode = 0x3005;
+    pub const DPOF: ObjectFormatCode = 0x3006;
+    pub const AIFF: ObjectFormatCode = 0x3007;
+    pub const WAV: ObjectFormatCode = 0x3008;
+    pub const MP3: ObjectFormatCode = 0x3009;
+    pub const AVI: ObjectFormatCode = 0x300A;
+    pub const MPEG: ObjectFormatCode = 0x300B;
+    pub const ASF: ObjectFormatCode = 0x300C;
+    pub const UndefinedImage: ObjectFormatCode = 0x3800;
+    pub const EXIF_JPEG: ObjectFormatCode = 0x3801;
+    pub const TIFF_EP: ObjectFormatCode = 0x3802;
+    pub const FlashPix: ObjectFormatCode = 0x3803;
+    pub const BMP: ObjectFormatCode = 0x3804;
+    pub const CIFF: ObjectFormatCode = 0x3805;
+    pub const GIF: ObjectFormatCode = 0x3807;
+    pub const JFIF: ObjectFormatCode = 0x3808;
+    pub const PCD: ObjectFormatCode = 0x3809;
+    pub const PICT: ObjectFormatCode = 0x380A;
+    pub const PNG: ObjectFormatCode = 0x380B;
+    pub const TIFF: ObjectFormatCode = 0x380D;
+    pub const TIFF_IT: ObjectFormatCode = 0x380E;
+    pub const JP2: ObjectFormatCode = 0x380F;
+    pub const JPX: ObjectFormatCode = 0x3810;
+
+    pub fn name(v: ObjectFormatCode) -> Option<&'static str> {
+        match v {
+            Undefined => Some("Undefined"),
+            Association => Some("Association"),
+            Script => Some("Script"),
+            Executable => Some("Executable"),
+            Text => Some("Text"),
+            HTML => Some("HTML"),
+            DPOF => Some("DPOF"),
+            AIFF => Some("AIFF"),
+            WAV => Some("WAV"),
+            MP3 => Some("MP3"),
+            AVI => Some("AVI"),
+            MPEG => Some("MPEG"),
+            ASF => Some("ASF"),
+            UndefinedImage => Some("UndefinedImage"),
+            EXIF_JPEG => Some("EXIF_JPEG"),
+            TIFF_EP => Some("TIFF_EP"),
+            FlashPix => Some("FlashPix"),
+            BMP => Some("BMP"),
+            CIFF => Some("CIFF"),
+            GIF => Some("GIF"),
+            JFIF => Some("JFIF"),
+            PCD => Some("PCD"),
+            PICT => Some("PICT"),
+            PNG => Some("PNG"),
+            TIFF => Some("TIFF"),
+            TIFF_IT => Some("TIFF_IT"),
+            JP2 => Some("JP2"),
+            JPX => Some("JPX"),
+            _ => None,
+        }
+    }
+
+    /// whether `v` is one of the image formats, 0x3800 to 0x3FFF
+    pub fn is_image(v: ObjectFormatCode) -> bool {
+        v & 0xF800 == 0x3800
+    }
+}
+
 /// An error in a PTP command
+///
+/// New variants may be added in minor releases, so matches outside this crate need a
+/// wildcard arm.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
     /// PTP Responder returned a status code other than Ok, either a constant in StandardResponseCode or a vendor-defined code
     Response(u16),
@@ -198,10 +416,16 @@ pub enum Error {
     Malformed(String),
 
     /// Another libusb error
-    Usb(libusb::Error),
+    Usb(rusb::Error),
 
     /// Another IO error
     Io(io::Error),
+
+    /// The transfer was aborted through a `transfer::CancelHandle`
+    Cancelled,
+
+    /// The device doesn't list the operation, a CommandCode, in its OperationsSupported
+    Unsupported(u16),
 }
 
 impl fmt::Display for Error {
@@ -211,6 +435,9 @@ impl fmt::Display for Error {
             Error::Usb(ref e) => write!(f, "USB error: {}", e),
             Error::Io(ref e) => write!(f, "IO error: {}", e),
             Error::Malformed(ref e) => write!(f, "{}", e),
+            Error::Cancelled => write!(f, "Transfer cancelled"),
+            Error::Unsupported(c) => write!(f, "{} (0x{:04x}) is not supported by the device",
+                                            StandardCommandCode::name(c).unwrap_or("Operation"), c),
         }
     }
 }
@@ -225,8 +452,8 @@ impl ::std::error::Error for Error {
     }
 }
 
-impl From<libusb::Error> for Error {
-    fn from(e: libusb::Error) -> Error {
+impl From<rusb::Error> for Error {
+    fn from(e: rusb::Error) -> Error {
         Error::Usb(e)
     }
 }
@@ -360,8 +587,30 @@ impl<T: AsRef<[u8]>> PtpRead for Cursor<T> {
 }
 
 
+// PTP strings are a u8 length in UTF-16 code units, including the trailing null unless empty.
+// that leaves room for 254 units, so longer strings are cut short at a character boundary.
+fn write_ptp_str(out: &mut Vec<u8>, val: &str) {
+    let mut data: Vec<u16> = Vec::with_capacity(min(val.len(), 254));
+    for c in val.chars() {
+        let mut buf = [0u16; 2];
+        let units = c.encode_utf16(&mut buf);
+        if data.len() + units.len() > 254 {
+            warn!("string too long for PTP, truncated to {} UTF-16 units: {:?}", data.len(), val);
+            break;
+        }
+        data.extend_from_slice(units);
+    }
+    if data.is_empty() {
+        out.write_u8(0).ok();
+    } else {
+        out.write_u8((data.len() + 1) as u8).ok();
+        for e in data { out.write_u16::<LittleEndian>(e).ok(); }
+        out.write_all(b"\0\0").ok();
+    }
+}
+
 #[allow(non_snake_case)]
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum PtpDataType {
     UNDEF,
     INT8(i8),
@@ -488,11 +737,7 @@ impl PtpDataType {
                 }
             }
             &STR(ref val) => {
-                out.write_u8(((val.len() as u8) * 2) + 1).ok();
-                if val.len() > 0 {
-                    for e in val.encode_utf16() { out.write_u16::<LittleEndian>(e).ok(); }
-                    out.write_all(b"\0\0").ok();
-                }
+                write_ptp_str(&mut out, val);
             }
             _ => {}
         }
@@ -632,7 +877,7 @@ impl PtpDeviceInfo {
 }
 
 #[allow(dead_code)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct PtpObjectInfo {
     pub StorageID: u32,
     pub ObjectFormat: u16,
@@ -681,6 +926,30 @@ impl PtpObjectInfo {
             Keywords: cur.read_ptp_str()?,
         })
     }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        out.write_u32::<LittleEndian>(self.StorageID).ok();
+        out.write_u16::<LittleEndian>(self.ObjectFormat).ok();
+        out.write_u16::<LittleEndian>(self.ProtectionStatus).ok();
+        out.write_u32::<LittleEndian>(self.ObjectCompressedSize).ok();
+        out.write_u16::<LittleEndian>(self.ThumbFormat).ok();
+        out.write_u32::<LittleEndian>(self.ThumbCompressedSize).ok();
+        out.write_u32::<LittleEndian>(self.ThumbPixWidth).ok();
+        out.write_u32::<LittleEndian>(self.ThumbPixHeight).ok();
+        out.write_u32::<LittleEndian>(self.ImagePixWidth).ok();
+        out.write_u32::<LittleEndian>(self.ImagePixHeight).ok();
+        out.write_u32::<LittleEndian>(self.ImageBitDepth).ok();
+        out.write_u32::<LittleEndian>(self.ParentObject).ok();
+        out.write_u16::<LittleEndian>(self.AssociationType).ok();
+        out.write_u32::<LittleEndian>(self.AssociationDesc).ok();
+        out.write_u32::<LittleEndian>(self.SequenceNumber).ok();
+        write_ptp_str(&mut out, &self.Filename);
+        write_ptp_str(&mut out, &self.CaptureDate);
+        write_ptp_str(&mut out, &self.ModificationDate);
+        write_ptp_str(&mut out, &self.Keywords);
+        out
+    }
 }
 
 
@@ -728,6 +997,36 @@ pub enum PtpFormData {
     },
 }
 
+impl PtpFormData {
+    /// read the form following a FormFlag of `form_flag`, for values of type `data_type`.
+    /// flags other than range (0x01) and enumeration (0x02) have no form data.
+    pub fn decode<T: PtpRead>(form_flag: u8, data_type: u16, cur: &mut T) -> Result<PtpFormData, Error> {
+        Ok(match form_flag {
+            // 0x00 => PtpFormData::None,
+            0x01 => {
+                PtpFormData::Range {
+                    minValue: PtpDataType::read_type(data_type, cur)?,
+                    maxValue: PtpDataType::read_type(data_type, cur)?,
+                    step: PtpDataType::read_type(data_type, cur)?,
+                }
+            }
+            0x02 => {
+                PtpFormData::Enumeration {
+                    array: {
+                        let len = cur.read_u16::<LittleEndian>()? as usize;
+                        let mut arr = Vec::with_capacity(len);
+                        for _ in 0..len {
+                            arr.push(PtpDataType::read_type(data_type, cur)?);
+                        }
+                        arr
+                    },
+                }
+            }
+            _ => PtpFormData::None,
+        })
+    }
+}
+
 #[allow(non_snake_case)]
 #[derive(Debug)]
 pub struct PtpPropInfo {
@@ -753,51 +1052,27 @@ impl PtpPropInfo {
             IsEnable: cur.read_u8()?,
             FactoryDefault: PtpDataType::read_type(data_type, cur)?,
             Current: PtpDataType::read_type(data_type, cur)?,
-            Form: {
-                match cur.read_u8()? {
-                    // 0x00 => PtpFormData::None,
-                    0x01 => {
-                        PtpFormData::Range {
-                            minValue: PtpDataType::read_type(data_type, cur)?,
-                            maxValue: PtpDataType::read_type(data_type, cur)?,
-                            step: PtpDataType::read_type(data_type, cur)?,
-                        }
-                    }
-                    0x02 => {
-                        PtpFormData::Enumeration {
-                            array: {
-                                let len = cur.read_u16::<LittleEndian>()? as usize;
-                                let mut arr = Vec::with_capacity(len);
-                                for _ in 0..len {
-                                    arr.push(PtpDataType::read_type(data_type, cur)?);
-                                }
-                                arr
-                            },
-                        }
-                    }
-                    _ => PtpFormData::None,
-                }
-            },
+            Form: PtpFormData::decode(cur.read_u8()?, data_type, cur)?,
         })
     }
 }
 
 #[derive(Debug)]
-struct PtpContainerInfo {
+pub struct PtpContainerInfo {
     /// payload len in bytes, usually relevant for data phases
-    payload_len: usize,
+    pub payload_len: usize,
 
     /// Container kind
-    kind: PtpContainerType,
+    pub kind: PtpContainerType,
 
     /// StandardCommandCode or ResponseCode, depending on 'kind'
-    code: u16,
+    pub code: u16,
 
     /// transaction ID that this container belongs to
-    tid: u32,
+    pub tid: u32,
 }
 
-const PTP_CONTAINER_INFO_SIZE: usize = 12;
+pub const PTP_CONTAINER_INFO_SIZE: usize = 12;
 
 impl PtpContainerInfo {
     pub fn parse<R: ReadBytesExt>(mut r: R) -> Result<PtpContainerInfo, Error> {
@@ -808,6 +1083,10 @@ impl PtpContainerInfo {
         let code = r.read_u16::<LittleEndian>()?;
         let tid = r.read_u32::<LittleEndian>()?;
 
+        if (len as usize) < PTP_CONTAINER_INFO_SIZE {
+            return Err(Error::Malformed(format!("Invalid container length {}", len)));
+        }
+
         Ok(PtpContainerInfo {
             payload_len: len as usize - PTP_CONTAINER_INFO_SIZE,
             kind: kind,
@@ -816,52 +1095,247 @@ impl PtpContainerInfo {
         })
     }
 
+    /// serialize the container header; the payload follows it on the wire
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(PTP_CONTAINER_INFO_SIZE);
+        buf.write_u32::<LittleEndian>((self.payload_len + PTP_CONTAINER_INFO_SIZE) as u32).ok();
+        buf.write_u16::<LittleEndian>(self.kind as u16).ok();
+        buf.write_u16::<LittleEndian>(self.code).ok();
+        buf.write_u32::<LittleEndian>(self.tid).ok();
+        buf
+    }
+
     // does this container belong to the given transaction?
     pub fn belongs_to(&self, tid: u32) -> bool {
         self.tid == tid
     }
 }
 
-pub struct PtpCamera<'a> {
-    iface: u8,
-    ep_in: u8,
-    ep_out: u8,
-    _ep_int: u8,
-    current_tid: u32,
-    handle: libusb::DeviceHandle<'a>,
+/// The outcome of a transaction, see `PtpCamera::command_full`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PtpResponse {
+    /// StandardResponseCode or a vendor-defined code
+    pub code: ResponseCode,
+
+    /// up to five response parameters
+    pub params: Vec<u32>,
+
+    /// payload of the data-in phase, empty if there was none
+    pub data: Vec<u8>,
 }
 
-impl<'a> PtpCamera<'a> {
-    pub fn new(device: &libusb::Device<'a>) -> Result<PtpCamera<'a>, Error> {
-        let config_desc = device.active_config_descriptor()?;
+impl PtpResponse {
+    pub fn decode(cinfo: &PtpContainerInfo, payload: &[u8], data: Vec<u8>) -> Result<PtpResponse, Error> {
+        let mut cur = Cursor::new(payload);
+        let params = (0..payload.len() / 4).map(|_| cur.read_ptp_u32()).collect::<Result<_, _>>()?;
 
-        let interface_desc = config_desc.interfaces()
-            .flat_map(|i| i.descriptors())
-            .find(|x| x.class_code() == 6)
-            .ok_or(libusb::Error::NotFound)?;
+        Ok(PtpResponse {
+            code: cinfo.code,
+            params,
+            data,
+        })
+    }
 
-        debug!("Found interface {}", interface_desc.interface_number());
+    pub fn is_ok(&self) -> bool {
+        self.code == StandardResponseCode::Ok
+    }
 
-        let mut handle = device.open()?;
+    /// turn a response other than Ok into `Error::Response`
+    pub fn check(self) -> Result<PtpResponse, Error> {
+        if self.is_ok() {
+            Ok(self)
+        } else {
+            Err(Error::Response(self.code))
+        }
+    }
+}
+
+/// An asynchronous event reported by the responder
+#[derive(Debug, Clone, PartialEq)]
+pub struct PtpEvent {
+    /// StandardEventCode or a vendor-defined code
+    pub code: EventCode,
+
+    /// transaction ID the event relates to, if any
+    pub tid: u32,
+
+    /// up to three event parameters
+    pub params: Vec<u32>,
+}
+
+impl PtpEvent {
+    pub fn decode(cinfo: &PtpContainerInfo, payload: &[u8]) -> Result<PtpEvent, Error> {
+        if cinfo.kind != PtpContainerType::Event {
+            return Err(Error::Malformed(format!("Expected an event container, got {:?}", cinfo.kind)));
+        }
+
+        let mut cur = Cursor::new(payload);
+        let params = (0..payload.len() / 4).map(|_| cur.read_ptp_u32()).collect::<Result<_, _>>()?;
 
-        handle.claim_interface(interface_desc.interface_number())?;
-        handle.set_alternate_setting(interface_desc.interface_number(), interface_desc.setting_number())?;
+        Ok(PtpEvent {
+            code: cinfo.code,
+            tid: cinfo.tid,
+            params,
+        })
+    }
+}
 
-        let find_endpoint = |direction, transfer_type| {
-            interface_desc.endpoint_descriptors()
-                .find(|ep| ep.direction() == direction && ep.transfer_type() == transfer_type)
-                .map(|x| x.address())
-                .ok_or(libusb::Error::NotFound)
+/// The link between a `PtpCamera` and a responder.
+///
+/// `PtpCamera` drives transactions in terms of USB-style containers (see `PtpContainerInfo`);
+/// a transport is responsible for moving those containers, plus the asynchronous event stream
+/// and the out-of-band class requests, over whatever medium connects to the device.
+///
+/// A `timeout` of zero means no timeout.
+pub trait PtpTransport {
+    /// Send a single container of the given kind, with `payload` following the header.
+    fn write_container(&mut self,
+                       kind: PtpContainerType,
+                       code: u16,
+                       tid: u32,
+                       payload: &[u8],
+                       timeout: Duration)
+                       -> Result<(), Error>;
+
+    /// Receive the next container from the responder, along with its complete payload.
+    fn read_container(&mut self, timeout: Duration) -> Result<(PtpContainerInfo, Vec<u8>), Error>;
+
+    /// Wait for an event container. Returns `Ok(None)` if nothing arrived within `timeout`.
+    fn read_event(&mut self, timeout: Duration) -> Result<Option<(PtpContainerInfo, Vec<u8>)>, Error>;
+
+    /// Ask the responder to abort the transaction `tid`.
+    fn cancel(&mut self, tid: u32, timeout: Duration) -> Result<(), Error>;
+
+    /// Reset the responder's transport state, closing any open session.
+    fn reset(&mut self, timeout: Duration) -> Result<(), Error>;
+
+    /// Query the responder's status, returning a response code and any parameters.
+    fn status(&mut self, timeout: Duration) -> Result<(ResponseCode, Vec<u32>), Error>;
+
+    /// Release the underlying connection to the device.
+    fn close(&mut self) -> Result<(), Error>;
+
+    /// Discard whatever the responder is still sending for a transaction that was abandoned
+    /// part way through, typically after `cancel`, so the next transaction starts cleanly.
+    /// Reads give up after `timeout` of silence. The default does nothing.
+    fn drain(&mut self, _timeout: Duration) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Receive the next container like `read_container`, but hand the payload of a data
+    /// container to `sink` as it arrives instead of returning it; the returned payload is then
+    /// empty. The default implementation buffers the whole container first, transports that can
+    /// do better should override it.
+    fn read_container_into(&mut self, sink: &mut dyn DataSink, timeout: Duration) -> Result<(PtpContainerInfo, Vec<u8>), Error> {
+        let (cinfo, payload) = self.read_container(timeout)?;
+        if cinfo.kind != PtpContainerType::Data {
+            return Ok((cinfo, payload));
+        }
+
+        sink.begin(payload.len() as u64)?;
+        sink.write_chunk(&payload)?;
+        Ok((cinfo, vec![]))
+    }
+
+    /// Send a container like `write_container`, with a payload of exactly `len` bytes read from
+    /// `source`. The default implementation reads the whole payload into memory first, transports
+    /// that can do better should override it.
+    ///
+    /// If `source` fails, or ends early, the transaction is cancelled before the error is returned,
+    /// so the responder isn't left waiting for the rest of the data phase.
+    fn write_container_from(&mut self,
+                            kind: PtpContainerType,
+                            code: u16,
+                            tid: u32,
+                            len: u64,
+                            source: &mut dyn Read,
+                            timeout: Duration)
+                            -> Result<(), Error> {
+        let mut payload = Vec::with_capacity(len as usize);
+        let result = match source.take(len).read_to_end(&mut payload) {
+            Ok(n) if (n as u64) < len => Err(Error::Malformed(format!("Data source ended after {} of {} bytes", n, len))),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e.into()),
         };
+        if let Err(e) = result {
+            if let Err(e) = self.cancel(tid, timeout) {
+                warn!("failed to cancel transaction {} after its data source failed: {}", tid, e);
+            }
+            return Err(e);
+        }
+        self.write_container(kind, code, tid, &payload, timeout)
+    }
+}
+
+/// Destination of a streamed data phase, see `PtpTransport::read_container_into`.
+/// Implemented for every `io::Write`.
+pub trait DataSink {
+    /// called with the payload length of each data container, before any of its payload is
+    /// written. most responders send the data phase in a single container, those that split it
+    /// announce each part in turn, so the lengths add up to that of the data phase.
+    fn begin(&mut self, _len: u64) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn write_chunk(&mut self, chunk: &[u8]) -> Result<(), Error>;
+}
+
+impl<W: Write> DataSink for W {
+    fn write_chunk(&mut self, chunk: &[u8]) -> Result<(), Error> {
+        self.write_all(chunk)?;
+        Ok(())
+    }
+}
+
+pub struct PtpCamera<T: PtpTransport> {
+    current_tid: u32,
+    transport: T,
+    // OperationsSupported and EventsSupported from the last GetDeviceInfo, for capability checks
+    operations: Option<Vec<CommandCode>>,
+    events: Option<Vec<EventCode>>,
+}
 
-        Ok(PtpCamera {
-            iface: interface_desc.interface_number(),
-            ep_in:  find_endpoint(libusb::Direction::In, libusb::TransferType::Bulk)?,
-            ep_out: find_endpoint(libusb::Direction::Out, libusb::TransferType::Bulk)?,
-            _ep_int: find_endpoint(libusb::Direction::In, libusb::TransferType::Interrupt)?,
+impl PtpCamera<UsbTransport> {
+    pub fn new(device: &rusb::Device<rusb::Context>) -> Result<PtpCamera<UsbTransport>, Error> {
+        Ok(PtpCamera::with_transport(UsbTransport::new(device)?))
+    }
+
+    /// Open the first attached PTP device matching `selector`, see `UsbDeviceInfo::list`
+    /// for what can be matched on.
+    pub fn open(context: &rusb::Context, selector: &UsbDeviceSelector) -> Result<PtpCamera<UsbTransport>, Error> {
+        Ok(PtpCamera::with_transport(UsbTransport::open(context, selector)?))
+    }
+}
+
+impl<T: PtpTransport> PtpCamera<T> {
+    pub fn with_transport(transport: T) -> PtpCamera<T> {
+        PtpCamera {
             current_tid: 0,
-            handle: handle,
-        })
+            transport,
+            operations: None,
+            events: None,
+        }
+    }
+
+    /// the transport this camera communicates over
+    pub fn transport(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    pub fn into_transport(self) -> T {
+        self.transport
+    }
+
+    /// execute a PTP transaction, returning the data-phase payload, if any.
+    /// a response other than Ok is returned as `Error::Response`.
+    /// see `command_full` for the details of the transaction.
+    pub fn command(&mut self,
+                   code: CommandCode,
+                   params: &[u32],
+                   data: Option<&[u8]>,
+                   timeout: Option<Duration>)
+                   -> Result<Vec<u8>, Error> {
+        Ok(self.command_full(code, params, data, timeout)?.check()?.data)
     }
 
     /// execute a PTP transaction.
@@ -870,118 +1344,141 @@ impl<'a> PtpCamera<'a> {
     ///  - command data (optional, if `data` is Some)
     ///  - response data (optional, if response contains a payload)
     ///  - response status
-    /// NB: each phase involves a separate USB transfer, and `timeout` is used for each phase,
+    /// NB: each phase involves a separate transfer, and `timeout` is used for each phase,
     /// so the total time taken may be greater than `timeout`.
-    pub fn command(&mut self,
-                   code: CommandCode,
-                   params: &[u32],
-                   data: Option<&[u8]>,
-                   timeout: Option<Duration>)
-                   -> Result<Vec<u8>, Error> {
+    ///
+    /// unlike `command`, the response is returned whatever its code, along with its parameters.
+    pub fn command_full(&mut self,
+                        code: CommandCode,
+                        params: &[u32],
+                        data: Option<&[u8]>,
+                        timeout: Option<Duration>)
+                        -> Result<PtpResponse, Error> {
 
         // timeout of 0 means unlimited timeout.
         let timeout = timeout.unwrap_or(Duration::new(0, 0));
 
-        let tid = self.current_tid;
-        self.current_tid += 1;
+        let tid = self.begin_transaction(code, params, timeout)?;
 
-        // Prepare payload of the request phase, containing the parameters
-        let mut request_payload = Vec::with_capacity(params.len() * 4);
-        for p in params {
-            request_payload.write_u32::<LittleEndian>(*p).ok();
+        if let Some(data) = data {
+            self.transport.write_container(PtpContainerType::Data, code, tid, data, timeout)?;
         }
 
-        self.write_txn_phase(PtpContainerType::Command, code, tid, &request_payload, timeout)?;
+        self.finish_transaction(tid, timeout)
+    }
 
-        if let Some(data) = data {
-            self.write_txn_phase(PtpContainerType::Data, code, tid, data, timeout)?;
+    /// execute a PTP transaction whose data-out phase is `len` bytes streamed from `source`,
+    /// rather than a slice held in memory.
+    pub fn command_from(&mut self,
+                        code: CommandCode,
+                        params: &[u32],
+                        source: &mut dyn Read,
+                        len: u64,
+                        timeout: Option<Duration>)
+                        -> Result<PtpResponse, Error> {
+        let timeout = timeout.unwrap_or(Duration::new(0, 0));
+
+        let tid = self.begin_transaction(code, params, timeout)?;
+        self.transport.write_container_from(PtpContainerType::Data, code, tid, len, source, timeout)?;
+        self.finish_transaction(tid, timeout)
+    }
+
+    /// execute a PTP transaction without a data-out phase, streaming the data-in phase
+    /// into `sink` rather than holding it in memory. `data` of the returned response is empty.
+    pub fn command_into(&mut self,
+                        code: CommandCode,
+                        params: &[u32],
+                        sink: &mut dyn DataSink,
+                        timeout: Option<Duration>)
+                        -> Result<PtpResponse, Error> {
+        let timeout = timeout.unwrap_or(Duration::new(0, 0));
+
+        let tid = self.begin_transaction(code, params, timeout)?;
+        self.finish_transaction_into(tid, sink, timeout)
+    }
+
+    // like finish_transaction, but the data phase goes to `sink`
+    fn finish_transaction_into(&mut self, tid: u32, sink: &mut dyn DataSink, timeout: Duration) -> Result<PtpResponse, Error> {
+        loop {
+            let (container, payload) = self.transport.read_container_into(sink, timeout)?;
+            if !container.belongs_to(tid) {
+                return Err(Error::Malformed(format!("mismatched txnid {}, expecting {}", container.tid, tid)));
+            }
+            if container.kind == PtpContainerType::Response {
+                return PtpResponse::decode(&container, &payload, vec![]);
+            }
         }
+    }
 
-        // request phase is followed by data phase (optional) and response phase.
-        // read both, check the status on the response, and return the data payload, if any.
+    // request phase is followed by data phase (optional) and response phase.
+    // read both, and return the response along with the data payload, if any.
+    fn finish_transaction(&mut self, tid: u32, timeout: Duration) -> Result<PtpResponse, Error> {
         let mut data_phase_payload = vec![];
         loop {
-            let (container, payload) = self.read_txn_phase(timeout)?;
+            let (container, payload) = self.transport.read_container(timeout)?;
             if !container.belongs_to(tid) {
                 return Err(Error::Malformed(format!("mismatched txnid {}, expecting {}", container.tid, tid)));
             }
             match container.kind {
                 PtpContainerType::Data => {
-                    data_phase_payload = payload;
+                    // some responders split the data phase into several containers
+                    if data_phase_payload.is_empty() {
+                        data_phase_payload = payload;
+                    } else {
+                        data_phase_payload.extend_from_slice(&payload);
+                    }
                 },
                 PtpContainerType::Response => {
-                    if container.code != StandardResponseCode::Ok {
-                        return Err(Error::Response(container.code));
-                    }
-                    return Ok(data_phase_payload);
+                    return PtpResponse::decode(&container, &payload, data_phase_payload);
                 },
                 _ => {}
             }
         }
     }
 
-    fn write_txn_phase(&mut self, kind: PtpContainerType, code: CommandCode, tid: u32, payload: &[u8], timeout: Duration) -> Result<(), Error> {
-        trace!("Write {:?} - 0x{:04x} ({}), tid:{}", kind, code, StandardCommandCode::name(code).unwrap_or("unknown"), tid);
-
-        const CHUNK_SIZE: usize = 1024 * 1024; // 1MB, must be a multiple of the endpoint packet size
-
-        // The first chunk contains the header, and its payload must be copied into the temporary buffer
-        let first_chunk_payload_bytes = min(payload.len(), CHUNK_SIZE - PTP_CONTAINER_INFO_SIZE);
-        let mut buf = Vec::with_capacity(first_chunk_payload_bytes + PTP_CONTAINER_INFO_SIZE);
-        buf.write_u32::<LittleEndian>((payload.len() + PTP_CONTAINER_INFO_SIZE) as u32).ok();
-        buf.write_u16::<LittleEndian>(kind as u16).ok();
-        buf.write_u16::<LittleEndian>(code).ok();
-        buf.write_u32::<LittleEndian>(tid).ok();
-        buf.extend_from_slice(&payload[..first_chunk_payload_bytes]);
-        self.handle.write_bulk(self.ep_out, &buf, timeout)?;
+    // send the command phase of a new transaction, returning its transaction ID
+    fn begin_transaction(&mut self, code: CommandCode, params: &[u32], timeout: Duration) -> Result<u32, Error> {
+        let tid = self.current_tid;
+        self.current_tid += 1;
 
-        // Write any subsequent chunks, straight from the source slice
-        for chunk in payload[first_chunk_payload_bytes..].chunks(CHUNK_SIZE) {
-            self.handle.write_bulk(self.ep_out, chunk, timeout)?;
+        // Prepare payload of the request phase, containing the parameters
+        let mut request_payload = Vec::with_capacity(params.len() * 4);
+        for p in params {
+            request_payload.write_u32::<LittleEndian>(*p).ok();
         }
 
-        Ok(())
+        self.transport.write_container(PtpContainerType::Command, code, tid, &request_payload, timeout)?;
+        Ok(tid)
     }
 
-    // helper for command() above, retrieve container info and payload for the current phase
-    fn read_txn_phase(&mut self, timeout: Duration) -> Result<(PtpContainerInfo, Vec<u8>), Error> {
-        // buf is stack allocated and intended to be large enough to accomodate most
-        // cmd/ctrl data (ie, not media) without allocating. payload handling below
-        // deals with larger media responses. mark it as uninitalized to avoid paying
-        // for zeroing out 8k of memory, since rust doesn't know what libusb does with this memory.
-        let mut unintialized_buf: [u8; 8 * 1024];
-        let buf = unsafe {
-            unintialized_buf = ::std::mem::uninitialized();
-            let n = self.handle.read_bulk(self.ep_in, &mut unintialized_buf[..], timeout)?;
-            &unintialized_buf[..n]
-        };
-
-        let cinfo = PtpContainerInfo::parse(&buf[..])?;
-        trace!("container {:?}", cinfo);
-
-        // no payload? we're done
-        if cinfo.payload_len == 0 {
-            return Ok((cinfo, vec![]));
-        }
+    /// Wait for the next event from the responder.
+    /// Returns `Ok(None)` if no event arrives within `timeout`; `None` waits indefinitely.
+    pub fn poll_event(&mut self, timeout: Option<Duration>) -> Result<Option<PtpEvent>, Error> {
+        let timeout = timeout.unwrap_or(Duration::new(0, 0));
 
-        // allocate one extra to avoid a separate read for trailing short packet
-        let mut payload = Vec::with_capacity(cinfo.payload_len + 1);
-        payload.extend_from_slice(&buf[PTP_CONTAINER_INFO_SIZE..]);
-
-        // response didn't fit into our original buf? read the rest
-        // or if our original read were satisfied exactly, so there is still a ZLP to read
-        if payload.len() < cinfo.payload_len || buf.len() == unintialized_buf.len() {
-            unsafe {
-                let p = payload.as_mut_ptr().offset(payload.len() as isize);
-                let pslice = slice::from_raw_parts_mut(p, payload.capacity() - payload.len());
-                let n = self.handle.read_bulk(self.ep_in, pslice, timeout)?;
-                let sz = payload.len();
-                payload.set_len(sz + n);
-                trace!("  bulk rx {}, ({}/{})", n, payload.len(), payload.capacity());
+        match self.transport.read_event(timeout)? {
+            Some((cinfo, payload)) => {
+                let event = PtpEvent::decode(&cinfo, &payload)?;
+                if event.code == StandardEventCode::DeviceInfoChanged {
+                    self.operations = None;
+                    self.events = None;
+                }
+                debug!("event 0x{:04x} ({}), tid:{}, params {:?}",
+                       event.code, StandardEventCode::name(event.code).unwrap_or("unknown"), event.tid, event.params);
+                Ok(Some(event))
             }
+            None => Ok(None),
         }
+    }
 
-        Ok((cinfo, payload))
+    /// Iterate over events as they arrive. Iteration ends once no event has arrived
+    /// within `timeout`, or after an error has been returned.
+    pub fn events(&mut self, timeout: Option<Duration>) -> PtpEvents<'_, T> {
+        PtpEvents {
+            camera: self,
+            timeout,
+            done: false,
+        }
     }
 
     pub fn get_objectinfo(&mut self, handle: u32, timeout: Option<Duration>) -> Result<PtpObjectInfo, Error> {
@@ -993,10 +1490,70 @@ impl<'a> PtpCamera<'a> {
         self.command(StandardCommandCode::GetObject, &[handle], None, timeout)
     }
 
+    /// download an object straight into `sink`, without holding it in memory
+    pub fn get_object_to<W: Write>(&mut self, handle: u32, sink: &mut W, timeout: Option<Duration>) -> Result<(), Error> {
+        self.command_into(StandardCommandCode::GetObject, &[handle], sink, timeout)?.check()?;
+        Ok(())
+    }
+
     pub fn get_partialobject(&mut self, handle: u32, offset: u32, max: u32, timeout: Option<Duration>) -> Result<Vec<u8>, Error> {
         self.command(StandardCommandCode::GetPartialObject, &[handle, offset, max], None, timeout)
     }
 
+    /// announce a new object with SendObjectInfo, which must be followed by SendObject.
+    /// `parent` may be 0xFFFFFFFF for the root of the store, and `storage_id` 0 to let the
+    /// responder choose. returns the storage, parent and handle the responder assigned.
+    pub fn send_object_info(&mut self,
+                            storage_id: u32,
+                            parent: u32,
+                            info: &PtpObjectInfo,
+                            timeout: Option<Duration>)
+                            -> Result<(u32, u32, u32), Error> {
+        let response = self.command_full(StandardCommandCode::SendObjectInfo,
+                                         &[storage_id, parent],
+                                         Some(&info.encode()), timeout)?.check()?;
+        match response.params[..] {
+            [storage_id, parent, handle, ..] => Ok((storage_id, parent, handle)),
+            _ => Err(Error::Malformed(format!("SendObjectInfo returned {} parameters, expected 3", response.params.len()))),
+        }
+    }
+
+    /// upload a new object, returning its handle. `info.ObjectCompressedSize` is set from `data`.
+    pub fn send_object(&mut self,
+                       storage_id: u32,
+                       parent: u32,
+                       info: &PtpObjectInfo,
+                       data: &[u8],
+                       timeout: Option<Duration>)
+                       -> Result<u32, Error> {
+        let mut info = info.clone();
+        // as in send_object_from, objects of 4GB and over are reported as 0xFFFFFFFF
+        info.ObjectCompressedSize = min(data.len() as u64, 0xFFFFFFFF) as u32;
+
+        let (_, _, handle) = self.send_object_info(storage_id, parent, &info, timeout)?;
+        self.command(StandardCommandCode::SendObject, &[], Some(data), timeout)?;
+        Ok(handle)
+    }
+
+    /// upload a new object of `len` bytes read from `source`, without holding it in memory.
+    /// returns the new object's handle. `info.ObjectCompressedSize` is set from `len`.
+    pub fn send_object_from<R: Read>(&mut self,
+                                     storage_id: u32,
+                                     parent: u32,
+                                     info: &PtpObjectInfo,
+                                     source: &mut R,
+                                     len: u64,
+                                     timeout: Option<Duration>)
+                                     -> Result<u32, Error> {
+        let mut info = info.clone();
+        // objects of 4GB and over have their size reported as 0xFFFFFFFF
+        info.ObjectCompressedSize = min(len, 0xFFFFFFFF) as u32;
+
+        let (_, _, handle) = self.send_object_info(storage_id, parent, &info, timeout)?;
+        self.command_from(StandardCommandCode::SendObject, &[], source, len, timeout)?.check()?;
+        Ok(handle)
+    }
+
     pub fn delete_object(&mut self, handle: u32, timeout: Option<Duration>) -> Result<(), Error> {
         self.command(StandardCommandCode::DeleteObject, &[handle], None, timeout).map(|_| ())
     }
@@ -1005,6 +1562,59 @@ impl<'a> PtpCamera<'a> {
         self.command(StandardCommandCode::PowerDown, &[], None, timeout).map(|_| ())
     }
 
+    /// the thumbnail of an object, in the format given by its ThumbFormat
+    pub fn get_thumb(&mut self, handle: u32, timeout: Option<Duration>) -> Result<Vec<u8>, Error> {
+        self.require(StandardCommandCode::GetThumb, timeout)?;
+        self.command(StandardCommandCode::GetThumb, &[handle], None, timeout)
+    }
+
+    /// erase a store. `filesystem` is a FilesystemType to format it with, or None to let the
+    /// responder choose.
+    pub fn format_store(&mut self, storage_id: u32, filesystem: Option<u16>, timeout: Option<Duration>) -> Result<(), Error> {
+        self.require(StandardCommandCode::FormatStore, timeout)?;
+        self.command(StandardCommandCode::FormatStore, &[storage_id, filesystem.unwrap_or(0) as u32], None, timeout)
+            .map(|_| ())
+    }
+
+    /// return the device to its default state. this closes the session.
+    pub fn reset_device(&mut self, timeout: Option<Duration>) -> Result<(), Error> {
+        self.require(StandardCommandCode::ResetDevice, timeout)?;
+        self.command(StandardCommandCode::ResetDevice, &[], None, timeout).map(|_| ())
+    }
+
+    /// run a self test, 0 being the default test; others are vendor-defined
+    pub fn self_test(&mut self, test_type: u16, timeout: Option<Duration>) -> Result<(), Error> {
+        self.require(StandardCommandCode::SelfTest, timeout)?;
+        self.command(StandardCommandCode::SelfTest, &[test_type as u32], None, timeout).map(|_| ())
+    }
+
+    /// set an object's ProtectionStatus: 0 for no protection, 1 for read-only
+    pub fn set_object_protection(&mut self, handle: u32, protection: u16, timeout: Option<Duration>) -> Result<(), Error> {
+        self.require(StandardCommandCode::SetObjectProtection, timeout)?;
+        self.command(StandardCommandCode::SetObjectProtection, &[handle, protection as u32], None, timeout)
+            .map(|_| ())
+    }
+
+    /// move an object to `parent` on `storage_id`; `parent` may be 0 for the root of the store.
+    /// the object keeps its handle.
+    pub fn move_object(&mut self, handle: u32, storage_id: u32, parent: u32, timeout: Option<Duration>) -> Result<(), Error> {
+        self.require(StandardCommandCode::MoveObject, timeout)?;
+        self.command(StandardCommandCode::MoveObject, &[handle, storage_id, parent], None, timeout).map(|_| ())
+    }
+
+    /// copy an object to `parent` on `storage_id`, returning the handle of the copy.
+    /// `parent` may be 0 for the root of the store.
+    pub fn copy_object(&mut self, handle: u32, storage_id: u32, parent: u32, timeout: Option<Duration>) -> Result<u32, Error> {
+        self.require(StandardCommandCode::CopyObject, timeout)?;
+        let response = self.command_full(StandardCommandCode::CopyObject,
+                                         &[handle, storage_id, parent],
+                                         None, timeout)?.check()?;
+        match response.params.first() {
+            Some(&handle) => Ok(handle),
+            None => Err(Error::Malformed("CopyObject returned no handle".to_owned())),
+        }
+    }
+
     pub fn get_objecthandles(&mut self,
                              storage_id: u32,
                              handle_id: u32,
@@ -1068,6 +1678,42 @@ impl<'a> PtpCamera<'a> {
         Ok(res)
     }
 
+    pub fn get_device_prop_desc(&mut self, prop_code: u16, timeout: Option<Duration>) -> Result<PtpPropInfo, Error> {
+        let data = self.command(StandardCommandCode::GetDevicePropDesc, &[prop_code as u32], None, timeout)?;
+
+        let mut cur = Cursor::new(data);
+        let res = PtpPropInfo::decode(&mut cur)?;
+        cur.expect_end()?;
+
+        Ok(res)
+    }
+
+    /// read the current value of a device property, decoded according to the DataType in its description.
+    /// this costs an extra GetDevicePropDesc round trip; use `get_device_prop_value_as` if the type is known.
+    pub fn get_device_prop_value(&mut self, prop_code: u16, timeout: Option<Duration>) -> Result<PtpDataType, Error> {
+        let desc = self.get_device_prop_desc(prop_code, timeout)?;
+        self.get_device_prop_value_as(prop_code, desc.DataType, timeout)
+    }
+
+    pub fn get_device_prop_value_as(&mut self, prop_code: u16, data_type: u16, timeout: Option<Duration>) -> Result<PtpDataType, Error> {
+        let data = self.command(StandardCommandCode::GetDevicePropValue, &[prop_code as u32], None, timeout)?;
+
+        let mut cur = Cursor::new(data);
+        let value = PtpDataType::read_type(data_type, &mut cur)?;
+        cur.expect_end()?;
+
+        Ok(value)
+    }
+
+    pub fn set_device_prop_value(&mut self, prop_code: u16, value: &PtpDataType, timeout: Option<Duration>) -> Result<(), Error> {
+        let data = value.encode();
+        self.command(StandardCommandCode::SetDevicePropValue, &[prop_code as u32], Some(&data), timeout).map(|_| ())
+    }
+
+    pub fn reset_device_prop_value(&mut self, prop_code: u16, timeout: Option<Duration>) -> Result<(), Error> {
+        self.command(StandardCommandCode::ResetDevicePropValue, &[prop_code as u32], None, timeout).map(|_| ())
+    }
+
     pub fn get_storageids(&mut self, timeout: Option<Duration>) -> Result<Vec<u32>, Error> {
         let data = self.command(StandardCommandCode::GetStorageIDs, &[], None, timeout)?;
 
@@ -1096,9 +1742,33 @@ impl<'a> PtpCamera<'a> {
 
         let device_info = PtpDeviceInfo::decode(&data)?;
         debug!("device_info {:?}", device_info);
+        self.operations = Some(device_info.OperationsSupported.clone());
+        self.events = Some(device_info.EventsSupported.clone());
         Ok(device_info)
     }
 
+    /// whether the device lists `code` in its OperationsSupported. the list is fetched with
+    /// GetDeviceInfo the first time, and again after a DeviceInfoChanged event.
+    pub fn supports(&mut self, code: CommandCode, timeout: Option<Duration>) -> Result<bool, Error> {
+        if self.operations.is_none() {
+            self.get_device_info(timeout)?;
+        }
+        Ok(self.operations.as_ref().is_some_and(|ops| ops.contains(&code)))
+    }
+
+    /// whether the device lists `code` in its EventsSupported, fetched like `supports`
+    pub fn supports_event(&mut self, code: EventCode, timeout: Option<Duration>) -> Result<bool, Error> {
+        if self.events.is_none() {
+            self.get_device_info(timeout)?;
+        }
+        Ok(self.events.as_ref().is_some_and(|events| events.contains(&code)))
+    }
+
+    // fail with Error::Unsupported, without a round trip, if the device doesn't support `code`
+    fn require(&mut self, code: CommandCode, timeout: Option<Duration>) -> Result<(), Error> {
+        if self.supports(code, timeout)? { Ok(()) } else { Err(Error::Unsupported(code)) }
+    }
+
     pub fn open_session(&mut self, timeout: Option<Duration>) -> Result<(), Error> {
         let session_id = 3;
 
@@ -1117,11 +1787,40 @@ impl<'a> PtpCamera<'a> {
 
     pub fn disconnect(&mut self, timeout: Option<Duration>) -> Result<(), Error> {
         self.close_session(timeout)?;
-        self.handle.release_interface(self.iface)?;
+        self.transport.close()?;
         Ok(())
     }
 }
 
+/// Iterator over the events of a `PtpCamera`, see `PtpCamera::events`
+pub struct PtpEvents<'c, T: PtpTransport> {
+    camera: &'c mut PtpCamera<T>,
+    timeout: Option<Duration>,
+    done: bool,
+}
+
+impl<'c, T: PtpTransport> Iterator for PtpEvents<'c, T> {
+    type Item = Result<PtpEvent, Error>;
+
+    fn next(&mut self) -> Option<Result<PtpEvent, Error>> {
+        if self.done {
+            return None;
+        }
+
+        match self.camera.poll_event(self.timeout) {
+            Ok(Some(event)) => Some(Ok(event)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct PtpObjectTree {
     pub handle: u32,
@@ -1154,3 +1853,257 @@ impl PtpObjectTree {
         output
     }
 }
+
+/// Options for `PtpCamera::object_tree`
+#[derive(Debug, Clone, Default)]
+pub struct ObjectTreeOptions {
+    max_depth: Option<usize>,
+    formats: Option<Vec<ObjectFormatCode>>,
+    skip_invalid: bool,
+}
+
+impl ObjectTreeOptions {
+    pub fn new() -> ObjectTreeOptions {
+        ObjectTreeOptions::default()
+    }
+
+    /// descend at most `depth` associations below the root; 0 lists the root only.
+    /// associations at the limit are returned with `children` set to `None`.
+    pub fn max_depth(mut self, depth: usize) -> ObjectTreeOptions {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// only return objects of these formats. associations are always walked and
+    /// returned, so the folder structure is kept even if a folder ends up empty.
+    pub fn formats(mut self, formats: &[ObjectFormatCode]) -> ObjectTreeOptions {
+        self.formats = Some(formats.to_vec());
+        self
+    }
+
+    /// leave out objects that fail with InvalidObjectHandle, as happens when they are deleted
+    /// while the tree is being read, instead of failing the whole walk
+    pub fn skip_invalid(mut self, skip: bool) -> ObjectTreeOptions {
+        self.skip_invalid = skip;
+        self
+    }
+}
+
+impl<T: PtpTransport> PtpCamera<T> {
+    /// Read the hierarchy of objects on a store, following associations (folders) down
+    /// from the root.
+    pub fn object_tree(&mut self,
+                       storage_id: u32,
+                       options: &ObjectTreeOptions,
+                       timeout: Option<Duration>)
+                       -> Result<Vec<PtpObjectTree>, Error> {
+        self.object_subtree(storage_id, 0xFFFFFFFF, 0, options, timeout)
+    }
+
+    /// `object_tree` for every store on the device, along with each store's ID
+    pub fn object_trees(&mut self,
+                        options: &ObjectTreeOptions,
+                        timeout: Option<Duration>)
+                        -> Result<Vec<(u32, Vec<PtpObjectTree>)>, Error> {
+        let mut trees = vec![];
+        for storage_id in self.get_storageids(timeout)? {
+            trees.push((storage_id, self.object_tree(storage_id, options, timeout)?));
+        }
+        Ok(trees)
+    }
+
+    fn object_subtree(&mut self,
+                      storage_id: u32,
+                      parent: u32,
+                      depth: usize,
+                      options: &ObjectTreeOptions,
+                      timeout: Option<Duration>)
+                      -> Result<Vec<PtpObjectTree>, Error> {
+        let tolerate = |e: &Error| {
+            options.skip_invalid && matches!(*e, Error::Response(StandardResponseCode::InvalidObjectHandle))
+        };
+
+        let mut objects = vec![];
+        for handle in self.get_objecthandles(storage_id, parent, None, timeout)? {
+            let info = match self.get_objectinfo(handle, timeout) {
+                Ok(info) => info,
+                Err(ref e) if tolerate(e) => {
+                    debug!("skipping object {}: {}", handle, e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            let children = if info.ObjectFormat != StandardObjectFormatCode::Association {
+                if let Some(ref formats) = options.formats {
+                    if !formats.contains(&info.ObjectFormat) {
+                        continue;
+                    }
+                }
+                None
+            } else if options.max_depth.is_some_and(|max| depth >= max) {
+                None
+            } else {
+                match self.object_subtree(storage_id, handle, depth + 1, options, timeout) {
+                    Ok(children) => Some(children),
+                    Err(ref e) if tolerate(e) => {
+                        debug!("skipping association {}: {}", handle, e);
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                }
+            };
+
+            objects.push(PtpObjectTree { handle, info, children });
+        }
+        Ok(objects)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::mock::MockTransport;
+
+    fn round_trip(s: &str) -> String {
+        let mut buf = vec![];
+        write_ptp_str(&mut buf, s);
+        let mut cur = Cursor::new(&buf[..]);
+        let read = cur.read_ptp_str().unwrap();
+        cur.expect_end().unwrap();
+        read
+    }
+
+    #[test]
+    fn ptp_str_round_trip() {
+        assert_eq!(round_trip(""), "");
+        assert_eq!(round_trip("IMG_0001.JPG"), "IMG_0001.JPG");
+        // outside the BMP, so encoded as a surrogate pair
+        assert_eq!(round_trip("caf\u{e9} \u{1F4F7}.jpg"), "caf\u{e9} \u{1F4F7}.jpg");
+    }
+
+    #[test]
+    fn ptp_str_truncated() {
+        let long = "a".repeat(300);
+        assert_eq!(round_trip(&long), "a".repeat(254));
+
+        // a surrogate pair that would straddle the limit is dropped whole
+        let straddling = format!("{}\u{1F4F7}", "a".repeat(253));
+        assert_eq!(round_trip(&straddling), "a".repeat(253));
+        let fitting = format!("{}\u{1F4F7}", "a".repeat(252));
+        assert_eq!(round_trip(&fitting), fitting);
+    }
+    fn handles(handles: &[u32]) -> Vec<u8> {
+        let mut data = (handles.len() as u32).to_le_bytes().to_vec();
+        for handle in handles {
+            data.extend_from_slice(&handle.to_le_bytes());
+        }
+        data
+    }
+
+    fn expect_object(mock: &mut MockTransport, handle: u32, name: &str, format: ObjectFormatCode) {
+        let info = PtpObjectInfo { Filename: name.to_owned(), ObjectFormat: format, ..Default::default() };
+        mock.expect(StandardCommandCode::GetObjectInfo, &[handle]).reply_data(&info.encode());
+    }
+
+    fn expect_listing(mock: &mut MockTransport, storage_id: u32, parent: u32, children: &[u32]) {
+        mock.expect(StandardCommandCode::GetObjectHandles, &[storage_id, 0, parent]).reply_data(&handles(children));
+    }
+
+    fn paths(trees: &[PtpObjectTree]) -> Vec<String> {
+        trees.iter().flat_map(|tree| tree.walk()).map(|(path, _)| path).collect()
+    }
+
+    #[test]
+    fn object_tree_depth() {
+        for depth in 0..3 {
+            let mut mock = MockTransport::new();
+            expect_listing(&mut mock, 1, 0xFFFFFFFF, &[1]);
+            expect_object(&mut mock, 1, "DCIM", StandardObjectFormatCode::Association);
+            if depth > 0 {
+                expect_listing(&mut mock, 1, 1, &[2]);
+                expect_object(&mut mock, 2, "100CANON", StandardObjectFormatCode::Association);
+            }
+            if depth > 1 {
+                expect_listing(&mut mock, 1, 2, &[3]);
+                expect_object(&mut mock, 3, "IMG_0001.JPG", StandardObjectFormatCode::EXIF_JPEG);
+            }
+            let mut camera = PtpCamera::with_transport(mock);
+
+            let trees = camera.object_tree(1, &ObjectTreeOptions::new().max_depth(depth), None).unwrap();
+            let expected = ["DCIM", "DCIM/100CANON", "DCIM/100CANON/IMG_0001.JPG"];
+            assert_eq!(paths(&trees), expected[..depth + 1].to_vec());
+            // whatever is at the limit has no children, and if it is an association it isn't listed
+            let mut last = &trees[0];
+            for _ in 0..depth {
+                last = &last.children.as_ref().unwrap()[0];
+            }
+            assert!(last.children.is_none());
+            camera.transport().verify();
+        }
+    }
+
+    #[test]
+    fn object_tree_formats() {
+        let mut mock = MockTransport::new();
+        expect_listing(&mut mock, 1, 0xFFFFFFFF, &[1, 2, 3]);
+        expect_object(&mut mock, 1, "DCIM", StandardObjectFormatCode::Association);
+        expect_listing(&mut mock, 1, 1, &[4, 5]);
+        expect_object(&mut mock, 4, "IMG_0001.JPG", StandardObjectFormatCode::EXIF_JPEG);
+        expect_object(&mut mock, 5, "IMG_0001.CR2", StandardObjectFormatCode::Undefined);
+        expect_object(&mut mock, 2, "MISC", StandardObjectFormatCode::Association);
+        expect_listing(&mut mock, 1, 2, &[6]);
+        expect_object(&mut mock, 6, "README.TXT", StandardObjectFormatCode::Text);
+        expect_object(&mut mock, 3, "IMG_0002.JPG", StandardObjectFormatCode::EXIF_JPEG);
+        let mut camera = PtpCamera::with_transport(mock);
+
+        let options = ObjectTreeOptions::new().formats(&[StandardObjectFormatCode::EXIF_JPEG]);
+        let trees = camera.object_tree(1, &options, None).unwrap();
+        // the folders are kept, even once empty
+        assert_eq!(paths(&trees), vec!["DCIM", "DCIM/IMG_0001.JPG", "MISC", "IMG_0002.JPG"]);
+        assert_eq!(trees[1].children.as_ref().map(|c| c.len()), Some(0));
+        camera.transport().verify();
+    }
+
+    // an object that disappears before its info is read, and a folder before it is listed
+    fn expect_vanishing(mock: &mut MockTransport) {
+        expect_listing(mock, 1, 0xFFFFFFFF, &[1, 2, 3]);
+        mock.expect(StandardCommandCode::GetObjectInfo, &[1]).respond(StandardResponseCode::InvalidObjectHandle, &[]);
+        expect_object(mock, 2, "DCIM", StandardObjectFormatCode::Association);
+        mock.expect(StandardCommandCode::GetObjectHandles, &[1, 0, 2]).respond(StandardResponseCode::InvalidObjectHandle, &[]);
+    }
+
+    #[test]
+    fn object_tree_skip_invalid() {
+        let mut mock = MockTransport::new();
+        expect_vanishing(&mut mock);
+        expect_object(&mut mock, 3, "IMG_0001.JPG", StandardObjectFormatCode::EXIF_JPEG);
+        let mut camera = PtpCamera::with_transport(mock);
+
+        let trees = camera.object_tree(1, &ObjectTreeOptions::new().skip_invalid(true), None).unwrap();
+        assert_eq!(paths(&trees), vec!["IMG_0001.JPG"]);
+        camera.transport().verify();
+
+        let mut mock = MockTransport::new();
+        expect_vanishing(&mut mock);
+        let mut camera = PtpCamera::with_transport(mock);
+        let result = camera.object_tree(1, &ObjectTreeOptions::new(), None);
+        assert!(matches!(result, Err(Error::Response(StandardResponseCode::InvalidObjectHandle))));
+    }
+
+    #[test]
+    fn object_trees_of_every_store() {
+        let mut mock = MockTransport::new();
+        mock.expect(StandardCommandCode::GetStorageIDs, &[]).reply_data(&handles(&[0x00010001, 0x00020001]));
+        expect_listing(&mut mock, 0x00010001, 0xFFFFFFFF, &[1]);
+        expect_object(&mut mock, 1, "A.JPG", StandardObjectFormatCode::EXIF_JPEG);
+        expect_listing(&mut mock, 0x00020001, 0xFFFFFFFF, &[2]);
+        expect_object(&mut mock, 2, "B.JPG", StandardObjectFormatCode::EXIF_JPEG);
+        let mut camera = PtpCamera::with_transport(mock);
+
+        let trees = camera.object_trees(&ObjectTreeOptions::new(), None).unwrap();
+        assert_eq!(trees.iter().map(|(id, tree)| (*id, paths(tree))).collect::<Vec<_>>(),
+                   vec![(0x00010001, vec!["A.JPG".to_owned()]), (0x00020001, vec!["B.JPG".to_owned()])]);
+        camera.transport().verify();
+    }
+}
@@ -0,0 +1,368 @@
+//! A scriptable in-process responder, for exercising `PtpCamera` without hardware.
+//!
+//! `MockTransport` speaks the USB container format: everything it hands back to the camera is
+//! serialized to raw container bytes and parsed again with `PtpContainerInfo::parse`, exactly as
+//! if it had come off the bulk endpoint. Tests queue the transactions they expect, along with the
+//! data and response the responder should send back, and the mock panics as soon as the camera
+//! sends something else.
+//!
+//! ```
+//! use ptp::{PtpCamera, StandardCommandCode, StandardResponseCode};
+//! use ptp::mock::MockTransport;
+//!
+//! let mut mock = MockTransport::new();
+//! mock.expect(StandardCommandCode::OpenSession, &[3, 0, 0]).tid(0);
+//! mock.expect(StandardCommandCode::GetStorageIDs, &[])
+//!     .reply_data(&[1, 0, 0, 0, 0x01, 0x00, 0x01, 0x00]);
+//! mock.expect(StandardCommandCode::DeleteObject, &[42])
+//!     .respond(StandardResponseCode::ObjectWriteProtected, &[]);
+//!
+//! let mut camera = PtpCamera::with_transport(mock);
+//! camera.open_session(None).unwrap();
+//! assert_eq!(camera.get_storageids(None).unwrap(), vec![0x00010001]);
+//! assert!(camera.delete_object(42, None).is_err());
+//! camera.transport().verify();
+//! ```
+
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+use std::collections::VecDeque;
+use std::io::Cursor;
+use std::io;
+use std::time::Duration;
+
+use super::{Error, PtpContainerInfo, PtpContainerType, PtpTransport, CommandCode, EventCode,
+            ResponseCode, StandardCommandCode, StandardResponseCode, PTP_CONTAINER_INFO_SIZE};
+
+/// One scripted transaction: what the camera is expected to send, and what to send back
+#[derive(Debug, Clone)]
+pub struct MockTransaction {
+    code: CommandCode,
+    params: Vec<u32>,
+    tid: Option<u32>,
+    expect_data: Option<Vec<u8>>,
+    reply_data: Vec<Vec<u8>>,
+    response: ResponseCode,
+    response_params: Vec<u32>,
+}
+
+impl MockTransaction {
+    /// expect the command to carry this exact transaction ID
+    pub fn tid(&mut self, tid: u32) -> &mut MockTransaction {
+        self.tid = Some(tid);
+        self
+    }
+
+    /// expect a data-out phase with exactly this payload
+    pub fn expect_data(&mut self, data: &[u8]) -> &mut MockTransaction {
+        self.expect_data = Some(data.to_vec());
+        self
+    }
+
+    /// send a data-in phase with this payload before the response. called again, the data
+    /// phase is split over several containers.
+    pub fn reply_data(&mut self, data: &[u8]) -> &mut MockTransaction {
+        self.reply_data.push(data.to_vec());
+        self
+    }
+
+    /// respond with this code and parameters, instead of a bare Ok
+    pub fn respond(&mut self, code: ResponseCode, params: &[u32]) -> &mut MockTransaction {
+        self.response = code;
+        self.response_params = params.to_vec();
+        self
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MockTransport {
+    script: VecDeque<MockTransaction>,
+    // a transaction whose command arrived, still waiting for its data-out phase
+    current: Option<MockTransaction>,
+    last_tid: Option<u32>,
+    // raw containers waiting to be read by the camera
+    incoming: VecDeque<Vec<u8>>,
+    events: VecDeque<Vec<u8>>,
+    status: Option<(ResponseCode, Vec<u32>)>,
+    queued_status: VecDeque<(ResponseCode, Vec<u32>)>,
+    cancelled: Vec<u32>,
+    resets: usize,
+    closed: bool,
+}
+
+impl MockTransport {
+    pub fn new() -> MockTransport {
+        MockTransport::default()
+    }
+
+    /// Script the next transaction. The camera must send `code` with exactly `params`;
+    /// unless configured otherwise the responder answers with a bare Ok.
+    pub fn expect(&mut self, code: CommandCode, params: &[u32]) -> &mut MockTransaction {
+        self.script.push_back(MockTransaction {
+            code,
+            params: params.to_vec(),
+            tid: None,
+            expect_data: None,
+            reply_data: vec![],
+            response: StandardResponseCode::Ok,
+            response_params: vec![],
+        });
+        self.script.back_mut().unwrap()
+    }
+
+    /// Script a GetDeviceInfo whose dataset lists `operations` as supported, and is otherwise
+    /// empty. For the capability checks made before many operations.
+    pub fn expect_device_info(&mut self, operations: &[CommandCode]) -> &mut MockTransaction {
+        self.expect_device_info_events(operations, &[])
+    }
+
+    /// `expect_device_info`, also listing `events` as supported
+    pub fn expect_device_info_events(&mut self, operations: &[CommandCode], events: &[EventCode]) -> &mut MockTransaction {
+        let mut info = vec![];
+        info.write_u16::<LittleEndian>(100).ok();
+        info.write_u32::<LittleEndian>(0).ok();
+        info.write_u16::<LittleEndian>(0).ok();
+        info.write_u8(0).ok();
+        info.write_u16::<LittleEndian>(0).ok();
+        info.write_u32::<LittleEndian>(operations.len() as u32).ok();
+        for op in operations {
+            info.write_u16::<LittleEndian>(*op).ok();
+        }
+        info.write_u32::<LittleEndian>(events.len() as u32).ok();
+        for event in events {
+            info.write_u16::<LittleEndian>(*event).ok();
+        }
+        // no properties, capture or image formats, and empty strings
+        info.extend_from_slice(&[0; 3 * 4 + 4]);
+        self.expect(StandardCommandCode::GetDeviceInfo, &[0, 0, 0]).reply_data(&info)
+    }
+
+    /// Queue an arbitrary container for the camera to read, bypassing the script.
+    pub fn push_container(&mut self, kind: PtpContainerType, code: u16, tid: u32, payload: &[u8]) {
+        self.incoming.push_back(encode_container(kind, code, tid, payload));
+    }
+
+    /// Queue an event for `read_event`.
+    pub fn push_event(&mut self, code: u16, tid: u32, params: &[u32]) {
+        self.events.push_back(encode_container(PtpContainerType::Event, code, tid, &encode_params(params)));
+    }
+
+    /// Set what `status` reports; by default the responder is always Ok.
+    pub fn set_status(&mut self, code: ResponseCode, params: &[u32]) {
+        self.status = Some((code, params.to_vec()));
+    }
+
+    /// Queue a status for a single `status` request, reported ahead of the one set with
+    /// `set_status`.
+    pub fn push_status(&mut self, code: ResponseCode, params: &[u32]) {
+        self.queued_status.push_back((code, params.to_vec()));
+    }
+
+    /// transaction IDs the camera asked to cancel, in order
+    pub fn cancelled(&self) -> &[u32] {
+        &self.cancelled
+    }
+
+    /// number of device resets the camera requested
+    pub fn resets(&self) -> usize {
+        self.resets
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Panic if any scripted transaction has not run to completion.
+    pub fn verify(&self) {
+        if let Some(ref txn) = self.current {
+            panic!("mock: {} still waiting for its data phase", name(txn.code));
+        }
+        if let Some(txn) = self.script.front() {
+            panic!("mock: {} scripted transaction(s) never ran, next is {}", self.script.len(), name(txn.code));
+        }
+        if !self.incoming.is_empty() {
+            panic!("mock: {} container(s) were never read", self.incoming.len());
+        }
+    }
+
+    fn finish(&mut self, txn: MockTransaction, tid: u32) {
+        for data in &txn.reply_data {
+            self.incoming.push_back(encode_container(PtpContainerType::Data, txn.code, tid, data));
+        }
+        self.incoming.push_back(encode_container(PtpContainerType::Response, txn.response, tid,
+                                                 &encode_params(&txn.response_params)));
+    }
+}
+
+impl PtpTransport for MockTransport {
+    fn write_container(&mut self, kind: PtpContainerType, code: CommandCode, tid: u32, payload: &[u8], _timeout: Duration) -> Result<(), Error> {
+        match kind {
+            PtpContainerType::Command => {
+                if let Some(ref txn) = self.current {
+                    panic!("mock: got {} while {} was waiting for its data phase", name(code), name(txn.code));
+                }
+
+                let txn = self.script.pop_front()
+                    .unwrap_or_else(|| panic!("mock: unexpected command {}", name(code)));
+
+                assert_eq!(code, txn.code, "mock: got {}, expected {}", name(code), name(txn.code));
+                assert_eq!(decode_params(payload), txn.params, "mock: parameters of {}", name(code));
+                match (txn.tid, self.last_tid) {
+                    (Some(expected), _) => assert_eq!(tid, expected, "mock: transaction ID of {}", name(code)),
+                    (None, Some(last)) => assert_eq!(tid, last.wrapping_add(1), "mock: transaction ID of {}", name(code)),
+                    (None, None) => {}
+                }
+                self.last_tid = Some(tid);
+
+                if txn.expect_data.is_some() {
+                    self.current = Some(txn);
+                } else {
+                    self.finish(txn, tid);
+                }
+            }
+            PtpContainerType::Data => {
+                let txn = self.current.take()
+                    .unwrap_or_else(|| panic!("mock: unexpected data phase for {}", name(code)));
+
+                assert_eq!(Some(tid), self.last_tid, "mock: transaction ID of data phase for {}", name(code));
+                assert_eq!(code, txn.code, "mock: data phase code");
+                assert_eq!(Some(payload), txn.expect_data.as_ref().map(|d| &d[..]), "mock: data phase of {}", name(code));
+
+                self.finish(txn, tid);
+            }
+            _ => panic!("mock: initiator sent a {:?} container", kind),
+        }
+        Ok(())
+    }
+
+    fn read_container(&mut self, _timeout: Duration) -> Result<(PtpContainerInfo, Vec<u8>), Error> {
+        if let Some(ref txn) = self.current {
+            panic!("mock: read before the data phase of {} was sent", name(txn.code));
+        }
+
+        let buf = self.incoming.pop_front()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "mock: nothing to read"))?;
+        let cinfo = PtpContainerInfo::parse(&buf[..])?;
+        let payload = buf[PTP_CONTAINER_INFO_SIZE..].to_vec();
+        Ok((cinfo, payload))
+    }
+
+    fn read_event(&mut self, _timeout: Duration) -> Result<Option<(PtpContainerInfo, Vec<u8>)>, Error> {
+        match self.events.pop_front() {
+            Some(buf) => {
+                let cinfo = PtpContainerInfo::parse(&buf[..])?;
+                let payload = buf[PTP_CONTAINER_INFO_SIZE..].to_vec();
+                Ok(Some((cinfo, payload)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn cancel(&mut self, tid: u32, _timeout: Duration) -> Result<(), Error> {
+        self.cancelled.push(tid);
+        self.current = None;
+        Ok(())
+    }
+
+    fn reset(&mut self, _timeout: Duration) -> Result<(), Error> {
+        self.resets += 1;
+        Ok(())
+    }
+
+    fn status(&mut self, _timeout: Duration) -> Result<(ResponseCode, Vec<u32>), Error> {
+        match self.queued_status.pop_front() {
+            Some(status) => Ok(status),
+            None => Ok(self.status.clone().unwrap_or((StandardResponseCode::Ok, vec![]))),
+        }
+    }
+
+    fn close(&mut self) -> Result<(), Error> {
+        self.closed = true;
+        Ok(())
+    }
+
+    fn drain(&mut self, _timeout: Duration) -> Result<(), Error> {
+        self.incoming.clear();
+        Ok(())
+    }
+}
+
+fn name(code: CommandCode) -> String {
+    format!("0x{:04x} ({})", code, StandardCommandCode::name(code).unwrap_or("unknown"))
+}
+
+fn encode_container(kind: PtpContainerType, code: u16, tid: u32, payload: &[u8]) -> Vec<u8> {
+    let cinfo = PtpContainerInfo { payload_len: payload.len(), kind, code, tid };
+    let mut buf = cinfo.encode();
+    buf.extend_from_slice(payload);
+    buf
+}
+
+fn encode_params(params: &[u32]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(params.len() * 4);
+    for p in params {
+        buf.write_u32::<LittleEndian>(*p).ok();
+    }
+    buf
+}
+
+fn decode_params(payload: &[u8]) -> Vec<u32> {
+    let mut cur = Cursor::new(payload);
+    (0..payload.len() / 4).map(|_| cur.read_u32::<LittleEndian>().unwrap()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::PtpCamera;
+
+    #[test]
+    fn mismatched_tid_is_rejected() {
+        let mut mock = MockTransport::new();
+        mock.expect(StandardCommandCode::GetStorageIDs, &[]).tid(0)
+            .respond(StandardResponseCode::GeneralError, &[]);
+        let mut camera = PtpCamera::with_transport(mock);
+        // answered as if for another transaction, ahead of the scripted response
+        camera.transport().push_container(PtpContainerType::Response, StandardResponseCode::Ok, 5, &[]);
+
+        match camera.command(StandardCommandCode::GetStorageIDs, &[], None, None) {
+            Err(Error::Malformed(ref msg)) if msg.contains("mismatched txnid 5") => {}
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn data_containers_are_accumulated() {
+        let mut mock = MockTransport::new();
+        mock.expect(StandardCommandCode::GetObject, &[7])
+            .reply_data(b"first ")
+            .reply_data(b"second");
+        let mut camera = PtpCamera::with_transport(mock);
+
+        let data = camera.command(StandardCommandCode::GetObject, &[7], None, None).unwrap();
+        assert_eq!(data, b"first second");
+        camera.transport().verify();
+    }
+
+    #[test]
+    fn failed_response_is_an_error() {
+        let mut mock = MockTransport::new();
+        mock.expect(StandardCommandCode::DeleteObject, &[42])
+            .respond(StandardResponseCode::ObjectWriteProtected, &[]);
+        let mut camera = PtpCamera::with_transport(mock);
+
+        match camera.command(StandardCommandCode::DeleteObject, &[42], None, None) {
+            Err(Error::Response(StandardResponseCode::ObjectWriteProtected)) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        camera.transport().verify();
+    }
+
+    #[test]
+    #[should_panic(expected = "mock: parameters of 0x100b")]
+    fn parameter_mismatch_panics() {
+        let mut mock = MockTransport::new();
+        mock.expect(StandardCommandCode::DeleteObject, &[42]);
+        let mut camera = PtpCamera::with_transport(mock);
+        let _ = camera.command(StandardCommandCode::DeleteObject, &[43], None, None);
+    }
+}
@@ -0,0 +1,500 @@
+//! MTP object property operations.
+//!
+//! MTP devices, such as Android phones and many action cameras, describe objects through object
+//! properties rather than, or as well as, the ObjectInfo dataset. The properties an object
+//! supports depend on its format: `get_object_props_supported` lists them, and
+//! `get_object_prop_desc` describes each one.
+//!
+//! ```no_run
+//! # fn example<T: ptp::PtpTransport>(camera: &mut ptp::PtpCamera<T>) -> Result<(), ptp::Error> {
+//! use ptp::StandardObjectFormatCode;
+//! use ptp::mtp::MtpObjectPropCode;
+//!
+//! let desc = camera.get_object_prop_desc(MtpObjectPropCode::DateModified, StandardObjectFormatCode::EXIF_JPEG, None)?;
+//! let modified = camera.get_object_prop_value_as(0x1234, MtpObjectPropCode::DateModified, desc.DataType, None)?;
+//!
+//! // every property of every object in a store, in one round trip
+//! for prop in camera.get_object_prop_list(0xFFFFFFFF, 0, 0xFFFFFFFF, 0, 0xFFFFFFFF, None)? {
+//!     println!("{} {}: {:?}", prop.handle, MtpObjectPropCode::name(prop.PropertyCode).unwrap_or("?"), prop.Value);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::io::Cursor;
+use std::time::Duration;
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use super::{Error, PtpCamera, PtpTransport, PtpDataType, PtpFormData, PtpRead, ObjectFormatCode};
+
+pub type ObjectPropCode = u16;
+
+#[allow(non_upper_case_globals)]
+pub mod MtpCommandCode {
+    use super::super::CommandCode;
+
+    pub const GetObjectPropsSupported: CommandCode = 0x9801;
+    pub const GetObjectPropDesc: CommandCode = 0x9802;
+    pub const GetObjectPropValue: CommandCode = 0x9803;
+    pub const SetObjectPropValue: CommandCode = 0x9804;
+    pub const GetObjectPropList: CommandCode = 0x9805;
+
+    pub fn name(v: CommandCode) -> Option<&'static str> {
+        match v {
+            GetObjectPropsSupported => Some("GetObjectPropsSupported"),
+            GetObjectPropDesc => Some("GetObjectPropDesc"),
+            GetObjectPropValue => Some("GetObjectPropValue"),
+            SetObjectPropValue => Some("SetObjectPropValue"),
+            GetObjectPropList => Some("GetObjectPropList"),
+            _ => None,
+        }
+    }
+}
+
+#[allow(non_upper_case_globals)]
+pub mod MtpObjectPropCode {
+    use super::ObjectPropCode;
+
+    pub const StorageID: ObjectPropCode = 0xDC01;
+    pub const ObjectFormat: ObjectPropCode = 0xDC02;
+    pub const ProtectionStatus: ObjectPropCode = 0xDC03;
+    pub const ObjectSize: ObjectPropCode = 0xDC04;
+    pub const AssociationType: ObjectPropCode = 0xDC05;
+    pub const AssociationDesc: ObjectPropCode = 0xDC06;
+    pub const ObjectFileName: ObjectPropCode = 0xDC07;
+    pub const DateCreated: ObjectPropCode = 0xDC08;
+    pub const DateModified: ObjectPropCode = 0xDC09;
+    pub const Keywords: ObjectPropCode = 0xDC0A;
+    pub const ParentObject: ObjectPropCode = 0xDC0B;
+    pub const AllowedFolderContents: ObjectPropCode = 0xDC0C;
+    pub const Hidden: ObjectPropCode = 0xDC0D;
+    pub const SystemObject: ObjectPropCode = 0xDC0E;
+    pub const PersistentUniqueObjectIdentifier: ObjectPropCode = 0xDC41;
+    pub const SyncID: ObjectPropCode = 0xDC42;
+    pub const PropertyBag: ObjectPropCode = 0xDC43;
+    pub const Name: ObjectPropCode = 0xDC44;
+    pub const CreatedBy: ObjectPropCode = 0xDC45;
+    pub const Artist: ObjectPropCode = 0xDC46;
+    pub const DateAuthored: ObjectPropCode = 0xDC47;
+    pub const Description: ObjectPropCode = 0xDC48;
+    pub const URLReference: ObjectPropCode = 0xDC49;
+    pub const LanguageLocale: ObjectPropCode = 0xDC4A;
+    pub const CopyrightInformation: ObjectPropCode = 0xDC4B;
+    pub const Source: ObjectPropCode = 0xDC4C;
+    pub const OriginLocation: ObjectPropCode = 0xDC4D;
+    pub const DateAdded: ObjectPropCode = 0xDC4E;
+    pub const NonConsumable: ObjectPropCode = 0xDC4F;
+    pub const CorruptUnplayable: ObjectPropCode = 0xDC50;
+    pub const ProducerSerialNumber: ObjectPropCode = 0xDC51;
+    pub const RepresentativeSampleFormat: ObjectPropCode = 0xDC81;
+    pub const RepresentativeSampleSize: ObjectPropCode = 0xDC82;
+    pub const RepresentativeSampleHeight: ObjectPropCode = 0xDC83;
+    pub const RepresentativeSampleWidth: ObjectPropCode = 0xDC84;
+    pub const RepresentativeSampleDuration: ObjectPropCode = 0xDC85;
+    pub const RepresentativeSampleData: ObjectPropCode = 0xDC86;
+    pub const Width: ObjectPropCode = 0xDC87;
+    pub const Height: ObjectPropCode = 0xDC88;
+    pub const Duration: ObjectPropCode = 0xDC89;
+    pub const Rating: ObjectPropCode = 0xDC8A;
+    pub const Track: ObjectPropCode = 0xDC8B;
+    pub const Genre: ObjectPropCode = 0xDC8C;
+    pub const Credits: ObjectPropCode = 0xDC8D;
+    pub const Lyrics: ObjectPropCode = 0xDC8E;
+    pub const SubscriptionContentID: ObjectPropCode = 0xDC8F;
+    pub const ProducedBy: ObjectPropCode = 0xDC90;
+    pub const UseCount: ObjectPropCode = 0xDC91;
+    pub const SkipCount: ObjectPropCode = 0xDC92;
+    pub const LastAccessed: ObjectPropCode = 0xDC93;
+    pub const ParentalRating: ObjectPropCode = 0xDC94;
+    pub const MetaGenre: ObjectPropCode = 0xDC95;
+    pub const Composer: ObjectPropCode = 0xDC96;
+    pub const EffectiveRating: ObjectPropCode = 0xDC97;
+    pub const Subtitle: ObjectPropCode = 0xDC98;
+    pub const OriginalReleaseDate: ObjectPropCode = 0xDC99;
+    pub const AlbumName: ObjectPropCode = 0xDC9A;
+    pub const AlbumArtist: ObjectPropCode = 0xDC9B;
+    pub const Mood: ObjectPropCode = 0xDC9C;
+    pub const DRMStatus: ObjectPropCode = 0xDC9D;
+    pub const SubDescription: ObjectPropCode = 0xDC9E;
+    pub const IsCropped: ObjectPropCode = 0xDCD1;
+    pub const IsColourCorrected: ObjectPropCode = 0xDCD2;
+    pub const ImageBitDepth: ObjectPropCode = 0xDCD3;
+    pub const Fnumber: ObjectPropCode = 0xDCD4;
+    pub const ExposureTime: ObjectPropCode = 0xDCD5;
+    pub const ExposureIndex: ObjectPropCode = 0xDCD6;
+    pub const TotalBitRate: ObjectPropCode = 0xDE91;
+    pub const BitrateType: ObjectPropCode = 0xDE92;
+    pub const SampleRate: ObjectPropCode = 0xDE93;
+    pub const NumberOfChannels: ObjectPropCode = 0xDE94;
+    pub const AudioBitDepth: ObjectPropCode = 0xDE95;
+    pub const ScanType: ObjectPropCode = 0xDE97;
+    pub const AudioWAVECodec: ObjectPropCode = 0xDE99;
+    pub const AudioBitRate: ObjectPropCode = 0xDE9A;
+    pub const VideoFourCCCodec: ObjectPropCode = 0xDE9B;
+    pub const VideoBitRate: ObjectPropCode = 0xDE9C;
+    pub const FramesPerThousandSeconds: ObjectPropCode = 0xDE9D;
+    pub const KeyFrameDistance: ObjectPropCode = 0xDE9E;
+    pub const BufferSize: ObjectPropCode = 0xDE9F;
+    pub const EncodingQuality: ObjectPropCode = 0xDEA0;
+    pub const EncodingProfile: ObjectPropCode = 0xDEA1;
+
+    pub fn name(v: ObjectPropCode) -> Option<&'static str> {
+        match v {
+            StorageID => Some("StorageID"),
+            ObjectFormat => Some("ObjectFormat"),
+            ProtectionStatus => Some("ProtectionStatus"),
+            ObjectSize => Some("ObjectSize"),
+            AssociationType => Some("AssociationType"),
+            AssociationDesc => Some("AssociationDesc"),
+            ObjectFileName => Some("ObjectFileName"),
+            DateCreated => Some("DateCreated"),
+            DateModified => Some("DateModified"),
+            Keywords => Some("Keywords"),
+            ParentObject => Some("ParentObject"),
+            AllowedFolderContents => Some("AllowedFolderContents"),
+            Hidden => Some("Hidden"),
+            SystemObject => Some("SystemObject"),
+            PersistentUniqueObjectIdentifier => Some("PersistentUniqueObjectIdentifier"),
+            SyncID => Some("SyncID"),
+            PropertyBag => Some("PropertyBag"),
+            Name => Some("Name"),
+            CreatedBy => Some("CreatedBy"),
+            Artist => Some("Artist"),
+            DateAuthored => Some("DateAuthored"),
+            Description => Some("Description"),
+            URLReference => Some("URLReference"),
+            LanguageLocale => Some("LanguageLocale"),
+            CopyrightInformation => Some("CopyrightInformation"),
+            Source => Some("Source"),
+            OriginLocation => Some("OriginLocation"),
+            DateAdded => Some("DateAdded"),
+            NonConsumable => Some("NonConsumable"),
+            CorruptUnplayable => Some("CorruptUnplayable"),
+            ProducerSerialNumber => Some("ProducerSerialNumber"),
+            RepresentativeSampleFormat => Some("RepresentativeSampleFormat"),
+            RepresentativeSampleSize => Some("RepresentativeSampleSize"),
+            RepresentativeSampleHeight => Some("RepresentativeSampleHeight"),
+            RepresentativeSampleWidth => Some("RepresentativeSampleWidth"),
+            RepresentativeSampleDuration => Some("RepresentativeSampleDuration"),
+            RepresentativeSampleData => Some("RepresentativeSampleData"),
+            Width => Some("Width"),
+            Height => Some("Height"),
+            Duration => Some("Duration"),
+            Rating => Some("Rating"),
+            Track => Some("Track"),
+            Genre => Some("Genre"),
+            Credits => Some("Credits"),
+            Lyrics => Some("Lyrics"),
+            SubscriptionContentID => Some("SubscriptionContentID"),
+            ProducedBy => Some("ProducedBy"),
+            UseCount => Some("UseCount"),
+            SkipCount => Some("SkipCount"),
+            LastAccessed => Some("LastAccessed"),
+            ParentalRating => Some("ParentalRating"),
+            MetaGenre => Some("MetaGenre"),
+            Composer => Some("Composer"),
+            EffectiveRating => Some("EffectiveRating"),
+            Subtitle => Some("Subtitle"),
+            OriginalReleaseDate => Some("OriginalReleaseDate"),
+            AlbumName => Some("AlbumName"),
+            AlbumArtist => Some("AlbumArtist"),
+            Mood => Some("Mood"),
+            DRMStatus => Some("DRMStatus"),
+            SubDescription => Some("SubDescription"),
+            IsCropped => Some("IsCropped"),
+            IsColourCorrected => Some("IsColourCorrected"),
+            ImageBitDepth => Some("ImageBitDepth"),
+            Fnumber => Some("Fnumber"),
+            ExposureTime => Some("ExposureTime"),
+            ExposureIndex => Some("ExposureIndex"),
+            TotalBitRate => Some("TotalBitRate"),
+            BitrateType => Some("BitrateType"),
+            SampleRate => Some("SampleRate"),
+            NumberOfChannels => Some("NumberOfChannels"),
+            AudioBitDepth => Some("AudioBitDepth"),
+            ScanType => Some("ScanType"),
+            AudioWAVECodec => Some("AudioWAVECodec"),
+            AudioBitRate => Some("AudioBitRate"),
+            VideoFourCCCodec => Some("VideoFourCCCodec"),
+            VideoBitRate => Some("VideoBitRate"),
+            FramesPerThousandSeconds => Some("FramesPerThousandSeconds"),
+            KeyFrameDistance => Some("KeyFrameDistance"),
+            BufferSize => Some("BufferSize"),
+            EncodingQuality => Some("EncodingQuality"),
+            EncodingProfile => Some("EncodingProfile"),
+            _ => None,
+        }
+    }
+}
+
+/// The allowed values of an object property. MTP adds forms to the range and enumeration of
+/// device properties.
+#[derive(Debug)]
+pub enum MtpFormData {
+    /// no form, a range or an enumeration, as for device properties
+    Ptp(PtpFormData),
+    /// a string holding a date and time
+    DateTime,
+    /// an array of exactly this many elements
+    FixedLengthArray(u16),
+    /// a string matching this regular expression
+    RegularExpression(String),
+    /// a UINT8 array of at most this many bytes, holding arbitrary data
+    ByteArray(u32),
+    /// a UINT16 array of at most this many characters, holding a string too long for the STR type
+    LongString(u32),
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug)]
+pub struct MtpObjectPropDesc {
+    pub PropertyCode: ObjectPropCode,
+    pub DataType: u16,
+    pub GetSet: u8,
+    pub FactoryDefault: PtpDataType,
+    pub GroupCode: u32,
+    pub Form: MtpFormData,
+}
+
+impl MtpObjectPropDesc {
+    pub fn decode<T: PtpRead>(cur: &mut T) -> Result<MtpObjectPropDesc, Error> {
+        let property_code = cur.read_u16::<LittleEndian>()?;
+        let data_type = cur.read_u16::<LittleEndian>()?;
+        Ok(MtpObjectPropDesc {
+            PropertyCode: property_code,
+            DataType: data_type,
+            GetSet: cur.read_u8()?,
+            FactoryDefault: PtpDataType::read_type(data_type, cur)?,
+            GroupCode: cur.read_u32::<LittleEndian>()?,
+            Form: match cur.read_u8()? {
+                0x03 => MtpFormData::DateTime,
+                0x04 => MtpFormData::FixedLengthArray(cur.read_u16::<LittleEndian>()?),
+                0x05 => MtpFormData::RegularExpression(cur.read_ptp_str()?),
+                0x06 => MtpFormData::ByteArray(cur.read_u32::<LittleEndian>()?),
+                0xFF => MtpFormData::LongString(cur.read_u32::<LittleEndian>()?),
+                form_flag => MtpFormData::Ptp(PtpFormData::decode(form_flag, data_type, cur)?),
+            },
+        })
+    }
+}
+
+/// One element of an ObjectPropList, see `PtpCamera::get_object_prop_list`
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct MtpObjectProp {
+    pub handle: u32,
+    pub PropertyCode: ObjectPropCode,
+    pub DataType: u16,
+    pub Value: PtpDataType,
+}
+
+impl<T: PtpTransport> PtpCamera<T> {
+    /// the object properties supported for objects of `format`
+    pub fn get_object_props_supported(&mut self, format: ObjectFormatCode, timeout: Option<Duration>) -> Result<Vec<ObjectPropCode>, Error> {
+        let data = self.command(MtpCommandCode::GetObjectPropsSupported, &[format as u32], None, timeout)?;
+
+        let mut cur = Cursor::new(data);
+        let value = cur.read_ptp_u16_vec()?;
+        cur.expect_end()?;
+
+        Ok(value)
+    }
+
+    pub fn get_object_prop_desc(&mut self,
+                                prop_code: ObjectPropCode,
+                                format: ObjectFormatCode,
+                                timeout: Option<Duration>)
+                                -> Result<MtpObjectPropDesc, Error> {
+        let data = self.command(MtpCommandCode::GetObjectPropDesc, &[prop_code as u32, format as u32], None, timeout)?;
+
+        let mut cur = Cursor::new(data);
+        let res = MtpObjectPropDesc::decode(&mut cur)?;
+        cur.expect_end()?;
+
+        Ok(res)
+    }
+
+    /// read an object property, decoded as `data_type`, the DataType from its description
+    pub fn get_object_prop_value_as(&mut self,
+                                    handle: u32,
+                                    prop_code: ObjectPropCode,
+                                    data_type: u16,
+                                    timeout: Option<Duration>)
+                                    -> Result<PtpDataType, Error> {
+        let data = self.command(MtpCommandCode::GetObjectPropValue, &[handle, prop_code as u32], None, timeout)?;
+
+        let mut cur = Cursor::new(data);
+        let value = PtpDataType::read_type(data_type, &mut cur)?;
+        cur.expect_end()?;
+
+        Ok(value)
+    }
+
+    pub fn set_object_prop_value(&mut self,
+                                 handle: u32,
+                                 prop_code: ObjectPropCode,
+                                 value: &PtpDataType,
+                                 timeout: Option<Duration>)
+                                 -> Result<(), Error> {
+        let data = value.encode();
+        self.command(MtpCommandCode::SetObjectPropValue, &[handle, prop_code as u32], Some(&data), timeout).map(|_| ())
+    }
+
+    /// read many properties of many objects at once.
+    ///  - `handle` is an object, or 0xFFFFFFFF for all objects
+    ///  - `format` limits the objects to one format, or is 0 for any
+    ///  - `prop_code` is a property, 0xFFFFFFFF for all of them, or 0 to select by `group`
+    ///  - `depth` is how many levels below `handle` to include, 0xFFFFFFFF for all
+    pub fn get_object_prop_list(&mut self,
+                                handle: u32,
+                                format: ObjectFormatCode,
+                                prop_code: u32,
+                                group: u32,
+                                depth: u32,
+                                timeout: Option<Duration>)
+                                -> Result<Vec<MtpObjectProp>, Error> {
+        let data = self.command(MtpCommandCode::GetObjectPropList,
+                                &[handle, format as u32, prop_code, group, depth],
+                                None, timeout)?;
+
+        let mut cur = Cursor::new(data);
+        let len = cur.read_u32::<LittleEndian>()?;
+        let mut props = Vec::with_capacity(len.min(0x10000) as usize);
+        for _ in 0..len {
+            let handle = cur.read_u32::<LittleEndian>()?;
+            let property_code = cur.read_u16::<LittleEndian>()?;
+            let data_type = cur.read_u16::<LittleEndian>()?;
+            // the size of a value of unknown type is unknown, so nothing after it can be read
+            let value = PtpDataType::read_type(data_type, &mut cur)?;
+            if value == PtpDataType::UNDEF {
+                return Err(Error::Malformed(format!("Unknown data type 0x{:04x} of object property 0x{:04x}",
+                                                    data_type, property_code)));
+            }
+            props.push(MtpObjectProp {
+                handle,
+                PropertyCode: property_code,
+                DataType: data_type,
+                Value: value,
+            });
+        }
+        cur.expect_end()?;
+
+        Ok(props)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::mock::MockTransport;
+
+    // an ObjectPropDesc header: code, data type, GetSet, then the factory default and group
+    fn desc(code: u16, data_type: u16, default: &[u8], group: u32) -> Vec<u8> {
+        let mut data = vec![];
+        data.extend_from_slice(&code.to_le_bytes());
+        data.extend_from_slice(&data_type.to_le_bytes());
+        data.push(0x01);
+        data.extend_from_slice(default);
+        data.extend_from_slice(&group.to_le_bytes());
+        data
+    }
+
+    fn decode(data: &[u8]) -> MtpObjectPropDesc {
+        let mut cur = Cursor::new(data);
+        let desc = MtpObjectPropDesc::decode(&mut cur).unwrap();
+        cur.expect_end().unwrap();
+        desc
+    }
+
+    #[test]
+    fn prop_desc_forms() {
+        // DateModified, an empty string by default, with the DateTime form
+        let mut data = desc(MtpObjectPropCode::DateModified, 0xFFFF, &[0x00], 0);
+        data.push(0x03);
+        let d = decode(&data);
+        assert_eq!((d.PropertyCode, d.DataType, d.GetSet), (MtpObjectPropCode::DateModified, 0xFFFF, 0x01));
+        assert_eq!(d.FactoryDefault, PtpDataType::STR(String::new()));
+        assert!(matches!(d.Form, MtpFormData::DateTime));
+
+        // an AUINT16 array of exactly 8 elements, in group 2
+        let mut data = desc(MtpObjectPropCode::PersistentUniqueObjectIdentifier, 0x4004, &[0, 0, 0, 0], 2);
+        data.extend_from_slice(&[0x04, 0x08, 0x00]);
+        let d = decode(&data);
+        assert_eq!(d.GroupCode, 2);
+        assert_eq!(d.FactoryDefault, PtpDataType::AUINT16(vec![]));
+        assert!(matches!(d.Form, MtpFormData::FixedLengthArray(8)));
+
+        // a string matching a regular expression, "[a-z]" as a PTP string
+        let mut data = desc(MtpObjectPropCode::Name, 0xFFFF, &[0x00], 0);
+        data.extend_from_slice(&[0x05, 0x06, b'[', 0, b'a', 0, b'-', 0, b'z', 0, b']', 0, 0, 0]);
+        match decode(&data).Form {
+            MtpFormData::RegularExpression(ref re) => assert_eq!(re, "[a-z]"),
+            ref form => panic!("unexpected {:?}", form),
+        }
+
+        // a byte array of at most 1024 bytes
+        let mut data = desc(MtpObjectPropCode::RepresentativeSampleData, 0x4002, &[0, 0, 0, 0], 0);
+        data.extend_from_slice(&[0x06, 0x00, 0x04, 0x00, 0x00]);
+        let d = decode(&data);
+        assert_eq!(d.FactoryDefault, PtpDataType::AUINT8(vec![]));
+        assert!(matches!(d.Form, MtpFormData::ByteArray(1024)));
+
+        // a long string of at most 65536 characters
+        let mut data = desc(MtpObjectPropCode::Description, 0x4004, &[0, 0, 0, 0], 0);
+        data.extend_from_slice(&[0xFF, 0x00, 0x00, 0x01, 0x00]);
+        assert!(matches!(decode(&data).Form, MtpFormData::LongString(0x10000)));
+
+        // a UINT16 range, as for device properties
+        let mut data = desc(MtpObjectPropCode::ProtectionStatus, 0x0004, &[0x00, 0x00], 0);
+        data.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00]);
+        match decode(&data).Form {
+            MtpFormData::Ptp(PtpFormData::Range { minValue, maxValue, step }) => {
+                assert_eq!((minValue, maxValue, step), (PtpDataType::UINT16(0), PtpDataType::UINT16(1), PtpDataType::UINT16(1)));
+            }
+            ref form => panic!("unexpected {:?}", form),
+        }
+
+        // a form cut short
+        let mut data = desc(MtpObjectPropCode::Description, 0x4004, &[0, 0, 0, 0], 0);
+        data.extend_from_slice(&[0xFF, 0x00, 0x00]);
+        assert!(MtpObjectPropDesc::decode(&mut Cursor::new(&data[..])).is_err());
+    }
+
+    // one element of an ObjectPropList
+    fn element(handle: u32, code: u16, data_type: u16, value: &[u8]) -> Vec<u8> {
+        let mut data = handle.to_le_bytes().to_vec();
+        data.extend_from_slice(&code.to_le_bytes());
+        data.extend_from_slice(&data_type.to_le_bytes());
+        data.extend_from_slice(value);
+        data
+    }
+
+    #[test]
+    fn prop_list() {
+        let mut list = 3u32.to_le_bytes().to_vec();
+        list.extend(element(5, MtpObjectPropCode::ObjectSize, 0x0008, &[0x00, 0x10, 0, 0, 1, 0, 0, 0]));
+        list.extend(element(5, MtpObjectPropCode::ObjectFileName, 0xFFFF, &[0x03, b'A', 0, b'B', 0, 0, 0]));
+        list.extend(element(6, MtpObjectPropCode::ParentObject, 0x0006, &[0x05, 0, 0, 0]));
+
+        let mut mock = MockTransport::new();
+        mock.expect(MtpCommandCode::GetObjectPropList, &[0xFFFFFFFF, 0, 0xFFFFFFFF, 0, 1]).reply_data(&list);
+        // an unknown data type, and a list with more elements than it holds
+        let mut unknown = 1u32.to_le_bytes().to_vec();
+        unknown.extend(element(5, MtpObjectPropCode::Keywords, 0x1234, &[0; 4]));
+        mock.expect(MtpCommandCode::GetObjectPropList, &[0xFFFFFFFF, 0, 0xFFFFFFFF, 0, 1]).reply_data(&unknown);
+        list[0] = 4;
+        mock.expect(MtpCommandCode::GetObjectPropList, &[0xFFFFFFFF, 0, 0xFFFFFFFF, 0, 1]).reply_data(&list);
+        let mut camera = PtpCamera::with_transport(mock);
+
+        let props = camera.get_object_prop_list(0xFFFFFFFF, 0, 0xFFFFFFFF, 0, 1, None).unwrap();
+        assert_eq!(props, vec![
+            MtpObjectProp { handle: 5, PropertyCode: MtpObjectPropCode::ObjectSize, DataType: 0x0008, Value: PtpDataType::UINT64(0x1_0000_1000) },
+            MtpObjectProp { handle: 5, PropertyCode: MtpObjectPropCode::ObjectFileName, DataType: 0xFFFF, Value: PtpDataType::STR("AB".to_owned()) },
+            MtpObjectProp { handle: 6, PropertyCode: MtpObjectPropCode::ParentObject, DataType: 0x0006, Value: PtpDataType::UINT32(5) },
+        ]);
+        assert!(matches!(camera.get_object_prop_list(0xFFFFFFFF, 0, 0xFFFFFFFF, 0, 1, None), Err(Error::Malformed(_))));
+        assert!(matches!(camera.get_object_prop_list(0xFFFFFFFF, 0, 0xFFFFFFFF, 0, 1, None), Err(Error::Malformed(_))));
+        camera.transport().verify();
+    }
+}
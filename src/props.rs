@@ -0,0 +1,378 @@
+//! Typed views of the standard device properties.
+//!
+//! Each type here knows its `StandardDevicePropCode`, its PTP data type and how to convert to and
+//! from the raw `PtpDataType`, following the units in section 13 of the PTP specification.
+//! `PtpCamera::get_prop` and `PtpCamera::set_prop` use them to read and write properties in
+//! meaningful units:
+//!
+//! ```no_run
+//! # fn example<T: ptp::PtpTransport>(camera: &mut ptp::PtpCamera<T>) -> Result<(), ptp::Error> {
+//! use ptp::props::{FNumber, WhiteBalance};
+//!
+//! let FNumber(f) = camera.get_prop(None)?;
+//! println!("shooting at f/{}", f);
+//! camera.set_prop(&WhiteBalance::Daylight, None)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::convert::TryFrom;
+use std::io;
+use std::time::Duration;
+
+use super::{Error, PtpCamera, PtpDataType, PtpTransport, DevicePropCode, StandardDevicePropCode};
+
+/// A device property value with a known code, type and unit
+pub trait DevicePropValue: Sized {
+    /// the property this type represents
+    const CODE: DevicePropCode;
+
+    /// the PTP data type code of the property's value
+    const DATA_TYPE: u16;
+
+    /// convert from the raw value, returning `None` if it is of the wrong type
+    fn from_value(value: &PtpDataType) -> Option<Self>;
+
+    /// convert to the raw value, failing if this value can't be represented
+    fn to_value(&self) -> Result<PtpDataType, Error>;
+}
+
+impl<T: PtpTransport> PtpCamera<T> {
+    /// read a standard device property, converted to its typed representation
+    pub fn get_prop<P: DevicePropValue>(&mut self, timeout: Option<Duration>) -> Result<P, Error> {
+        let value = self.get_device_prop_value_as(P::CODE, P::DATA_TYPE, timeout)?;
+        P::from_value(&value).ok_or_else(|| {
+            Error::Malformed(format!("Unexpected value {:?} for device property {}", value,
+                                     StandardDevicePropCode::name(P::CODE).unwrap_or("unknown")))
+        })
+    }
+
+    pub fn set_prop<P: DevicePropValue>(&mut self, value: &P, timeout: Option<Duration>) -> Result<(), Error> {
+        self.set_device_prop_value(P::CODE, &value.to_value()?, timeout)
+    }
+}
+
+/// Battery level, in the units of the range given by the property description
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BatteryLevel(pub u8);
+
+impl DevicePropValue for BatteryLevel {
+    const CODE: DevicePropCode = StandardDevicePropCode::BatteryLevel;
+    const DATA_TYPE: u16 = 0x0002; // UINT8
+
+    fn from_value(value: &PtpDataType) -> Option<BatteryLevel> {
+        match *value {
+            PtpDataType::UINT8(v) => Some(BatteryLevel(v)),
+            _ => None,
+        }
+    }
+
+    fn to_value(&self) -> Result<PtpDataType, Error> {
+        Ok(PtpDataType::UINT8(self.0))
+    }
+}
+
+/// Aperture as an f-number, e.g. `FNumber(2.8)` for f/2.8
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FNumber(pub f32);
+
+impl DevicePropValue for FNumber {
+    const CODE: DevicePropCode = StandardDevicePropCode::FNumber;
+    const DATA_TYPE: u16 = 0x0004; // UINT16
+
+    // transmitted as the f-number multiplied by 100
+    fn from_value(value: &PtpDataType) -> Option<FNumber> {
+        match *value {
+            PtpDataType::UINT16(v) => Some(FNumber(v as f32 / 100.0)),
+            _ => None,
+        }
+    }
+
+    fn to_value(&self) -> Result<PtpDataType, Error> {
+        scaled(Self::CODE, self.0, 100.0).map(PtpDataType::UINT16)
+    }
+}
+
+/// Focal length in millimetres
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FocalLength(pub f32);
+
+impl DevicePropValue for FocalLength {
+    const CODE: DevicePropCode = StandardDevicePropCode::FocalLength;
+    const DATA_TYPE: u16 = 0x0006; // UINT32
+
+    // transmitted in hundredths of a millimetre
+    fn from_value(value: &PtpDataType) -> Option<FocalLength> {
+        match *value {
+            PtpDataType::UINT32(v) => Some(FocalLength(v as f32 / 100.0)),
+            _ => None,
+        }
+    }
+
+    fn to_value(&self) -> Result<PtpDataType, Error> {
+        scaled(Self::CODE, self.0, 100.0).map(PtpDataType::UINT32)
+    }
+}
+
+/// Shutter speed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExposureTime(pub Duration);
+
+impl DevicePropValue for ExposureTime {
+    const CODE: DevicePropCode = StandardDevicePropCode::ExposureTime;
+    const DATA_TYPE: u16 = 0x0006; // UINT32
+
+    // transmitted in units of 0.1ms
+    fn from_value(value: &PtpDataType) -> Option<ExposureTime> {
+        match *value {
+            PtpDataType::UINT32(v) => Some(ExposureTime(Duration::from_micros(v as u64 * 100))),
+            _ => None,
+        }
+    }
+
+    fn to_value(&self) -> Result<PtpDataType, Error> {
+        u32::try_from(self.0.as_micros() / 100)
+            .map(PtpDataType::UINT32)
+            .map_err(|_| out_of_range(Self::CODE, &self.0))
+    }
+}
+
+/// Exposure index, ie. ISO sensitivity
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExposureIndex {
+    Auto,
+    Iso(u16),
+}
+
+impl DevicePropValue for ExposureIndex {
+    const CODE: DevicePropCode = StandardDevicePropCode::ExposureIndex;
+    const DATA_TYPE: u16 = 0x0004; // UINT16
+
+    fn from_value(value: &PtpDataType) -> Option<ExposureIndex> {
+        match *value {
+            PtpDataType::UINT16(0xFFFF) => Some(ExposureIndex::Auto),
+            PtpDataType::UINT16(v) => Some(ExposureIndex::Iso(v)),
+            _ => None,
+        }
+    }
+
+    fn to_value(&self) -> Result<PtpDataType, Error> {
+        Ok(match *self {
+            ExposureIndex::Auto => PtpDataType::UINT16(0xFFFF),
+            ExposureIndex::Iso(v) => PtpDataType::UINT16(v),
+        })
+    }
+}
+
+/// Exposure compensation in stops
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExposureBiasCompensation(pub f32);
+
+impl DevicePropValue for ExposureBiasCompensation {
+    const CODE: DevicePropCode = StandardDevicePropCode::ExposureBiasCompensation;
+    const DATA_TYPE: u16 = 0x0003; // INT16
+
+    // transmitted in thousandths of a stop
+    fn from_value(value: &PtpDataType) -> Option<ExposureBiasCompensation> {
+        match *value {
+            PtpDataType::INT16(v) => Some(ExposureBiasCompensation(v as f32 / 1000.0)),
+            _ => None,
+        }
+    }
+
+    fn to_value(&self) -> Result<PtpDataType, Error> {
+        scaled(Self::CODE, self.0, 1000.0).map(PtpDataType::INT16)
+    }
+}
+
+/// The device clock
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DateTime(pub time::Tm);
+
+impl DevicePropValue for DateTime {
+    const CODE: DevicePropCode = StandardDevicePropCode::DateTime;
+    const DATA_TYPE: u16 = 0xFFFF; // STR
+
+    // "YYYYMMDDThhmmss", optionally followed by tenths of a second and a UTC offset,
+    // which are ignored here
+    fn from_value(value: &PtpDataType) -> Option<DateTime> {
+        match *value {
+            PtpDataType::STR(ref s) if s.len() >= 15 => {
+                s.get(..15).and_then(|s| time::strptime(s, "%Y%m%dT%H%M%S").ok()).map(DateTime)
+            }
+            _ => None,
+        }
+    }
+
+    fn to_value(&self) -> Result<PtpDataType, Error> {
+        Ok(PtpDataType::STR(format!("{:04}{:02}{:02}T{:02}{:02}{:02}",
+                                    self.0.tm_year + 1900, self.0.tm_mon + 1, self.0.tm_mday,
+                                    self.0.tm_hour, self.0.tm_min, self.0.tm_sec)))
+    }
+}
+
+// `value` in units of 1/`scale`, if that fits the property's type
+fn scaled<T: TryFrom<i64>>(code: DevicePropCode, value: f32, scale: f32) -> Result<T, Error> {
+    let units = (value * scale).round();
+    // a cast would saturate NaN and infinities into range
+    if units.is_finite() {
+        if let Ok(units) = T::try_from(units as i64) {
+            return Ok(units);
+        }
+    }
+    Err(out_of_range(code, &value))
+}
+
+fn out_of_range(code: DevicePropCode, value: &dyn ::std::fmt::Debug) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::InvalidInput,
+                             format!("{:?} is out of range for device property {}", value,
+                                     StandardDevicePropCode::name(code).unwrap_or("unknown"))))
+}
+
+// an enumerated UINT16 property, with a catch-all for vendor-defined values
+macro_rules! enum_prop {
+    ($(#[$attr:meta])* $name:ident, $code:expr, { $($variant:ident = $value:expr,)* }) => {
+        $(#[$attr])*
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        pub enum $name {
+            $($variant,)*
+            /// a value not defined by the PTP specification
+            Other(u16),
+        }
+
+        impl DevicePropValue for $name {
+            const CODE: DevicePropCode = $code;
+            const DATA_TYPE: u16 = 0x0004; // UINT16
+
+            fn from_value(value: &PtpDataType) -> Option<$name> {
+                match *value {
+                    $(PtpDataType::UINT16($value) => Some($name::$variant),)*
+                    PtpDataType::UINT16(v) => Some($name::Other(v)),
+                    _ => None,
+                }
+            }
+
+            fn to_value(&self) -> Result<PtpDataType, Error> {
+                Ok(PtpDataType::UINT16(match *self {
+                    $($name::$variant => $value,)*
+                    $name::Other(v) => v,
+                }))
+            }
+        }
+    }
+}
+
+enum_prop!(WhiteBalance, StandardDevicePropCode::WhiteBalance, {
+    Manual = 0x0001,
+    Automatic = 0x0002,
+    OnePushAutomatic = 0x0003,
+    Daylight = 0x0004,
+    Fluorescent = 0x0005,
+    Tungsten = 0x0006,
+    Flash = 0x0007,
+});
+
+enum_prop!(FocusMode, StandardDevicePropCode::FocusMode, {
+    Manual = 0x0001,
+    Automatic = 0x0002,
+    AutomaticMacro = 0x0003,
+});
+
+enum_prop!(ExposureMeteringMode, StandardDevicePropCode::ExposureMeteringMode, {
+    Average = 0x0001,
+    CenterWeightedAverage = 0x0002,
+    MultiSpot = 0x0003,
+    CenterSpot = 0x0004,
+});
+
+enum_prop!(FlashMode, StandardDevicePropCode::FlashMode, {
+    AutoFlash = 0x0001,
+    FlashOff = 0x0002,
+    FillFlash = 0x0003,
+    RedEyeAuto = 0x0004,
+    RedEyeFill = 0x0005,
+    ExternalSync = 0x0006,
+});
+
+enum_prop!(ExposureProgramMode, StandardDevicePropCode::ExposureProgramMode, {
+    Manual = 0x0001,
+    Automatic = 0x0002,
+    AperturePriority = 0x0003,
+    ShutterPriority = 0x0004,
+    ProgramCreative = 0x0005,
+    ProgramAction = 0x0006,
+    Portrait = 0x0007,
+});
+
+enum_prop!(StillCaptureMode, StandardDevicePropCode::StillCaptureMode, {
+    Normal = 0x0001,
+    Burst = 0x0002,
+    Timelapse = 0x0003,
+});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<P: DevicePropValue + PartialEq + ::std::fmt::Debug>(prop: P, value: PtpDataType) {
+        assert_eq!(prop.to_value().unwrap(), value);
+        assert_eq!(P::from_value(&value), Some(prop));
+    }
+
+    fn rejected<P: DevicePropValue>(prop: P) {
+        match prop.to_value() {
+            Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::InvalidInput => {}
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn f_number_and_focal_length() {
+        round_trip(FNumber(2.8), PtpDataType::UINT16(280));
+        round_trip(FNumber(16.0), PtpDataType::UINT16(1600));
+        assert_eq!(FNumber(5.599).to_value().unwrap(), PtpDataType::UINT16(560));
+        rejected(FNumber(700.0));
+        rejected(FNumber(-1.0));
+        rejected(FNumber(f32::NAN));
+
+        round_trip(FocalLength(50.0), PtpDataType::UINT32(5000));
+        round_trip(FocalLength(18.5), PtpDataType::UINT32(1850));
+        rejected(FocalLength(f32::INFINITY));
+        assert_eq!(FocalLength::from_value(&PtpDataType::UINT16(5000)), None);
+    }
+
+    #[test]
+    fn exposure_time() {
+        round_trip(ExposureTime(Duration::from_millis(8)), PtpDataType::UINT32(80));
+        round_trip(ExposureTime(Duration::from_secs(30)), PtpDataType::UINT32(300000));
+        round_trip(ExposureTime(Duration::from_micros(100)), PtpDataType::UINT32(1));
+        // 0xFFFFFFFF tenths of a millisecond is about 5 days
+        round_trip(ExposureTime(Duration::from_micros(0xFFFFFFFF * 100)), PtpDataType::UINT32(0xFFFFFFFF));
+        rejected(ExposureTime(Duration::from_micros(0x1_0000_0000 * 100)));
+        rejected(ExposureTime(Duration::from_secs(u64::MAX)));
+    }
+
+    #[test]
+    fn exposure_bias() {
+        round_trip(ExposureBiasCompensation(-0.667), PtpDataType::INT16(-667));
+        round_trip(ExposureBiasCompensation(2.0), PtpDataType::INT16(2000));
+        assert_eq!(ExposureBiasCompensation(0.3333).to_value().unwrap(), PtpDataType::INT16(333));
+        rejected(ExposureBiasCompensation(40.0));
+        assert_eq!(ExposureBiasCompensation::from_value(&PtpDataType::UINT16(0)), None);
+    }
+
+    #[test]
+    fn date_time() {
+        let DateTime(tm) = DateTime::from_value(&PtpDataType::from("20240229T235958")).unwrap();
+        assert_eq!((tm.tm_year, tm.tm_mon, tm.tm_mday, tm.tm_hour, tm.tm_min, tm.tm_sec), (124, 1, 29, 23, 59, 58));
+        assert_eq!(DateTime(tm).to_value().unwrap(), PtpDataType::from("20240229T235958"));
+
+        // tenths of a second and a UTC offset are ignored
+        let DateTime(tm) = DateTime::from_value(&PtpDataType::from("20261017T101112.3+0200")).unwrap();
+        assert_eq!(DateTime(tm).to_value().unwrap(), PtpDataType::from("20261017T101112"));
+
+        assert_eq!(DateTime::from_value(&PtpDataType::from("20261017T1011")), None);
+        assert_eq!(DateTime::from_value(&PtpDataType::from("2026-10-17T10:11:12")), None);
+        assert_eq!(DateTime::from_value(&PtpDataType::UINT32(0)), None);
+    }
+}
@@ -0,0 +1,747 @@
+//! PTP/IP (ISO 15740 Annex, CIPA DC-005) initiator transport.
+//!
+//! A PTP/IP session uses two TCP connections to the responder: the command/data connection,
+//! which carries the transactions, and the event connection. Both are set up with the
+//! Init Command and Init Event handshakes in `PtpIpTransport::connect`.
+//!
+//! PTP/IP frames a transaction differently from USB: the Operation Request has to say up front
+//! whether a data-out phase follows, and data phases are split into Start Data / Data / End Data
+//! packets. This transport translates to and from the USB-style containers that `PtpCamera`
+//! works with, so the whole command layer runs unchanged on top of it.
+
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+use std::io::prelude::*;
+use std::io::Cursor;
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs, Shutdown};
+use std::time::Duration;
+use std::cmp::min;
+
+use super::{Error, PtpContainerInfo, PtpContainerType, PtpTransport, DataSink, CommandCode, ResponseCode,
+            StandardResponseCode};
+
+#[allow(non_upper_case_globals)]
+pub mod PacketType {
+    pub const InitCommandRequest: u32 = 1;
+    pub const InitCommandAck: u32 = 2;
+    pub const InitEventRequest: u32 = 3;
+    pub const InitEventAck: u32 = 4;
+    pub const InitFail: u32 = 5;
+    pub const OperationRequest: u32 = 6;
+    pub const OperationResponse: u32 = 7;
+    pub const Event: u32 = 8;
+    pub const StartData: u32 = 9;
+    pub const Data: u32 = 10;
+    pub const Cancel: u32 = 11;
+    pub const EndData: u32 = 12;
+    pub const ProbeRequest: u32 = 13;
+    pub const ProbeResponse: u32 = 14;
+}
+
+/// PTP/IP protocol version 1.0, sent in the Init Command Request
+pub const PTPIP_VERSION: u32 = 0x0001_0000;
+
+/// The well-known PTP/IP TCP port
+pub const PTPIP_PORT: u16 = 15740;
+
+const PTPIP_HEADER_SIZE: usize = 8;
+
+// data phase field of the Operation Request
+const DATA_PHASE_NONE_OR_IN: u32 = 1;
+const DATA_PHASE_OUT: u32 = 2;
+
+// largest Data packet payload written during a data-out phase
+const DATA_CHUNK_SIZE: usize = 1024 * 1024;
+
+// how long the rest of a packet may take once its first byte has arrived, if that is longer
+// than the caller's timeout
+const PACKET_TIMEOUT_SECS: u64 = 10;
+
+/// What the responder told us about itself in the Init Command Ack
+#[derive(Debug, Clone)]
+pub struct PtpIpResponderInfo {
+    pub connection_number: u32,
+    pub guid: [u8; 16],
+    pub name: String,
+    pub version: u32,
+}
+
+// an Operation Request held back until we know whether a data-out phase follows
+struct PendingRequest {
+    code: CommandCode,
+    tid: u32,
+    params: Vec<u8>,
+}
+
+pub struct PtpIpTransport {
+    command: TcpStream,
+    event: TcpStream,
+    responder: PtpIpResponderInfo,
+    pending: Option<PendingRequest>,
+    // operation code of the last request, used to label incoming data containers
+    last_code: CommandCode,
+}
+
+impl PtpIpTransport {
+    /// Connect to a PTP/IP responder and perform the Init Command and Init Event handshakes.
+    ///
+    /// `guid` and `name` identify this initiator; responders that require pairing remember them.
+    pub fn connect<A: ToSocketAddrs>(addr: A,
+                                     guid: [u8; 16],
+                                     name: &str,
+                                     timeout: Option<Duration>)
+                                     -> Result<PtpIpTransport, Error> {
+        let mut command = connect_stream(addr, timeout)?;
+        let timeout = timeout.unwrap_or(Duration::new(0, 0));
+        command.set_nodelay(true)?;
+        set_timeouts(&command, timeout)?;
+
+        let mut request = Vec::with_capacity(16 + name.len() * 2 + 6);
+        request.extend_from_slice(&guid);
+        write_ptpip_str(&mut request, name);
+        request.write_u32::<LittleEndian>(PTPIP_VERSION).ok();
+        write_packet(&mut command, PacketType::InitCommandRequest, &request)?;
+
+        let (kind, payload) = read_packet(&mut command)?;
+        let responder = match kind {
+            PacketType::InitCommandAck => {
+                let mut cur = Cursor::new(&payload[..]);
+                let connection_number = cur.read_u32::<LittleEndian>()?;
+                let mut guid = [0u8; 16];
+                cur.read_exact(&mut guid)?;
+                let name = read_ptpip_str(&mut cur)?;
+                let version = cur.read_u32::<LittleEndian>()?;
+                PtpIpResponderInfo {
+                    connection_number,
+                    guid,
+                    name,
+                    version,
+                }
+            }
+            PacketType::InitFail => return Err(init_failed("command", &payload)),
+            _ => return Err(Error::Malformed(format!("Unexpected PTP/IP packet type {} during init", kind))),
+        };
+        debug!("PTP/IP responder {:?}", responder);
+
+        let mut event = connect_stream(command.peer_addr()?, Some(timeout))?;
+        event.set_nodelay(true)?;
+        set_timeouts(&event, timeout)?;
+
+        let mut request = Vec::with_capacity(4);
+        request.write_u32::<LittleEndian>(responder.connection_number).ok();
+        write_packet(&mut event, PacketType::InitEventRequest, &request)?;
+
+        let (kind, payload) = read_packet(&mut event)?;
+        match kind {
+            PacketType::InitEventAck => {}
+            PacketType::InitFail => return Err(init_failed("event", &payload)),
+            _ => return Err(Error::Malformed(format!("Unexpected PTP/IP packet type {} during init", kind))),
+        }
+
+        Ok(PtpIpTransport {
+            command,
+            event,
+            responder,
+            pending: None,
+            last_code: 0,
+        })
+    }
+
+    pub fn responder(&self) -> &PtpIpResponderInfo {
+        &self.responder
+    }
+
+    // send the held-back Operation Request, if any
+    fn flush_request(&mut self, data_phase: u32) -> Result<(), Error> {
+        if let Some(req) = self.pending.take() {
+            trace!("PTP/IP OperationRequest 0x{:04x}, tid:{}, data phase {}", req.code, req.tid, data_phase);
+            let mut buf = Vec::with_capacity(10 + req.params.len());
+            buf.write_u32::<LittleEndian>(data_phase).ok();
+            buf.write_u16::<LittleEndian>(req.code).ok();
+            buf.write_u32::<LittleEndian>(req.tid).ok();
+            buf.extend_from_slice(&req.params);
+            write_packet(&mut self.command, PacketType::OperationRequest, &buf)?;
+        }
+        Ok(())
+    }
+}
+
+impl PtpTransport for PtpIpTransport {
+    fn write_container(&mut self, kind: PtpContainerType, code: CommandCode, tid: u32, payload: &[u8], timeout: Duration) -> Result<(), Error> {
+        set_timeouts(&self.command, timeout)?;
+
+        match kind {
+            PtpContainerType::Command => {
+                // a request still held back was abandoned before its data phase or response, so the
+                // responder never saw it; sending it now would leave its response unread
+                if let Some(req) = self.pending.take() {
+                    warn!("PTP/IP request 0x{:04x}, tid:{} abandoned before it was sent", req.code, req.tid);
+                }
+                self.last_code = code;
+                self.pending = Some(PendingRequest {
+                    code,
+                    tid,
+                    params: payload.to_vec(),
+                });
+                Ok(())
+            }
+            PtpContainerType::Data => {
+                self.flush_request(DATA_PHASE_OUT)?;
+
+                let mut buf = Vec::with_capacity(12);
+                buf.write_u32::<LittleEndian>(tid).ok();
+                buf.write_u64::<LittleEndian>(payload.len() as u64).ok();
+                write_packet(&mut self.command, PacketType::StartData, &buf)?;
+
+                let mut chunks = payload.chunks(DATA_CHUNK_SIZE).peekable();
+                if chunks.peek().is_none() {
+                    write_data_packet(&mut self.command, PacketType::EndData, tid, &[])?;
+                }
+                while let Some(chunk) = chunks.next() {
+                    let kind = if chunks.peek().is_some() { PacketType::Data } else { PacketType::EndData };
+                    write_data_packet(&mut self.command, kind, tid, chunk)?;
+                }
+                Ok(())
+            }
+            _ => Err(Error::Malformed(format!("Cannot send a {:?} container over PTP/IP", kind))),
+        }
+    }
+
+    fn write_container_from(&mut self,
+                            kind: PtpContainerType,
+                            code: CommandCode,
+                            tid: u32,
+                            len: u64,
+                            source: &mut dyn Read,
+                            timeout: Duration)
+                            -> Result<(), Error> {
+        if kind != PtpContainerType::Data {
+            return Err(Error::Malformed(format!("Cannot stream a {:?} container over PTP/IP", kind)));
+        }
+        set_timeouts(&self.command, timeout)?;
+        trace!("PTP/IP data phase 0x{:04x}, tid:{}, {} bytes streamed", code, tid, len);
+
+        self.flush_request(DATA_PHASE_OUT)?;
+
+        let mut buf = Vec::with_capacity(12);
+        buf.write_u32::<LittleEndian>(tid).ok();
+        buf.write_u64::<LittleEndian>(len).ok();
+        write_packet(&mut self.command, PacketType::StartData, &buf)?;
+
+        let mut chunk = vec![0u8; min(len, DATA_CHUNK_SIZE as u64) as usize];
+        let mut remaining = len;
+        loop {
+            let n = min(remaining, DATA_CHUNK_SIZE as u64) as usize;
+            if let Err(e) = source.read_exact(&mut chunk[..n]) {
+                if let Err(e) = self.cancel(tid, timeout) {
+                    warn!("failed to cancel transaction {} after its data source failed: {}", tid, e);
+                }
+                return Err(e.into());
+            }
+            remaining -= n as u64;
+
+            let kind = if remaining > 0 { PacketType::Data } else { PacketType::EndData };
+            write_data_packet(&mut self.command, kind, tid, &chunk[..n])?;
+            if remaining == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    fn read_container(&mut self, timeout: Duration) -> Result<(PtpContainerInfo, Vec<u8>), Error> {
+        let mut data = vec![];
+        let (cinfo, payload) = self.read_container_into(&mut data, timeout)?;
+        if cinfo.kind == PtpContainerType::Data {
+            Ok((cinfo, data))
+        } else {
+            Ok((cinfo, payload))
+        }
+    }
+
+    fn read_container_into(&mut self, sink: &mut dyn DataSink, timeout: Duration) -> Result<(PtpContainerInfo, Vec<u8>), Error> {
+        set_timeouts(&self.command, timeout)?;
+        self.flush_request(DATA_PHASE_NONE_OR_IN)?;
+
+        let (kind, payload) = wait_packet(&mut self.command, timeout)?;
+        let mut cur = Cursor::new(&payload[..]);
+        match kind {
+            PacketType::StartData => {
+                let tid = cur.read_u32::<LittleEndian>()?;
+                let total = cur.read_u64::<LittleEndian>()?;
+                trace!("PTP/IP StartData tid:{}, {} bytes", tid, total);
+
+                // total may be 0xFFFFFFFFFFFFFFFF if the responder doesn't know the size up front
+                sink.begin(total)?;
+                let mut received = 0u64;
+                loop {
+                    let (kind, payload) = wait_packet(&mut self.command, timeout)?;
+                    let mut cur = Cursor::new(&payload[..]);
+                    let data_tid = cur.read_u32::<LittleEndian>()?;
+                    if data_tid != tid {
+                        return Err(Error::Malformed(format!("mismatched txnid {} in data phase, expecting {}", data_tid, tid)));
+                    }
+                    match kind {
+                        PacketType::Data | PacketType::EndData => {
+                            received += (payload.len() - 4) as u64;
+                            if total != u64::MAX && received > total {
+                                return Err(Error::Malformed(format!("PTP/IP data phase longer than the {} bytes announced", total)));
+                            }
+                            sink.write_chunk(&payload[4..])?;
+                            if kind == PacketType::EndData {
+                                break;
+                            }
+                        }
+                        PacketType::Cancel => return Err(Error::Response(StandardResponseCode::TransactionCancelled)),
+                        _ => return Err(Error::Malformed(format!("Unexpected PTP/IP packet type {} in data phase", kind))),
+                    }
+                }
+                if total != u64::MAX && received != total {
+                    return Err(Error::Malformed(format!("PTP/IP data phase ended after {} of the {} bytes announced", received, total)));
+                }
+
+                let cinfo = PtpContainerInfo {
+                    payload_len: received as usize,
+                    kind: PtpContainerType::Data,
+                    code: self.last_code,
+                    tid,
+                };
+                Ok((cinfo, vec![]))
+            }
+            PacketType::OperationResponse => {
+                let code = cur.read_u16::<LittleEndian>()?;
+                let tid = cur.read_u32::<LittleEndian>()?;
+                let params = payload[6..].to_vec();
+                let cinfo = PtpContainerInfo {
+                    payload_len: params.len(),
+                    kind: PtpContainerType::Response,
+                    code,
+                    tid,
+                };
+                Ok((cinfo, params))
+            }
+            _ => Err(Error::Malformed(format!("Unexpected PTP/IP packet type {} on command connection", kind))),
+        }
+    }
+
+    fn read_event(&mut self, timeout: Duration) -> Result<Option<(PtpContainerInfo, Vec<u8>)>, Error> {
+        loop {
+            let (kind, payload) = match poll_packet(&mut self.event, timeout)? {
+                Some(packet) => packet,
+                None => return Ok(None),
+            };
+
+            match kind {
+                PacketType::Event => {
+                    let mut cur = Cursor::new(&payload[..]);
+                    let code = cur.read_u16::<LittleEndian>()?;
+                    let tid = cur.read_u32::<LittleEndian>()?;
+                    let params = payload[6..].to_vec();
+                    let cinfo = PtpContainerInfo {
+                        payload_len: params.len(),
+                        kind: PtpContainerType::Event,
+                        code,
+                        tid,
+                    };
+                    return Ok(Some((cinfo, params)));
+                }
+                PacketType::ProbeRequest => {
+                    // keep-alive from the responder
+                    write_packet(&mut self.event, PacketType::ProbeResponse, &[])?;
+                }
+                _ => return Err(Error::Malformed(format!("Unexpected PTP/IP packet type {} on event connection", kind))),
+            }
+        }
+    }
+
+    fn cancel(&mut self, tid: u32, timeout: Duration) -> Result<(), Error> {
+        set_timeouts(&self.command, timeout)?;
+        self.pending = None;
+
+        let mut buf = Vec::with_capacity(4);
+        buf.write_u32::<LittleEndian>(tid).ok();
+        write_packet(&mut self.command, PacketType::Cancel, &buf)
+    }
+
+    fn reset(&mut self, _timeout: Duration) -> Result<(), Error> {
+        // PTP/IP has no transport-level reset; use the ResetDevice operation instead
+        Err(Error::Response(StandardResponseCode::OperationNotSupported))
+    }
+
+    fn status(&mut self, _timeout: Duration) -> Result<(ResponseCode, Vec<u32>), Error> {
+        // there is no status request either, but the TCP framing means there is never
+        // a stalled pipe to wait on, so the responder is always ready for the next request
+        Ok((StandardResponseCode::Ok, vec![]))
+    }
+
+    fn close(&mut self) -> Result<(), Error> {
+        self.event.shutdown(Shutdown::Both)?;
+        self.command.shutdown(Shutdown::Both)?;
+        Ok(())
+    }
+
+    fn drain(&mut self, timeout: Duration) -> Result<(), Error> {
+        // packets are read whole, so whatever is left starts on a packet boundary
+        loop {
+            // a zero timeout would wait forever for the next packet, so only take those already queued
+            let packet = if timeout == Duration::new(0, 0) {
+                if !packet_queued(&self.command)? {
+                    return Ok(());
+                }
+                poll_packet(&mut self.command, Duration::from_secs(PACKET_TIMEOUT_SECS))?
+            } else {
+                poll_packet(&mut self.command, timeout)?
+            };
+            match packet {
+                Some((kind, payload)) => trace!("PTP/IP drained packet type {}, {} bytes", kind, payload.len()),
+                None => return Ok(()),
+            }
+        }
+    }
+}
+
+// connect to the first of `addr` that accepts, giving each at most `timeout`
+fn connect_stream<A: ToSocketAddrs>(addr: A, timeout: Option<Duration>) -> Result<TcpStream, Error> {
+    let timeout = match timeout {
+        Some(t) if t != Duration::new(0, 0) => t,
+        _ => return Ok(TcpStream::connect(addr)?),
+    };
+
+    let mut last_error = None;
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to")).into())
+}
+
+fn set_timeouts(stream: &TcpStream, timeout: Duration) -> Result<(), Error> {
+    // zero means unlimited, which std spells as None
+    let timeout = if timeout == Duration::new(0, 0) { None } else { Some(timeout) };
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)?;
+    Ok(())
+}
+
+fn init_failed(connection: &str, payload: &[u8]) -> Error {
+    let reason = Cursor::new(payload).read_u32::<LittleEndian>().unwrap_or(0);
+    Error::Io(io::Error::new(io::ErrorKind::ConnectionRefused,
+                             format!("PTP/IP {} connection rejected, reason 0x{:08x}", connection, reason)))
+}
+
+fn write_packet<W: Write>(w: &mut W, kind: u32, payload: &[u8]) -> Result<(), Error> {
+    let mut buf = Vec::with_capacity(PTPIP_HEADER_SIZE + payload.len());
+    buf.write_u32::<LittleEndian>((PTPIP_HEADER_SIZE + payload.len()) as u32).ok();
+    buf.write_u32::<LittleEndian>(kind).ok();
+    buf.extend_from_slice(payload);
+    w.write_all(&buf)?;
+    Ok(())
+}
+
+fn write_data_packet<W: Write>(w: &mut W, kind: u32, tid: u32, data: &[u8]) -> Result<(), Error> {
+    let mut buf = Vec::with_capacity(4 + data.len());
+    buf.write_u32::<LittleEndian>(tid).ok();
+    buf.extend_from_slice(data);
+    write_packet(w, kind, &buf)
+}
+
+// wait up to `timeout` for a packet to start, then read all of it; None if none started in time.
+// once the header has been consumed the packet can't be read again later, so a packet that stops
+// arriving partway is reported as a broken connection rather than as a timeout.
+fn poll_packet(stream: &mut TcpStream, timeout: Duration) -> Result<Option<(u32, Vec<u8>)>, Error> {
+    set_timeouts(stream, timeout)?;
+    match stream.peek(&mut [0u8]) {
+        Ok(0) => return Err(Error::Io(io::Error::new(io::ErrorKind::ConnectionAborted, "PTP/IP connection closed"))),
+        Ok(_) => {}
+        Err(ref e) if is_timeout(e) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    if timeout != Duration::new(0, 0) {
+        stream.set_read_timeout(Some(timeout.max(Duration::from_secs(PACKET_TIMEOUT_SECS))))?;
+    }
+    match read_packet(stream) {
+        Ok(packet) => Ok(Some(packet)),
+        Err(Error::Io(ref e)) if is_timeout(e) => {
+            Err(Error::Io(io::Error::new(io::ErrorKind::ConnectionAborted, "PTP/IP packet stalled partway")))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// whether a packet (or the end of the connection) is already waiting, without blocking
+fn packet_queued(stream: &TcpStream) -> Result<bool, Error> {
+    stream.set_nonblocking(true)?;
+    let result = stream.peek(&mut [0u8]);
+    stream.set_nonblocking(false)?;
+    match result {
+        Ok(_) => Ok(true),
+        Err(ref e) if is_timeout(e) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// like poll_packet, but a packet not starting in time is a timeout error
+fn wait_packet(stream: &mut TcpStream, timeout: Duration) -> Result<(u32, Vec<u8>), Error> {
+    poll_packet(stream, timeout)?
+        .ok_or_else(|| Error::Io(io::Error::new(io::ErrorKind::TimedOut, "no PTP/IP packet within the timeout")))
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+fn read_packet<R: Read>(r: &mut R) -> Result<(u32, Vec<u8>), Error> {
+    let len = r.read_u32::<LittleEndian>()? as usize;
+    let kind = r.read_u32::<LittleEndian>()?;
+    if len < PTPIP_HEADER_SIZE {
+        return Err(Error::Malformed(format!("Invalid PTP/IP packet length {}", len)));
+    }
+
+    let mut payload = vec![0u8; len - PTPIP_HEADER_SIZE];
+    r.read_exact(&mut payload)?;
+    Ok((kind, payload))
+}
+
+// PTP/IP strings are plain null-terminated UTF-16LE, without the length prefix of PTP datasets
+fn write_ptpip_str(out: &mut Vec<u8>, s: &str) {
+    for c in s.encode_utf16() {
+        out.write_u16::<LittleEndian>(c).ok();
+    }
+    out.write_u16::<LittleEndian>(0).ok();
+}
+
+fn read_ptpip_str<R: Read>(r: &mut R) -> Result<String, Error> {
+    let mut data = vec![];
+    loop {
+        match r.read_u16::<LittleEndian>()? {
+            0 => break,
+            c => data.push(c),
+        }
+    }
+    String::from_utf16(&data).map_err(|_| Error::Malformed(format!("Invalid UTF16 data: {:?}", data)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{PtpCamera, PtpDataType};
+    use std::net::TcpListener;
+    use std::thread;
+
+    // the responder's ends of the two connections
+    struct Responder {
+        command: TcpStream,
+        event: TcpStream,
+    }
+
+    impl Responder {
+        // accept both connections and answer the handshakes, as connection number 7
+        fn accept(listener: &TcpListener) -> Responder {
+            let (mut command, _) = listener.accept().unwrap();
+            let (kind, payload) = read_packet(&mut command).unwrap();
+            assert_eq!(kind, PacketType::InitCommandRequest);
+            assert_eq!(&payload[..16], &[1; 16]);
+
+            let mut ack = vec![];
+            ack.write_u32::<LittleEndian>(7).unwrap();
+            ack.extend_from_slice(&[2; 16]);
+            write_ptpip_str(&mut ack, "camera");
+            ack.write_u32::<LittleEndian>(PTPIP_VERSION).unwrap();
+            write_packet(&mut command, PacketType::InitCommandAck, &ack).unwrap();
+
+            let (mut event, _) = listener.accept().unwrap();
+            let (kind, payload) = read_packet(&mut event).unwrap();
+            assert_eq!(kind, PacketType::InitEventRequest);
+            assert_eq!(payload, [7, 0, 0, 0]);
+            write_packet(&mut event, PacketType::InitEventAck, &[]).unwrap();
+
+            Responder { command, event }
+        }
+
+        // read an Operation Request, returning its data phase, code, tid and parameters
+        fn request(&mut self) -> (u32, u16, u32, Vec<u8>) {
+            let (kind, payload) = read_packet(&mut self.command).unwrap();
+            assert_eq!(kind, PacketType::OperationRequest);
+            let mut cur = Cursor::new(&payload[..]);
+            let data_phase = cur.read_u32::<LittleEndian>().unwrap();
+            let code = cur.read_u16::<LittleEndian>().unwrap();
+            let tid = cur.read_u32::<LittleEndian>().unwrap();
+            (data_phase, code, tid, payload[10..].to_vec())
+        }
+
+        fn respond(&mut self, code: u16, tid: u32, params: &[u32]) {
+            let mut buf = vec![];
+            buf.write_u16::<LittleEndian>(code).unwrap();
+            buf.write_u32::<LittleEndian>(tid).unwrap();
+            for p in params {
+                buf.write_u32::<LittleEndian>(*p).unwrap();
+            }
+            write_packet(&mut self.command, PacketType::OperationResponse, &buf).unwrap();
+        }
+    }
+
+    fn event_packet(code: u16, tid: u32, param: u32) -> Vec<u8> {
+        let mut payload = vec![];
+        payload.write_u16::<LittleEndian>(code).unwrap();
+        payload.write_u32::<LittleEndian>(tid).unwrap();
+        payload.write_u32::<LittleEndian>(param).unwrap();
+        let mut packet = vec![];
+        write_packet(&mut packet, PacketType::Event, &payload).unwrap();
+        packet
+    }
+
+    fn connect<F: FnOnce(Responder) + Send + 'static>(responder: F) -> (PtpCamera<PtpIpTransport>, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let thread = thread::spawn(move || responder(Responder::accept(&listener)));
+        let transport = PtpIpTransport::connect(addr, [1; 16], "test", Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(transport.responder().name, "camera");
+        assert_eq!(transport.responder().guid, [2; 16]);
+        (PtpCamera::with_transport(transport), thread)
+    }
+
+    #[test]
+    fn data_in() {
+        let (mut camera, responder) = connect(|mut r| {
+            let (data_phase, code, tid, params) = r.request();
+            assert_eq!((data_phase, code, params), (DATA_PHASE_NONE_OR_IN, 0x1004, vec![]));
+
+            let mut start = vec![];
+            start.write_u32::<LittleEndian>(tid).unwrap();
+            start.write_u64::<LittleEndian>(12).unwrap();
+            write_packet(&mut r.command, PacketType::StartData, &start).unwrap();
+            write_data_packet(&mut r.command, PacketType::Data, tid, &[2, 0, 0, 0, 1, 0]).unwrap();
+            write_data_packet(&mut r.command, PacketType::EndData, tid, &[1, 0, 2, 0, 1, 0]).unwrap();
+            r.respond(StandardResponseCode::Ok, tid, &[]);
+        });
+
+        assert_eq!(camera.get_storageids(None).unwrap(), vec![0x00010001, 0x00010002]);
+        responder.join().unwrap();
+    }
+
+    #[test]
+    fn data_out() {
+        let (mut camera, responder) = connect(|mut r| {
+            let (data_phase, code, tid, params) = r.request();
+            assert_eq!((data_phase, code, params), (DATA_PHASE_OUT, 0x1016, vec![0x07, 0x50, 0, 0]));
+
+            let (kind, payload) = read_packet(&mut r.command).unwrap();
+            assert_eq!(kind, PacketType::StartData);
+            assert_eq!(payload, [tid as u8, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0]);
+            let (kind, payload) = read_packet(&mut r.command).unwrap();
+            assert_eq!(kind, PacketType::EndData);
+            assert_eq!(payload, [tid as u8, 0, 0, 0, 0x18, 0x01]);
+            r.respond(StandardResponseCode::Ok, tid, &[]);
+        });
+
+        camera.set_device_prop_value(0x5007, &PtpDataType::UINT16(280), None).unwrap();
+        responder.join().unwrap();
+    }
+
+    #[test]
+    fn response_params_and_errors() {
+        let (mut camera, responder) = connect(|mut r| {
+            let (_, code, tid, _) = r.request();
+            assert_eq!(code, 0x101A);
+            r.respond(StandardResponseCode::Ok, tid, &[42]);
+            let (_, _, tid, _) = r.request();
+            r.respond(StandardResponseCode::InvalidObjectHandle, tid, &[]);
+        });
+
+        let response = camera.command_full(0x101A, &[5, 0x00010001, 0], None, None).unwrap();
+        assert_eq!(response.params, vec![42]);
+        match camera.command(0x1008, &[6], None, None) {
+            Err(Error::Response(StandardResponseCode::InvalidObjectHandle)) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        responder.join().unwrap();
+    }
+
+    #[test]
+    fn data_phase_shorter_than_announced() {
+        let (mut camera, responder) = connect(|mut r| {
+            let (_, _, tid, _) = r.request();
+            let mut start = vec![];
+            start.write_u32::<LittleEndian>(tid).unwrap();
+            start.write_u64::<LittleEndian>(12).unwrap();
+            write_packet(&mut r.command, PacketType::StartData, &start).unwrap();
+            write_data_packet(&mut r.command, PacketType::EndData, tid, &[1, 0, 0, 0, 1, 0]).unwrap();
+            let _ = r.command.read(&mut [0u8]);
+        });
+
+        match camera.get_storageids(None) {
+            Err(Error::Malformed(_)) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        drop(camera);
+        responder.join().unwrap();
+    }
+
+    #[test]
+    fn abandoned_request_is_not_sent() {
+        let (mut camera, responder) = connect(|mut r| {
+            let (_, code, tid, _) = r.request();
+            assert_eq!((code, tid), (0x1002, 2));
+            r.respond(StandardResponseCode::Ok, tid, &[]);
+        });
+
+        let transport = camera.transport();
+        let timeout = Duration::from_secs(1);
+        transport.write_container(PtpContainerType::Command, 0x1001, 1, &[], timeout).unwrap();
+        transport.write_container(PtpContainerType::Command, 0x1002, 2, &[1, 0, 0, 0], timeout).unwrap();
+        let (cinfo, _) = transport.read_container(timeout).unwrap();
+        assert_eq!((cinfo.kind, cinfo.code, cinfo.tid), (PtpContainerType::Response, StandardResponseCode::Ok, 2));
+        responder.join().unwrap();
+    }
+
+    #[test]
+    fn drain_without_timeout() {
+        let (mut camera, responder) = connect(|mut r| {
+            r.respond(StandardResponseCode::Ok, 9, &[]);
+            // stay connected until the initiator hangs up
+            let _ = r.command.read(&mut [0u8]);
+        });
+
+        thread::sleep(Duration::from_millis(100));
+        camera.transport().drain(Duration::new(0, 0)).unwrap();
+        // nothing left, and a second drain must not wait for more
+        camera.transport().drain(Duration::new(0, 0)).unwrap();
+        drop(camera);
+        responder.join().unwrap();
+    }
+
+    #[test]
+    fn connect_times_out() {
+        // nothing answers on a non-routable address, so only the timeout ends the attempt
+        let start = std::time::Instant::now();
+        assert!(PtpIpTransport::connect("10.255.255.1:15740", [1; 16], "test", Some(Duration::from_millis(200))).is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn event_split_across_timeout() {
+        let (mut camera, responder) = connect(|mut r| {
+            let packet = event_packet(0x4002, 3, 0x1234);
+            r.event.write_all(&packet[..6]).unwrap();
+            thread::sleep(Duration::from_millis(200));
+            r.event.write_all(&packet[6..]).unwrap();
+            write_packet(&mut r.event, PacketType::ProbeRequest, &[]).unwrap();
+            r.event.write_all(&event_packet(0x4006, 0, 0x5007)).unwrap();
+            let (kind, _) = read_packet(&mut r.event).unwrap();
+            assert_eq!(kind, PacketType::ProbeResponse);
+            // stay connected until the initiator hangs up
+            let _ = r.event.read(&mut [0u8]);
+        });
+
+        // the first read times out while the packet is incomplete, but must not lose its start
+        let event = camera.poll_event(Some(Duration::from_millis(50))).unwrap().unwrap();
+        assert_eq!((event.code, event.tid, event.params), (0x4002, 3, vec![0x1234]));
+        let event = camera.poll_event(Some(Duration::from_secs(1))).unwrap().unwrap();
+        assert_eq!((event.code, event.params), (0x4006, vec![0x5007]));
+        assert!(camera.poll_event(Some(Duration::from_millis(50))).unwrap().is_none());
+        drop(camera);
+        responder.join().unwrap();
+    }
+}
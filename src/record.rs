@@ -0,0 +1,544 @@
+//! Recording and replaying PTP sessions.
+//!
+//! `RecordingTransport` wraps any transport and logs every container that passes through it,
+//! with a timestamp and direction, to a `Write` sink. `ReplayTransport` serves such a log back:
+//! it checks that the camera sends exactly what was recorded and answers with the recorded
+//! responses, so a session captured against a misbehaving device can be rerun offline through
+//! the same `PtpCamera` calls.
+//!
+//! The log is a sequence of records, each `kind: u8, sec: i64, nsec: u32, len: u32` followed by
+//! `len` bytes of data, all little endian. Container records hold the raw USB container,
+//! header included.
+
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufReader, Cursor};
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use super::{Error, PtpContainerInfo, PtpContainerType, PtpTransport, PtpRead, DataSink, CommandCode,
+            ResponseCode, PTP_CONTAINER_INFO_SIZE};
+
+/// What a record in a session log describes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordKind {
+    /// container sent by the initiator
+    Sent,
+    /// container received from the responder
+    Received,
+    /// event container received from the responder
+    Event,
+    /// a failed read; the data is the error message
+    Failed,
+    /// Cancel request; the data is the transaction ID
+    Cancel,
+    /// device reset request
+    Reset,
+    /// status request; the data is the status code followed by its parameters
+    Status,
+}
+
+impl RecordKind {
+    fn to_u8(self) -> u8 {
+        match self {
+            RecordKind::Sent => b'>',
+            RecordKind::Received => b'<',
+            RecordKind::Event => b'!',
+            RecordKind::Failed => b'x',
+            RecordKind::Cancel => b'c',
+            RecordKind::Reset => b'r',
+            RecordKind::Status => b's',
+        }
+    }
+
+    fn from_u8(v: u8) -> Option<RecordKind> {
+        match v {
+            b'>' => Some(RecordKind::Sent),
+            b'<' => Some(RecordKind::Received),
+            b'!' => Some(RecordKind::Event),
+            b'x' => Some(RecordKind::Failed),
+            b'c' => Some(RecordKind::Cancel),
+            b'r' => Some(RecordKind::Reset),
+            b's' => Some(RecordKind::Status),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub kind: RecordKind,
+    pub time: time::Timespec,
+    pub data: Vec<u8>,
+}
+
+impl Record {
+    pub fn write<W: Write>(&self, w: &mut W) -> Result<(), Error> {
+        let mut buf = Vec::with_capacity(17 + self.data.len());
+        buf.write_u8(self.kind.to_u8()).ok();
+        buf.write_i64::<LittleEndian>(self.time.sec).ok();
+        buf.write_u32::<LittleEndian>(self.time.nsec as u32).ok();
+        buf.write_u32::<LittleEndian>(self.data.len() as u32).ok();
+        buf.extend_from_slice(&self.data);
+        w.write_all(&buf)?;
+        Ok(())
+    }
+
+    /// Read the next record, or `None` at the end of the log.
+    pub fn read<R: Read>(r: &mut R) -> Result<Option<Record>, Error> {
+        let kind = match r.read_u8() {
+            Ok(v) => v,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let kind = RecordKind::from_u8(kind)
+            .ok_or_else(|| Error::Malformed(format!("Invalid record kind {:x}", kind)))?;
+        let sec = r.read_i64::<LittleEndian>()?;
+        let nsec = r.read_u32::<LittleEndian>()?;
+        let len = r.read_u32::<LittleEndian>()? as u64;
+        // grow the buffer as the data arrives, so a corrupt length can't make us allocate 4GB up front
+        let mut data = vec![];
+        if r.take(len).read_to_end(&mut data)? as u64 != len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+
+        Ok(Some(Record {
+            kind,
+            time: time::Timespec::new(sec, nsec as i32),
+            data,
+        }))
+    }
+
+    /// Read a complete session log.
+    pub fn read_all<R: Read>(r: &mut R) -> Result<Vec<Record>, Error> {
+        let mut records = vec![];
+        while let Some(record) = Record::read(r)? {
+            records.push(record);
+        }
+        Ok(records)
+    }
+
+    /// the container header of a Sent, Received or Event record
+    pub fn container(&self) -> Result<PtpContainerInfo, Error> {
+        PtpContainerInfo::parse(&self.data[..])
+    }
+}
+
+/// Transport wrapper that logs every container to `out`
+pub struct RecordingTransport<T: PtpTransport, W: Write> {
+    inner: T,
+    out: W,
+}
+
+impl<T: PtpTransport> RecordingTransport<T, File> {
+    /// Record to a new file at `path`, replacing any existing one.
+    pub fn create<P: AsRef<Path>>(inner: T, path: P) -> Result<RecordingTransport<T, File>, Error> {
+        Ok(RecordingTransport::new(inner, File::create(path)?))
+    }
+}
+
+impl<T: PtpTransport, W: Write> RecordingTransport<T, W> {
+    pub fn new(inner: T, out: W) -> RecordingTransport<T, W> {
+        RecordingTransport { inner, out }
+    }
+
+    pub fn into_inner(self) -> (T, W) {
+        (self.inner, self.out)
+    }
+
+    fn record(&mut self, kind: RecordKind, data: Vec<u8>) -> Result<(), Error> {
+        Record { kind, time: time::get_time(), data }.write(&mut self.out)
+    }
+
+    fn record_container(&mut self, kind: RecordKind, cinfo: &PtpContainerInfo, payload: &[u8]) -> Result<(), Error> {
+        let mut data = cinfo.encode();
+        data.extend_from_slice(payload);
+        self.record(kind, data)
+    }
+}
+
+impl<T: PtpTransport, W: Write> PtpTransport for RecordingTransport<T, W> {
+    fn write_container(&mut self, kind: PtpContainerType, code: CommandCode, tid: u32, payload: &[u8], timeout: Duration) -> Result<(), Error> {
+        let cinfo = PtpContainerInfo { payload_len: payload.len(), kind, code, tid };
+        self.record_container(RecordKind::Sent, &cinfo, payload)?;
+        self.inner.write_container(kind, code, tid, payload, timeout)
+    }
+
+    fn read_container(&mut self, timeout: Duration) -> Result<(PtpContainerInfo, Vec<u8>), Error> {
+        match self.inner.read_container(timeout) {
+            Ok((cinfo, payload)) => {
+                self.record_container(RecordKind::Received, &cinfo, &payload)?;
+                Ok((cinfo, payload))
+            }
+            Err(e) => {
+                self.record(RecordKind::Failed, e.to_string().into_bytes())?;
+                Err(e)
+            }
+        }
+    }
+
+    fn read_container_into(&mut self, sink: &mut dyn DataSink, timeout: Duration) -> Result<(PtpContainerInfo, Vec<u8>), Error> {
+        let mut tee = TeeSink { sink, copy: vec![] };
+        match self.inner.read_container_into(&mut tee, timeout) {
+            Ok((cinfo, payload)) => {
+                if cinfo.kind == PtpContainerType::Data {
+                    self.record_container(RecordKind::Received, &cinfo, &tee.copy)?;
+                } else {
+                    self.record_container(RecordKind::Received, &cinfo, &payload)?;
+                }
+                Ok((cinfo, payload))
+            }
+            Err(e) => {
+                self.record(RecordKind::Failed, e.to_string().into_bytes())?;
+                Err(e)
+            }
+        }
+    }
+
+    fn write_container_from(&mut self,
+                            kind: PtpContainerType,
+                            code: CommandCode,
+                            tid: u32,
+                            len: u64,
+                            source: &mut dyn Read,
+                            timeout: Duration)
+                            -> Result<(), Error> {
+        let mut tee = TeeReader { source, copy: vec![], failed: false };
+        let result = self.inner.write_container_from(kind, code, tid, len, &mut tee, timeout);
+
+        // the inner transport cancels the transaction itself when the source fails, which a
+        // replay does through `cancel`, so log it that way
+        if tee.failed {
+            let mut data = vec![];
+            data.write_u32::<LittleEndian>(tid).ok();
+            self.record(RecordKind::Cancel, data)?;
+        } else {
+            let cinfo = PtpContainerInfo { payload_len: tee.copy.len(), kind, code, tid };
+            self.record_container(RecordKind::Sent, &cinfo, &tee.copy)?;
+        }
+        result
+    }
+
+    fn read_event(&mut self, timeout: Duration) -> Result<Option<(PtpContainerInfo, Vec<u8>)>, Error> {
+        let event = self.inner.read_event(timeout)?;
+        if let Some((ref cinfo, ref payload)) = event {
+            self.record_container(RecordKind::Event, cinfo, payload)?;
+        }
+        Ok(event)
+    }
+
+    fn cancel(&mut self, tid: u32, timeout: Duration) -> Result<(), Error> {
+        let mut data = vec![];
+        data.write_u32::<LittleEndian>(tid).ok();
+        self.record(RecordKind::Cancel, data)?;
+        self.inner.cancel(tid, timeout)
+    }
+
+    fn reset(&mut self, timeout: Duration) -> Result<(), Error> {
+        self.record(RecordKind::Reset, vec![])?;
+        self.inner.reset(timeout)
+    }
+
+    fn status(&mut self, timeout: Duration) -> Result<(ResponseCode, Vec<u32>), Error> {
+        let (code, params) = self.inner.status(timeout)?;
+        let mut data = vec![];
+        data.write_u16::<LittleEndian>(code).ok();
+        for p in &params {
+            data.write_u32::<LittleEndian>(*p).ok();
+        }
+        self.record(RecordKind::Status, data)?;
+        Ok((code, params))
+    }
+
+    fn close(&mut self) -> Result<(), Error> {
+        self.out.flush()?;
+        self.inner.close()
+    }
+
+    fn drain(&mut self, timeout: Duration) -> Result<(), Error> {
+        self.inner.drain(timeout)
+    }
+}
+
+// passes a streamed data phase on to `sink`, keeping a copy for the log
+struct TeeSink<'a> {
+    sink: &'a mut dyn DataSink,
+    copy: Vec<u8>,
+}
+
+impl<'a> DataSink for TeeSink<'a> {
+    fn begin(&mut self, len: u64) -> Result<(), Error> {
+        self.sink.begin(len)
+    }
+
+    fn write_chunk(&mut self, chunk: &[u8]) -> Result<(), Error> {
+        self.copy.extend_from_slice(chunk);
+        self.sink.write_chunk(chunk)
+    }
+}
+
+// reads a streamed data phase from `source`, keeping a copy for the log
+struct TeeReader<'a> {
+    source: &'a mut dyn Read,
+    copy: Vec<u8>,
+    failed: bool,
+}
+
+impl<'a> Read for TeeReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.source.read(buf) {
+            // the transport only reads what is left of the data phase, so this is the source ending early
+            Ok(0) if !buf.is_empty() => {
+                self.failed = true;
+                Ok(0)
+            }
+            Ok(n) => {
+                self.copy.extend_from_slice(&buf[..n]);
+                Ok(n)
+            }
+            Err(e) => {
+                self.failed = true;
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Transport that plays back a recorded session
+///
+/// Anything the camera sends must match the recording byte for byte; the first divergence
+/// is reported as `Error::Malformed`.
+pub struct ReplayTransport {
+    // everything but events, in the order it happened
+    records: VecDeque<Record>,
+    // events are read from a separate endpoint, so they are served independently
+    events: VecDeque<Record>,
+}
+
+impl ReplayTransport {
+    pub fn new(records: Vec<Record>) -> ReplayTransport {
+        let (events, records) = records.into_iter().partition(|r| r.kind == RecordKind::Event);
+        ReplayTransport {
+            records,
+            events,
+        }
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<ReplayTransport, Error> {
+        let mut file = BufReader::new(File::open(path)?);
+        Ok(ReplayTransport::new(Record::read_all(&mut file)?))
+    }
+
+    /// number of recorded transport operations not yet replayed, not counting events
+    pub fn remaining(&self) -> usize {
+        self.records.len()
+    }
+
+    fn next(&mut self, expected: &str) -> Result<Record, Error> {
+        self.records.pop_front()
+            .ok_or_else(|| Error::Malformed(format!("Replay ended, expected {}", expected)))
+    }
+
+    fn next_of(&mut self, kind: RecordKind) -> Result<Record, Error> {
+        let record = self.next(&format!("{:?}", kind))?;
+        if record.kind != kind {
+            return Err(Error::Malformed(format!("Replay diverged: got {:?}, recorded {:?}", kind, record.kind)));
+        }
+        Ok(record)
+    }
+}
+
+impl PtpTransport for ReplayTransport {
+    fn write_container(&mut self, kind: PtpContainerType, code: CommandCode, tid: u32, payload: &[u8], _timeout: Duration) -> Result<(), Error> {
+        let record = self.next_of(RecordKind::Sent)?;
+
+        let cinfo = PtpContainerInfo { payload_len: payload.len(), kind, code, tid };
+        let mut data = cinfo.encode();
+        data.extend_from_slice(payload);
+        if data != record.data {
+            return Err(Error::Malformed(format!("Replay diverged: sent {:?}, recorded {:?}", cinfo, record.container()?)));
+        }
+        Ok(())
+    }
+
+    fn read_container(&mut self, _timeout: Duration) -> Result<(PtpContainerInfo, Vec<u8>), Error> {
+        let record = self.next("Received")?;
+        match record.kind {
+            RecordKind::Received => {
+                let cinfo = record.container()?;
+                Ok((cinfo, record.data[PTP_CONTAINER_INFO_SIZE..].to_vec()))
+            }
+            RecordKind::Failed => {
+                let msg = String::from_utf8_lossy(&record.data).into_owned();
+                Err(Error::Io(io::Error::other(msg)))
+            }
+            kind => Err(Error::Malformed(format!("Replay diverged: got Received, recorded {:?}", kind))),
+        }
+    }
+
+    fn read_event(&mut self, _timeout: Duration) -> Result<Option<(PtpContainerInfo, Vec<u8>)>, Error> {
+        match self.events.pop_front() {
+            Some(record) => {
+                let cinfo = record.container()?;
+                Ok(Some((cinfo, record.data[PTP_CONTAINER_INFO_SIZE..].to_vec())))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn cancel(&mut self, tid: u32, _timeout: Duration) -> Result<(), Error> {
+        let record = self.next_of(RecordKind::Cancel)?;
+        let recorded = Cursor::new(&record.data[..]).read_ptp_u32()?;
+        if recorded != tid {
+            return Err(Error::Malformed(format!("Replay diverged: cancelled {}, recorded {}", tid, recorded)));
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self, _timeout: Duration) -> Result<(), Error> {
+        self.next_of(RecordKind::Reset).map(|_| ())
+    }
+
+    fn status(&mut self, _timeout: Duration) -> Result<(ResponseCode, Vec<u32>), Error> {
+        let record = self.next_of(RecordKind::Status)?;
+        if record.data.len() < 2 {
+            return Err(Error::Malformed(format!("Status record of {} bytes", record.data.len())));
+        }
+        let mut cur = Cursor::new(&record.data[..]);
+        let code = cur.read_ptp_u16()?;
+        let params = (0..(record.data.len() - 2) / 4).map(|_| cur.read_ptp_u32()).collect::<Result<_, _>>()?;
+        Ok((code, params))
+    }
+
+    fn close(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{PtpCamera, StandardCommandCode, StandardResponseCode};
+    use super::super::mock::MockTransport;
+
+    // record a short session against the mock, returning the log
+    fn record_session() -> Vec<u8> {
+        let mut mock = MockTransport::new();
+        mock.expect(StandardCommandCode::OpenSession, &[3, 0, 0]).tid(0);
+        mock.expect(StandardCommandCode::GetStorageIDs, &[])
+            .reply_data(&[1, 0, 0, 0, 0x01, 0x00, 0x01, 0x00]);
+        mock.expect(StandardCommandCode::DeleteObject, &[42])
+            .respond(StandardResponseCode::ObjectWriteProtected, &[]);
+        mock.push_event(0x4002, 0, &[43]);
+
+        let mut camera = PtpCamera::with_transport(RecordingTransport::new(mock, vec![]));
+        camera.open_session(None).unwrap();
+        assert_eq!(camera.get_storageids(None).unwrap(), vec![0x00010001]);
+        assert!(camera.delete_object(42, None).is_err());
+        assert_eq!(camera.poll_event(None).unwrap().unwrap().params, vec![43]);
+
+        let (mock, log) = camera.into_transport().into_inner();
+        mock.verify();
+        log
+    }
+
+    #[test]
+    fn round_trip() {
+        let log = record_session();
+        let records = Record::read_all(&mut &log[..]).unwrap();
+        assert_eq!(records.iter().filter(|r| r.kind == RecordKind::Sent).count(), 3);
+
+        let mut camera = PtpCamera::with_transport(ReplayTransport::new(records));
+        camera.open_session(None).unwrap();
+        assert_eq!(camera.get_storageids(None).unwrap(), vec![0x00010001]);
+        match camera.delete_object(42, None) {
+            Err(Error::Response(StandardResponseCode::ObjectWriteProtected)) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        assert_eq!(camera.poll_event(None).unwrap().unwrap().code, 0x4002);
+        assert_eq!(camera.transport().remaining(), 0);
+    }
+
+    #[test]
+    fn streamed_data_phases() {
+        let mut mock = MockTransport::new();
+        mock.expect(StandardCommandCode::GetObject, &[7]).tid(0).reply_data(&[1, 2, 3]);
+        mock.expect(StandardCommandCode::SendObject, &[]).expect_data(&[4, 5]);
+        mock.expect(StandardCommandCode::SendObject, &[]);
+
+        let mut camera = PtpCamera::with_transport(RecordingTransport::new(mock, vec![]));
+        let mut object = vec![];
+        camera.get_object_to(7, &mut object, None).unwrap();
+        assert_eq!(object, [1, 2, 3]);
+        camera.command_from(StandardCommandCode::SendObject, &[], &mut &[4u8, 5][..], 2, None).unwrap();
+        // a source that ends early cancels the transaction
+        assert!(camera.command_from(StandardCommandCode::SendObject, &[], &mut &[6u8][..], 2, None).is_err());
+
+        let (mock, log) = camera.into_transport().into_inner();
+        assert_eq!(mock.cancelled(), &[2]);
+        let records = Record::read_all(&mut &log[..]).unwrap();
+        assert_eq!(records.last().unwrap().kind, RecordKind::Cancel);
+
+        let mut camera = PtpCamera::with_transport(ReplayTransport::new(records));
+        let mut object = vec![];
+        camera.get_object_to(7, &mut object, None).unwrap();
+        assert_eq!(object, [1, 2, 3]);
+        camera.command_from(StandardCommandCode::SendObject, &[], &mut &[4u8, 5][..], 2, None).unwrap();
+        assert!(camera.command_from(StandardCommandCode::SendObject, &[], &mut &[6u8][..], 2, None).is_err());
+        assert_eq!(camera.transport().remaining(), 0);
+    }
+
+    #[test]
+    fn short_status_record() {
+        let record = Record { kind: RecordKind::Status, time: time::Timespec::new(0, 0), data: vec![1] };
+        match ReplayTransport::new(vec![record]).status(Duration::new(0, 0)) {
+            Err(Error::Malformed(_)) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn divergence() {
+        let log = record_session();
+        let mut camera = PtpCamera::with_transport(ReplayTransport::new(Record::read_all(&mut &log[..]).unwrap()));
+        camera.open_session(None).unwrap();
+
+        match camera.get_objecthandles(0xFFFFFFFF, 0, None, None) {
+            Err(Error::Malformed(ref msg)) if msg.starts_with("Replay diverged") => {}
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn truncated_or_corrupt_log() {
+        let log = record_session();
+
+        match Record::read_all(&mut &log[..log.len() - 1]) {
+            Err(Error::Malformed(ref msg)) if msg == "Unexpected end of message" => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        match Record::read_all(&mut &log[..5]) {
+            Err(Error::Malformed(ref msg)) if msg == "Unexpected end of message" => {}
+            r => panic!("unexpected {:?}", r),
+        }
+
+        // a record claiming 4GB of data in a short log is truncated, not a 4GB allocation
+        let mut huge = log[..13].to_vec();
+        huge.extend_from_slice(&[0xFF; 4]);
+        huge.extend_from_slice(&[0; 8]);
+        match Record::read_all(&mut &huge[..]) {
+            Err(Error::Malformed(ref msg)) if msg == "Unexpected end of message" => {}
+            r => panic!("unexpected {:?}", r),
+        }
+
+        let mut corrupt = log.clone();
+        corrupt[0] = b'?';
+        match Record::read_all(&mut &corrupt[..]) {
+            Err(Error::Malformed(ref msg)) if msg.starts_with("Invalid record kind") => {}
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+}
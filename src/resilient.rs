@@ -0,0 +1,330 @@
+//! A camera that reconnects after the link fails.
+//!
+//! `ResilientCamera` holds a `PtpCamera` along with a way to make a new transport to the same
+//! device. When an operation fails because of the link, a USB error showing the device went
+//! away, its pipe broke or it stopped answering, or an IO error from the connection, including a
+//! timeout, the camera is dropped and the next
+//! operation reconnects and opens a new session first. Other failures, including malformed data
+//! from a responder that is otherwise still talking, are handed to the caller as they are.
+//!
+//! Operations run through `retry` are attempted again after reconnecting, which is only safe for
+//! operations without side effects; the wrapper's own methods are all such read-only operations.
+//! Anything else should go through `once`, which reconnects for the next call but hands the
+//! failure to the caller, since the device may or may not have carried the operation out.
+//!
+//! ```no_run
+//! # fn example() -> Result<(), ptp::Error> {
+//! use ptp::UsbDeviceSelector;
+//! use ptp::resilient::ResilientCamera;
+//!
+//! let context = rusb::Context::new()?;
+//! let mut camera = ResilientCamera::usb(&context, UsbDeviceSelector::Serial("3110D2".to_owned()))?;
+//! let handles = camera.get_objecthandles(0xFFFFFFFF, 0, None, None)?;
+//! camera.once(|c| c.delete_object(handles[0], None))?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::thread;
+use std::time::Duration;
+
+use super::{Error, PtpCamera, PtpTransport, PtpObjectInfo, PtpDeviceInfo, PtpStorageInfo, StandardResponseCode,
+            UsbTransport, UsbDeviceSelector};
+
+type Connector<'a, T> = Box<dyn FnMut() -> Result<T, Error> + 'a>;
+
+/// A `PtpCamera` that reconnects and reopens its session when the link fails
+pub struct ResilientCamera<'a, T: PtpTransport> {
+    connect: Connector<'a, T>,
+    camera: Option<PtpCamera<T>>,
+    retries: usize,
+    retry_delay: Duration,
+    timeout: Option<Duration>,
+    connections: usize,
+}
+
+impl<'a> ResilientCamera<'a, UsbTransport> {
+    /// Open the USB device matching `selector`, reopening it the same way after a failure.
+    /// Select by serial number where possible: a device that was unplugged or power cycled
+    /// comes back at a different address.
+    pub fn usb(context: &rusb::Context, selector: UsbDeviceSelector) -> Result<ResilientCamera<'a, UsbTransport>, Error> {
+        let context = context.clone();
+        ResilientCamera::new(move || UsbTransport::open(&context, &selector))
+    }
+}
+
+impl<'a, T: PtpTransport> ResilientCamera<'a, T> {
+    /// Connect with `connect` and open a session. `connect` is called again to make a new
+    /// transport whenever the link has failed.
+    pub fn new<C: FnMut() -> Result<T, Error> + 'a>(connect: C) -> Result<ResilientCamera<'a, T>, Error> {
+        let mut camera = ResilientCamera {
+            connect: Box::new(connect),
+            camera: None,
+            retries: 3,
+            retry_delay: Duration::from_secs(1),
+            timeout: None,
+            connections: 0,
+        };
+        camera.connected()?;
+        Ok(camera)
+    }
+
+    /// how many times `retry` tries again after a failure of the link, 3 by default
+    pub fn retries(mut self, retries: usize) -> ResilientCamera<'a, T> {
+        self.retries = retries;
+        self
+    }
+
+    /// how long to wait before reconnecting, to give a device that is restarting time to
+    /// reappear. 1s by default.
+    pub fn retry_delay(mut self, delay: Duration) -> ResilientCamera<'a, T> {
+        self.retry_delay = delay;
+        self
+    }
+
+    /// timeout for opening sessions after reconnecting, unlimited by default
+    pub fn timeout(mut self, timeout: Option<Duration>) -> ResilientCamera<'a, T> {
+        self.timeout = timeout;
+        self
+    }
+
+    /// number of times the camera has been reconnected
+    pub fn reconnects(&self) -> usize {
+        self.connections.saturating_sub(1)
+    }
+
+    /// the current camera, connecting first if the link had failed
+    pub fn camera(&mut self) -> Result<&mut PtpCamera<T>, Error> {
+        self.connected()
+    }
+
+    /// Run an operation without side effects, reconnecting and running it again if the link
+    /// fails, up to the configured number of retries.
+    pub fn retry<R, F: FnMut(&mut PtpCamera<T>) -> Result<R, Error>>(&mut self, mut op: F) -> Result<R, Error> {
+        let mut attempt = 0;
+        loop {
+            let result = self.connected().and_then(&mut op);
+            match result {
+                Err(ref e) if lost_link(e) && attempt < self.retries => {
+                    attempt += 1;
+                    warn!("link failed ({}), retrying, attempt {} of {}", e, attempt, self.retries);
+                    self.camera = None;
+                    thread::sleep(self.retry_delay);
+                }
+                result => return self.check(result),
+            }
+        }
+    }
+
+    /// Run an operation that must not be repeated. If the link fails the error is returned,
+    /// and the camera reconnects on the next call.
+    pub fn once<R, F: FnOnce(&mut PtpCamera<T>) -> Result<R, Error>>(&mut self, op: F) -> Result<R, Error> {
+        let result = self.connected().and_then(op);
+        self.check(result)
+    }
+
+    pub fn get_device_info(&mut self, timeout: Option<Duration>) -> Result<PtpDeviceInfo, Error> {
+        self.retry(|c| c.get_device_info(timeout))
+    }
+
+    pub fn get_storageids(&mut self, timeout: Option<Duration>) -> Result<Vec<u32>, Error> {
+        self.retry(|c| c.get_storageids(timeout))
+    }
+
+    pub fn get_storage_info(&mut self, storage_id: u32, timeout: Option<Duration>) -> Result<PtpStorageInfo, Error> {
+        self.retry(|c| c.get_storage_info(storage_id, timeout))
+    }
+
+    pub fn get_objecthandles(&mut self,
+                             storage_id: u32,
+                             handle_id: u32,
+                             filter: Option<u32>,
+                             timeout: Option<Duration>)
+                             -> Result<Vec<u32>, Error> {
+        self.retry(|c| c.get_objecthandles(storage_id, handle_id, filter, timeout))
+    }
+
+    pub fn get_objectinfo(&mut self, handle: u32, timeout: Option<Duration>) -> Result<PtpObjectInfo, Error> {
+        self.retry(|c| c.get_objectinfo(handle, timeout))
+    }
+
+    pub fn get_partialobject(&mut self, handle: u32, offset: u32, max: u32, timeout: Option<Duration>) -> Result<Vec<u8>, Error> {
+        self.retry(|c| c.get_partialobject(handle, offset, max, timeout))
+    }
+
+    /// Close the session and the transport, if connected.
+    pub fn disconnect(&mut self) -> Result<(), Error> {
+        match self.camera.take() {
+            Some(mut camera) => camera.disconnect(self.timeout),
+            None => Ok(()),
+        }
+    }
+
+    fn connected(&mut self) -> Result<&mut PtpCamera<T>, Error> {
+        if self.camera.is_none() {
+            let mut camera = PtpCamera::with_transport((self.connect)()?);
+            match camera.open_session(self.timeout) {
+                // the device kept the session from before the failure
+                Ok(()) | Err(Error::Response(StandardResponseCode::SessionAlreadyOpen)) => {}
+                Err(e) => return Err(e),
+            }
+            if self.connections > 0 {
+                info!("reconnected to camera");
+            }
+            self.connections += 1;
+            self.camera = Some(camera);
+        }
+        Ok(self.camera.as_mut().unwrap())
+    }
+
+    // drop the camera if `result` shows the link failed, so the next call reconnects
+    fn check<R>(&mut self, result: Result<R, Error>) -> Result<R, Error> {
+        if let Err(ref e) = result {
+            if lost_link(e) && self.camera.take().is_some() {
+                debug!("link failed ({}), will reconnect", e);
+            }
+        }
+        result
+    }
+}
+
+// A timeout counts: the responder may still be busy with the request, and what it eventually
+// sends would be taken as the reply to the next one. A new session starts from a clean slate.
+fn lost_link(e: &Error) -> bool {
+    matches!(*e, Error::Usb(rusb::Error::NoDevice) | Error::Usb(rusb::Error::Pipe) | Error::Usb(rusb::Error::Io) |
+                 Error::Usb(rusb::Error::Timeout) | Error::Io(_))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::io;
+    use super::super::StandardCommandCode;
+    use super::super::mock::MockTransport;
+
+    // a transport whose session opens, with `handles` scripted as the reply to GetObjectHandles
+    fn transport(connections: &Cell<usize>, handles: Option<&[u8]>) -> Result<MockTransport, Error> {
+        connections.set(connections.get() + 1);
+        let mut mock = MockTransport::new();
+        mock.expect(StandardCommandCode::OpenSession, &[3, 0, 0]);
+        if let Some(handles) = handles {
+            mock.expect(StandardCommandCode::GetObjectHandles, &[0xFFFFFFFF, 0, 0]).reply_data(handles);
+        }
+        Ok(mock)
+    }
+
+    #[test]
+    fn retries_after_lost_link() {
+        for error in [Error::Usb(rusb::Error::Timeout), Error::Io(io::Error::new(io::ErrorKind::TimedOut, "timed out"))] {
+            let connections = Cell::new(0);
+            let mut camera = ResilientCamera::new(|| transport(&connections, Some(&[1, 0, 0, 0, 7, 0, 0, 0])))
+                .unwrap()
+                .retry_delay(Duration::new(0, 0));
+
+            let mut error = Some(error);
+            let handles = camera.retry(|c| match error.take() {
+                Some(e) => Err(e),
+                None => c.get_objecthandles(0xFFFFFFFF, 0, None, None),
+            });
+            assert_eq!(handles.unwrap(), vec![7]);
+            assert_eq!(camera.reconnects(), 1);
+            assert_eq!(connections.get(), 2);
+        }
+    }
+
+    #[test]
+    fn reconnects_until_the_device_is_back() {
+        let connections = Cell::new(0);
+        let mut camera = ResilientCamera::new(|| {
+            // the device is gone for the first reconnection
+            if connections.get() == 1 {
+                connections.set(2);
+                return Err(Error::Usb(rusb::Error::NoDevice));
+            }
+            transport(&connections, None)
+        }).unwrap().retry_delay(Duration::new(0, 0));
+
+        let mut attempts = 0;
+        let result = camera.retry(|_| {
+            attempts += 1;
+            match attempts {
+                1 => Err(Error::Usb(rusb::Error::Pipe)),
+                _ => Ok(attempts),
+            }
+        });
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(camera.reconnects(), 1);
+        assert_eq!(connections.get(), 3);
+    }
+
+    #[test]
+    fn gives_up_after_retries() {
+        let connections = Cell::new(0);
+        let mut camera = ResilientCamera::new(|| transport(&connections, None))
+            .unwrap()
+            .retries(2)
+            .retry_delay(Duration::new(0, 0));
+
+        let mut attempts = 0;
+        let result: Result<(), Error> = camera.retry(|_| {
+            attempts += 1;
+            Err(Error::Usb(rusb::Error::Io))
+        });
+        assert!(matches!(result, Err(Error::Usb(rusb::Error::Io))));
+        assert_eq!(attempts, 3);
+        assert_eq!(connections.get(), 3);
+
+        // the next call starts on a new connection
+        camera.camera().unwrap();
+        assert_eq!(connections.get(), 4);
+    }
+
+    #[test]
+    fn other_failures_are_returned() {
+        let connections = Cell::new(0);
+        let mut camera = ResilientCamera::new(|| transport(&connections, None))
+            .unwrap()
+            .retry_delay(Duration::new(0, 0));
+
+        let mut attempts = 0;
+        let result: Result<(), Error> = camera.retry(|_| {
+            attempts += 1;
+            Err(Error::Malformed("bad data".to_owned()))
+        });
+        assert!(matches!(result, Err(Error::Malformed(_))));
+        assert_eq!(attempts, 1);
+        camera.camera().unwrap();
+        assert_eq!(camera.reconnects(), 0);
+    }
+
+    #[test]
+    fn once_reconnects_on_the_next_call() {
+        let connections = Cell::new(0);
+        let mut camera = ResilientCamera::new(|| transport(&connections, None))
+            .unwrap()
+            .retry_delay(Duration::new(0, 0));
+
+        let mut attempts = 0;
+        let result: Result<(), Error> = camera.once(|_| {
+            attempts += 1;
+            Err(Error::Usb(rusb::Error::Timeout))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+        assert_eq!(connections.get(), 1);
+
+        camera.camera().unwrap();
+        assert_eq!(camera.reconnects(), 1);
+    }
+
+    #[test]
+    fn session_kept_by_the_device() {
+        let camera = ResilientCamera::new(|| {
+            let mut mock = MockTransport::new();
+            mock.expect(StandardCommandCode::OpenSession, &[3, 0, 0]).respond(StandardResponseCode::SessionAlreadyOpen, &[]);
+            Ok(mock)
+        });
+        assert!(camera.is_ok());
+    }
+}
@@ -0,0 +1,504 @@
+//! Incremental copy of a camera's stores to a local directory.
+//!
+//! `SyncJob` walks the object tree of one or all stores and copies every object that hasn't
+//! been copied before, recreating the association (folder) structure under the destination.
+//! With all stores selected, each store gets a subdirectory named after its storage ID.
+//!
+//! An object counts as already copied if the state file lists it with the same path, size and
+//! CaptureDate, or, for a path the state file doesn't know, if a local file of that name has
+//! the same size and was last modified at the CaptureDate, as copies made by this module and
+//! most other tools are. Cameras reuse file names once their counters wrap, so a local file of
+//! the same name and size from another time is an older shot; it is replaced by the new object.
+//! Objects whose paths collide once made safe for the local filesystem are reported as failed
+//! rather than overwriting each other.
+//!
+//! Downloads go to a `.part` file that is only renamed into place once its size has been checked,
+//! and are resumed on the next run if interrupted. If asked to, objects are then deleted from the
+//! camera, but only once samples from the start, middle and end of the copy have been read back
+//! with GetPartialObject and compared; objects that can't be checked that way are kept.
+//!
+//! The state file holds one line per copied object, `<size>\t<capture date>\t<path>`, and lives
+//! at `.ptpsync` in the destination unless configured otherwise.
+
+use std::cmp::min;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{self, BufReader, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::{Error, PtpCamera, PtpTransport, PtpObjectInfo, ObjectTreeOptions, StandardCommandCode,
+            StandardObjectFormatCode};
+use super::download::Downloader;
+
+// bytes read back from each of the start, middle and end of a copy before deleting the original
+const VERIFY_SAMPLE_SIZE: u32 = 64 * 1024;
+
+/// What a sync run did
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    /// local paths of the objects copied
+    pub copied: Vec<PathBuf>,
+    /// number of objects skipped as already copied
+    pub skipped: usize,
+    /// handles of the objects deleted from the camera
+    pub deleted: Vec<u32>,
+    /// objects that could not be copied, with the reason; the run carries on past them
+    pub failed: Vec<(String, Error)>,
+}
+
+/// Mirror of a camera's stores in a local directory
+#[derive(Debug, Clone)]
+pub struct SyncJob {
+    dest: PathBuf,
+    storage_id: Option<u32>,
+    state_file: Option<PathBuf>,
+    delete: bool,
+    tree_options: ObjectTreeOptions,
+    timeout: Option<Duration>,
+}
+
+impl SyncJob {
+    pub fn new<P: Into<PathBuf>>(dest: P) -> SyncJob {
+        SyncJob {
+            dest: dest.into(),
+            storage_id: None,
+            state_file: None,
+            delete: false,
+            tree_options: ObjectTreeOptions::new(),
+            timeout: None,
+        }
+    }
+
+    /// sync only this store, straight into the destination. all stores by default.
+    pub fn storage(mut self, storage_id: u32) -> SyncJob {
+        self.storage_id = Some(storage_id);
+        self
+    }
+
+    /// where to keep the record of copied objects, instead of `.ptpsync` in the destination
+    pub fn state_file<P: Into<PathBuf>>(mut self, path: P) -> SyncJob {
+        self.state_file = Some(path.into());
+        self
+    }
+
+    /// delete each object from the camera once it has been copied and verified
+    pub fn delete_after_copy(mut self, delete: bool) -> SyncJob {
+        self.delete = delete;
+        self
+    }
+
+    /// how to walk the stores, eg. to copy only some formats
+    pub fn tree_options(mut self, options: ObjectTreeOptions) -> SyncJob {
+        self.tree_options = options;
+        self
+    }
+
+    /// timeout for every transaction, unlimited by default
+    pub fn timeout(mut self, timeout: Option<Duration>) -> SyncJob {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Copy everything new. Failures to copy individual objects are collected in the report;
+    /// errors walking the stores or writing the state file end the run.
+    pub fn run<T: PtpTransport>(&self, camera: &mut PtpCamera<T>) -> Result<SyncReport, Error> {
+        let state_path = self.state_file.clone().unwrap_or_else(|| self.dest.join(".ptpsync"));
+        fs::create_dir_all(&self.dest)?;
+        let mut state = SyncState::load(&state_path)?;
+        let mut report = SyncReport::default();
+
+        let trees = match self.storage_id {
+            Some(id) => vec![(id, camera.object_tree(id, &self.tree_options, self.timeout)?)],
+            None => camera.object_trees(&self.tree_options, self.timeout)?,
+        };
+
+        // the object copied to each local path this run, to catch collisions
+        let mut claimed: HashMap<String, u32> = HashMap::new();
+
+        for (storage_id, roots) in trees {
+            let prefix = match self.storage_id {
+                Some(_) => String::new(),
+                None => format!("{:08x}/", storage_id),
+            };
+
+            for (path, object) in roots.iter().flat_map(|t| t.walk()) {
+                let path = match sanitize(&path) {
+                    Some(path) => prefix.clone() + &path,
+                    None => {
+                        warn!("skipping object {} with unusable path {:?}", object.handle, path);
+                        continue;
+                    }
+                };
+                let local = self.dest.join(&path);
+
+                if object.info.ObjectFormat == StandardObjectFormatCode::Association {
+                    fs::create_dir_all(&local)?;
+                    continue;
+                }
+
+                if let Some(&other) = claimed.get(&path) {
+                    warn!("object {} has the same local path {} as object {}, skipping it", object.handle, path, other);
+                    let e = Error::Malformed(format!("Local path collides with that of object {}", other));
+                    report.failed.push((path, e));
+                    continue;
+                }
+                claimed.insert(path.clone(), object.handle);
+
+                if state.contains(&path, &object.info) {
+                    trace!("{} already copied", path);
+                    report.skipped += 1;
+                    continue;
+                }
+                if !state.knows(&path) && present(&local, &object.info) {
+                    trace!("{} already present", path);
+                    state.record(&state_path, &path, &object.info)?;
+                    report.skipped += 1;
+                    continue;
+                }
+
+                match self.copy(camera, object.handle, &object.info, &local) {
+                    Ok(()) => {
+                        debug!("copied {}", path);
+                        state.record(&state_path, &path, &object.info)?;
+                        report.copied.push(local.clone());
+                    }
+                    Err(e) => {
+                        warn!("failed to copy {}: {}", path, e);
+                        report.failed.push((path, e));
+                        continue;
+                    }
+                }
+
+                if self.delete {
+                    let deleted = self.verify(camera, object.handle, &object.info, &local)
+                        .and_then(|_| camera.delete_object(object.handle, self.timeout));
+                    match deleted {
+                        Ok(()) => report.deleted.push(object.handle),
+                        Err(e) => {
+                            warn!("not deleting {} from the camera: {}", path, e);
+                            report.failed.push((path, e));
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    // download into `<local>.part`, resuming an earlier attempt, then check it and move it into place
+    fn copy<T: PtpTransport>(&self,
+                             camera: &mut PtpCamera<T>,
+                             handle: u32,
+                             info: &PtpObjectInfo,
+                             local: &Path)
+                             -> Result<(), Error> {
+        if let Some(dir) = local.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let part = append_extension(local, "part");
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&part)?;
+        let size = Downloader::new()
+            .state_file(append_extension(local, "part.state"))
+            .download(camera, handle, &mut file, self.timeout)?;
+
+        file.set_len(size)?;
+        file.sync_all()?;
+        if info.ObjectCompressedSize != 0xFFFFFFFF && size != info.ObjectCompressedSize as u64 {
+            return Err(Error::Malformed(format!("Copied {} bytes, object is {} bytes", size, info.ObjectCompressedSize)));
+        }
+
+        // so the copy is recognised as such should the state file be lost
+        if let Some(time) = capture_time(info) {
+            file.set_modified(time)?;
+        }
+        fs::rename(&part, local)?;
+        Ok(())
+    }
+
+    // read back samples of the object and compare them with the copy at `local`
+    fn verify<T: PtpTransport>(&self,
+                               camera: &mut PtpCamera<T>,
+                               handle: u32,
+                               info: &PtpObjectInfo,
+                               local: &Path)
+                               -> Result<(), Error> {
+        let size = info.ObjectCompressedSize;
+        if size == 0 {
+            return Ok(());
+        }
+        // GetPartialObject can't reach past 4GB
+        if size == 0xFFFFFFFF || !camera.supports(StandardCommandCode::GetPartialObject, self.timeout)? {
+            return Err(Error::Unsupported(StandardCommandCode::GetPartialObject));
+        }
+
+        let mut file = File::open(local)?;
+        let len = min(VERIFY_SAMPLE_SIZE, size);
+        let mut offsets = vec![0, (size - len) / 2, size - len];
+        offsets.dedup();
+        for offset in offsets {
+            let original = camera.get_partialobject(handle, offset, len, self.timeout)?;
+            let mut copy = vec![0u8; len as usize];
+            file.seek(SeekFrom::Start(offset as u64))?;
+            file.read_exact(&mut copy)?;
+            if original != copy {
+                return Err(Error::Malformed(format!("Copy differs from the object in the {} bytes at {}", len, offset)));
+            }
+        }
+        Ok(())
+    }
+}
+
+// objects copied on previous runs, by path
+struct SyncState {
+    copied: HashMap<String, (u64, String)>,
+}
+
+impl SyncState {
+    fn load(path: &Path) -> Result<SyncState, Error> {
+        let mut copied = HashMap::new();
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(SyncState { copied }),
+            Err(e) => return Err(e.into()),
+        };
+
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            let mut fields = line.splitn(3, '\t');
+            match (fields.next().and_then(|s| s.parse().ok()), fields.next(), fields.next()) {
+                (Some(size), Some(date), Some(path)) => {
+                    copied.insert(path.to_owned(), (size, date.to_owned()));
+                }
+                _ => warn!("ignoring malformed sync state line {:?}", line),
+            }
+        }
+        Ok(SyncState { copied })
+    }
+
+    fn contains(&self, path: &str, info: &PtpObjectInfo) -> bool {
+        self.copied.get(path).is_some_and(|&(size, ref date)| {
+            size == info.ObjectCompressedSize as u64 && *date == info.CaptureDate
+        })
+    }
+
+    // whether the state has any record of `path`
+    fn knows(&self, path: &str) -> bool {
+        self.copied.contains_key(path)
+    }
+
+    fn record(&mut self, state_path: &Path, path: &str, info: &PtpObjectInfo) -> Result<(), Error> {
+        let mut file = OpenOptions::new().create(true).append(true).open(state_path)?;
+        writeln!(file, "{}\t{}\t{}", info.ObjectCompressedSize, info.CaptureDate, path)?;
+        self.copied.insert(path.to_owned(), (info.ObjectCompressedSize as u64, info.CaptureDate.clone()));
+        Ok(())
+    }
+}
+
+// whether `local` looks like a copy of the object: same size, and last modified at the capture
+// date, to within the 2 second resolution of FAT filesystems
+fn present(local: &Path, info: &PtpObjectInfo) -> bool {
+    let metadata = match fs::metadata(local) {
+        Ok(metadata) => metadata,
+        Err(_) => return false,
+    };
+    let modified = metadata.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok());
+    let captured = capture_time(info).and_then(|t| t.duration_since(UNIX_EPOCH).ok());
+    match (modified, captured) {
+        (Some(modified), Some(captured)) => {
+            metadata.len() == info.ObjectCompressedSize as u64 &&
+                modified.as_secs().abs_diff(captured.as_secs()) <= 2
+        }
+        _ => false,
+    }
+}
+
+// the object's CaptureDate, "YYYYMMDDThhmmss" in the camera's local time
+fn capture_time(info: &PtpObjectInfo) -> Option<SystemTime> {
+    let tm = time::strptime(info.CaptureDate.get(..15)?, "%Y%m%dT%H%M%S").ok()?;
+    // to_timespec takes a Tm with a UTC offset of 0 as UTC, and any other as local time, with
+    // daylight saving time worked out for the date
+    let spec = time::Tm { tm_utcoff: 1, tm_isdst: -1, ..tm }.to_timespec();
+    if spec.sec < 0 {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::from_secs(spec.sec as u64))
+}
+
+// a camera-supplied path made safe to join onto the destination, or None if nothing is left.
+// drops empty, "." and ".." components, and backslashes, which Windows takes as separators.
+fn sanitize(path: &str) -> Option<String> {
+    let components: Vec<String> = path.split('/')
+        .map(|c| c.replace('\\', "_"))
+        .filter(|c| !c.is_empty() && c != "." && c != "..")
+        .collect();
+    if components.is_empty() { None } else { Some(components.join("/")) }
+}
+
+fn append_extension(path: &Path, ext: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(ext);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::StandardResponseCode;
+    use super::super::mock::MockTransport;
+    use std::env;
+
+    const CAPTURED: &str = "20200101T120000";
+
+    fn dest(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("ptp-sync-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn info(name: &str, format: u16, size: u32) -> PtpObjectInfo {
+        PtpObjectInfo {
+            Filename: name.into(),
+            ObjectFormat: format,
+            ObjectCompressedSize: size,
+            CaptureDate: CAPTURED.into(),
+            ..Default::default()
+        }
+    }
+
+    fn handles(handles: &[u32]) -> Vec<u8> {
+        let mut data = (handles.len() as u32).to_le_bytes().to_vec();
+        for h in handles {
+            data.extend_from_slice(&h.to_le_bytes());
+        }
+        data
+    }
+
+    // store 1 holding DCIM/ (handle 1) with the given files in it, numbered from 2
+    fn expect_tree(mock: &mut MockTransport, files: &[(&str, &[u8])]) {
+        mock.expect(StandardCommandCode::GetObjectHandles, &[1, 0, 0xFFFFFFFF]).reply_data(&handles(&[1]));
+        mock.expect(StandardCommandCode::GetObjectInfo, &[1])
+            .reply_data(&info("DCIM", StandardObjectFormatCode::Association, 0).encode());
+        let numbers: Vec<u32> = (2..2 + files.len() as u32).collect();
+        mock.expect(StandardCommandCode::GetObjectHandles, &[1, 0, 1]).reply_data(&handles(&numbers));
+        for (&handle, &(name, data)) in numbers.iter().zip(files) {
+            mock.expect(StandardCommandCode::GetObjectInfo, &[handle])
+                .reply_data(&info(name, StandardObjectFormatCode::EXIF_JPEG, data.len() as u32).encode());
+        }
+    }
+
+    // the download of object `handle`, with GetDeviceInfo first if `first`
+    fn expect_copy(mock: &mut MockTransport, handle: u32, name: &str, data: &[u8], first: bool) {
+        mock.expect(StandardCommandCode::GetObjectInfo, &[handle])
+            .reply_data(&info(name, StandardObjectFormatCode::EXIF_JPEG, data.len() as u32).encode());
+        if first {
+            mock.expect_device_info(&[StandardCommandCode::GetPartialObject]);
+        }
+        mock.expect(StandardCommandCode::GetPartialObject, &[handle, 0, data.len() as u32]).reply_data(data);
+    }
+
+    #[test]
+    fn skips_objects_in_state() {
+        let dir = dest("state");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(".ptpsync"), format!("3\t{}\tDCIM/A.JPG\n", CAPTURED)).unwrap();
+        let mut mock = MockTransport::new();
+        expect_tree(&mut mock, &[("A.JPG", b"abc")]);
+        let mut camera = PtpCamera::with_transport(mock);
+
+        let report = SyncJob::new(&dir).storage(1).run(&mut camera).unwrap();
+        assert_eq!((report.copied.len(), report.skipped), (0, 1));
+        camera.transport().verify();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn skips_files_already_present() {
+        let dir = dest("present");
+        fs::create_dir_all(dir.join("DCIM")).unwrap();
+        // copied by something else, with the capture date kept
+        let file = File::create(dir.join("DCIM/A.JPG")).unwrap();
+        file.set_len(3).unwrap();
+        file.set_modified(capture_time(&info("A.JPG", 0, 3)).unwrap()).unwrap();
+        // a different shot of the same name and size
+        fs::write(dir.join("DCIM/B.JPG"), b"old").unwrap();
+
+        let mut mock = MockTransport::new();
+        expect_tree(&mut mock, &[("A.JPG", b"abc"), ("B.JPG", b"new")]);
+        expect_copy(&mut mock, 3, "B.JPG", b"new", true);
+        let mut camera = PtpCamera::with_transport(mock);
+
+        let report = SyncJob::new(&dir).storage(1).run(&mut camera).unwrap();
+        assert_eq!(report.copied, vec![dir.join("DCIM/B.JPG")]);
+        assert_eq!(report.skipped, 1);
+        assert_eq!(fs::read(dir.join("DCIM/B.JPG")).unwrap(), b"new");
+        // both are in the state now
+        assert_eq!(fs::read_to_string(dir.join(".ptpsync")).unwrap().lines().count(), 2);
+        // and the copy carries the capture date
+        assert!(present(&dir.join("DCIM/B.JPG"), &info("B.JPG", 0, 3)));
+        camera.transport().verify();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn deletes_after_verified_copy() {
+        let dir = dest("delete");
+        let mut mock = MockTransport::new();
+        expect_tree(&mut mock, &[("A.JPG", b"abc")]);
+        expect_copy(&mut mock, 2, "A.JPG", b"abc", true);
+        mock.expect(StandardCommandCode::GetPartialObject, &[2, 0, 3]).reply_data(b"abc");
+        mock.expect(StandardCommandCode::DeleteObject, &[2]);
+        let mut camera = PtpCamera::with_transport(mock);
+
+        let report = SyncJob::new(&dir).storage(1).delete_after_copy(true).run(&mut camera).unwrap();
+        assert_eq!(report.deleted, vec![2]);
+        assert_eq!(fs::read(dir.join("DCIM/A.JPG")).unwrap(), b"abc");
+        camera.transport().verify();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_objects_not_copied_or_verified() {
+        let dir = dest("keep");
+        let mut mock = MockTransport::new();
+        expect_tree(&mut mock, &[("A.JPG", b"abc"), ("B.JPG", b"def")]);
+        // the copy fails outright
+        mock.expect(StandardCommandCode::GetObjectInfo, &[2])
+            .reply_data(&info("A.JPG", StandardObjectFormatCode::EXIF_JPEG, 3).encode());
+        mock.expect_device_info(&[StandardCommandCode::GetPartialObject]);
+        mock.expect(StandardCommandCode::GetPartialObject, &[2, 0, 3])
+            .respond(StandardResponseCode::IncompleteTransfer, &[]);
+        // the copy has the right size, but not the object's contents
+        expect_copy(&mut mock, 3, "B.JPG", b"\0\0\0", false);
+        mock.expect(StandardCommandCode::GetPartialObject, &[3, 0, 3]).reply_data(b"def");
+        let mut camera = PtpCamera::with_transport(mock);
+
+        let report = SyncJob::new(&dir).storage(1).delete_after_copy(true).run(&mut camera).unwrap();
+        assert!(report.deleted.is_empty());
+        assert_eq!(report.failed.len(), 2);
+        assert!(!dir.join("DCIM/A.JPG").exists());
+        camera.transport().verify();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn colliding_paths() {
+        let dir = dest("collide");
+        let mut mock = MockTransport::new();
+        expect_tree(&mut mock, &[("A_B.JPG", b"abc"), ("A\\B.JPG", b"def")]);
+        expect_copy(&mut mock, 2, "A_B.JPG", b"abc", true);
+        let mut camera = PtpCamera::with_transport(mock);
+
+        let report = SyncJob::new(&dir).storage(1).run(&mut camera).unwrap();
+        assert_eq!(report.copied.len(), 1);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, "DCIM/A_B.JPG");
+        assert_eq!(fs::read(dir.join("DCIM/A_B.JPG")).unwrap(), b"abc");
+        camera.transport().verify();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,438 @@
+//! Thumbnails, with an optional disk cache.
+//!
+//! `ThumbnailFetcher` gets an object's thumbnail with GetThumb when the object info says it has
+//! one. Many cameras give RAW files no thumbnail of their own, but RAW formats embed a JPEG
+//! preview near the start of the file; for those the fetcher reads the start of the file with
+//! GetPartialObject and extracts the first complete JPEG it finds.
+//!
+//! With a cache directory configured, thumbnails are kept on disk keyed by store, filename, size
+//! and capture date rather than by handle, so they stay valid across sessions even though
+//! handles may not:
+//!
+//! ```no_run
+//! # fn example<T: ptp::PtpTransport>(camera: &mut ptp::PtpCamera<T>, handles: &[u32]) -> Result<(), ptp::Error> {
+//! use ptp::thumb::ThumbnailFetcher;
+//!
+//! let fetcher = ThumbnailFetcher::new().cache_dir("/var/cache/gallery");
+//! for &handle in handles {
+//!     let thumb = fetcher.fetch(camera, handle, None)?;
+//!     println!("{}: {} bytes", handle, thumb.data.len());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::cmp::min;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use super::{Error, PtpCamera, PtpTransport, PtpObjectInfo, ObjectFormatCode, StandardCommandCode,
+            StandardObjectFormatCode, StandardResponseCode};
+
+// extensions of RAW formats known to embed a JPEG preview
+const RAW_EXTENSIONS: &[&str] = &["3fr", "arw", "cr2", "cr3", "crw", "dcr", "dng", "erf", "iiq", "k25", "kdc", "mef",
+                                  "mos", "mrw", "nef", "nrw", "orf", "pef", "raf", "rw2", "rwl", "sr2", "srf", "srw", "x3f"];
+
+// numbers the temporary files written by this process while caching
+static CACHE_WRITES: AtomicUsize = AtomicUsize::new(0);
+
+/// An object's thumbnail
+#[derive(Debug, Clone, PartialEq)]
+pub struct Thumbnail {
+    /// format of `data`, an ObjectFormatCode
+    pub format: ObjectFormatCode,
+    pub data: Vec<u8>,
+}
+
+/// Fetches thumbnails, from GetThumb or from the preview embedded in RAW files
+#[derive(Debug, Clone)]
+pub struct ThumbnailFetcher {
+    cache_dir: Option<PathBuf>,
+    scan_limit: u32,
+    chunk_size: u32,
+}
+
+impl Default for ThumbnailFetcher {
+    fn default() -> ThumbnailFetcher {
+        ThumbnailFetcher::new()
+    }
+}
+
+impl ThumbnailFetcher {
+    pub fn new() -> ThumbnailFetcher {
+        ThumbnailFetcher {
+            cache_dir: None,
+            scan_limit: 4 * 1024 * 1024,
+            chunk_size: 256 * 1024,
+        }
+    }
+
+    /// keep thumbnails in `dir`, which is created if need be. no caching by default.
+    pub fn cache_dir<P: Into<PathBuf>>(mut self, dir: P) -> ThumbnailFetcher {
+        self.cache_dir = Some(dir.into());
+        self
+    }
+
+    /// how much of a RAW file to read looking for a preview, 4MB by default
+    pub fn scan_limit(mut self, bytes: u32) -> ThumbnailFetcher {
+        self.scan_limit = bytes;
+        self
+    }
+
+    /// bytes to read from a RAW file per GetPartialObject, 256kB by default
+    pub fn chunk_size(mut self, bytes: u32) -> ThumbnailFetcher {
+        self.chunk_size = bytes.max(1);
+        self
+    }
+
+    /// The thumbnail of an object, from the cache if it is there. Fails with
+    /// `Error::Response(NoThumbnailPresent)` if the object has none and isn't a RAW file with a
+    /// preview.
+    pub fn fetch<T: PtpTransport>(&self, camera: &mut PtpCamera<T>, handle: u32, timeout: Option<Duration>) -> Result<Thumbnail, Error> {
+        let info = camera.get_objectinfo(handle, timeout)?;
+
+        let cache_path = self.cache_dir.as_ref().map(|dir| dir.join(cache_name(&info)));
+        if let Some(ref path) = cache_path {
+            if let Some(thumb) = load_cached(path, &info) {
+                trace!("thumbnail of {} from {}", info.Filename, path.display());
+                return Ok(thumb);
+            }
+        }
+
+        let thumb = self.fetch_uncached(camera, handle, &info, timeout)?;
+        if let Some(ref path) = cache_path {
+            // a cache that can't be written only costs a refetch next time
+            if let Err(e) = store_cached(path, &info, &thumb) {
+                warn!("can't cache thumbnail at {}: {}", path.display(), e);
+            }
+        }
+        Ok(thumb)
+    }
+
+    fn fetch_uncached<T: PtpTransport>(&self,
+                                       camera: &mut PtpCamera<T>,
+                                       handle: u32,
+                                       info: &PtpObjectInfo,
+                                       timeout: Option<Duration>)
+                                       -> Result<Thumbnail, Error> {
+        let has_thumb = info.ThumbFormat != 0 && info.ThumbFormat != StandardObjectFormatCode::Undefined &&
+            info.ThumbCompressedSize != 0;
+        if has_thumb && camera.supports(StandardCommandCode::GetThumb, timeout)? {
+            match camera.get_thumb(handle, timeout) {
+                Ok(ref data) if data.is_empty() => {}
+                Ok(data) => {
+                    if data.len() != info.ThumbCompressedSize as usize {
+                        debug!("thumbnail of {} is {} bytes, object info says {}",
+                               info.Filename, data.len(), info.ThumbCompressedSize);
+                    }
+                    return Ok(Thumbnail { format: info.ThumbFormat, data });
+                }
+                // the object info may claim a thumbnail the camera can't produce
+                Err(Error::Response(code)) => debug!("GetThumb of {} failed: 0x{:04x}", info.Filename, code),
+                Err(e) => return Err(e),
+            }
+        }
+
+        if is_raw(info) && camera.supports(StandardCommandCode::GetPartialObject, timeout)? {
+            if let Some(data) = self.embedded_preview(camera, handle, info, timeout)? {
+                return Ok(Thumbnail { format: StandardObjectFormatCode::EXIF_JPEG, data });
+            }
+            debug!("no embedded preview found in the first {} bytes of {}", self.scan_limit, info.Filename);
+        }
+
+        Err(Error::Response(StandardResponseCode::NoThumbnailPresent))
+    }
+
+    // read the start of the file until it holds a complete JPEG, and return that
+    fn embedded_preview<T: PtpTransport>(&self,
+                                         camera: &mut PtpCamera<T>,
+                                         handle: u32,
+                                         info: &PtpObjectInfo,
+                                         timeout: Option<Duration>)
+                                         -> Result<Option<Vec<u8>>, Error> {
+        let limit = min(self.scan_limit, info.ObjectCompressedSize);
+        let mut buf = Vec::new();
+        while (buf.len() as u32) < limit {
+            let max = min(self.chunk_size, limit - buf.len() as u32);
+            let chunk = camera.get_partialobject(handle, buf.len() as u32, max, timeout)?;
+            buf.extend_from_slice(&chunk);
+            let last = chunk.is_empty() || buf.len() as u32 >= limit;
+
+            if let Some((start, end)) = find_jpeg(&buf, last) {
+                buf.truncate(end);
+                buf.drain(..start);
+                return Ok(Some(buf));
+            }
+            if last {
+                break;
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn is_raw(info: &PtpObjectInfo) -> bool {
+    info.ObjectFormat == StandardObjectFormatCode::TIFF_EP ||
+        Path::new(&info.Filename).extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| RAW_EXTENSIONS.contains(&&ext.to_ascii_lowercase()[..]))
+}
+
+// the first complete JPEG in `buf`, as a start and end offset, or None if there is none yet.
+// the segments are followed rather than searching for the first end of image marker, which may
+// belong to a smaller thumbnail nested in the preview's EXIF data. once no more data is coming,
+// a JPEG that runs past the end is skipped in favour of any complete one after its start.
+fn find_jpeg(buf: &[u8], last: bool) -> Option<(usize, usize)> {
+    let mut from = 0;
+    while let Some(pos) = buf[from..].windows(3).position(|w| w == [0xFF, 0xD8, 0xFF]) {
+        let start = from + pos;
+        match jpeg_end(buf, start) {
+            JpegEnd::Complete(end) => return Some((start, end)),
+            JpegEnd::Truncated if !last => return None,
+            JpegEnd::Truncated | JpegEnd::Invalid => from = start + 1,
+        }
+    }
+    None
+}
+
+enum JpegEnd {
+    Complete(usize),
+    /// continues past the end of the buffer
+    Truncated,
+    /// the start of image marker was a coincidence
+    Invalid,
+}
+
+fn jpeg_end(buf: &[u8], start: usize) -> JpegEnd {
+    let segment_len = |pos: usize| ((buf[pos + 2] as usize) << 8) | buf[pos + 3] as usize;
+
+    let mut pos = start + 2;
+    // marker segments, each with a big-endian length, up to the start of scan
+    loop {
+        if pos + 4 > buf.len() {
+            return JpegEnd::Truncated;
+        }
+        // only the markers from 0xC0 up, other than restarts and start and end of image, have
+        // a segment; anything else means this was never a JPEG
+        let marker = buf[pos + 1];
+        if buf[pos] != 0xFF || marker < 0xC0 || (0xD0..=0xD9).contains(&marker) || marker == 0xFF ||
+            segment_len(pos) < 2 {
+            return JpegEnd::Invalid;
+        }
+        pos += 2 + segment_len(pos);
+        if marker == 0xDA {
+            break;
+        }
+    }
+    // entropy-coded data, where a 0xFF is only followed by 0x00 or a restart marker until the
+    // end of image. progressive JPEGs have further segments between scans.
+    while pos + 1 < buf.len() {
+        if buf[pos] == 0xFF {
+            match buf[pos + 1] {
+                0xD9 => return JpegEnd::Complete(pos + 2),
+                0x00 | 0xD0..=0xD7 | 0xFF => {}
+                _ => {
+                    if pos + 4 > buf.len() {
+                        return JpegEnd::Truncated;
+                    }
+                    pos += 2 + segment_len(pos);
+                    continue;
+                }
+            }
+        }
+        pos += 1;
+    }
+    JpegEnd::Truncated
+}
+
+// the first line of a cache file, identifying the object it belongs to
+fn cache_key(info: &PtpObjectInfo) -> String {
+    format!("{}\t{}\t{}\t{}", info.StorageID, info.ObjectCompressedSize, info.CaptureDate, info.Filename)
+}
+
+fn cache_name(info: &PtpObjectInfo) -> String {
+    // 64-bit FNV-1a, which unlike the std hashers is stable between releases
+    let hash = cache_key(info).bytes().fold(0xcbf29ce484222325u64, |h, b| (h ^ b as u64).wrapping_mul(0x100000001b3));
+    format!("{:08x}-{:016x}.thumb", info.StorageID, hash)
+}
+
+// a cache file holds the key line, then the format code line, then the thumbnail
+fn load_cached(path: &Path, info: &PtpObjectInfo) -> Option<Thumbnail> {
+    let contents = fs::read(path).ok()?;
+    let mut lines = contents.splitn(3, |&b| b == b'\n');
+    let key = lines.next()?;
+    let format = lines.next()?;
+    let data = lines.next()?;
+    if key != cache_key(info).as_bytes() {
+        return None;
+    }
+    let format = ObjectFormatCode::from_str_radix(::std::str::from_utf8(format).ok()?, 16).ok()?;
+    Some(Thumbnail { format, data: data.to_vec() })
+}
+
+fn store_cached(path: &Path, info: &PtpObjectInfo, thumb: &Thumbnail) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut contents = format!("{}\n{:04x}\n", cache_key(info), thumb.format).into_bytes();
+    contents.extend_from_slice(&thumb.data);
+
+    // a temporary file of its own, so fetchers sharing the cache, in this process or another,
+    // don't write over each other's
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".{}-{}.tmp", process::id(), CACHE_WRITES.fetch_add(1, Ordering::Relaxed)));
+    let tmp = PathBuf::from(tmp);
+    let result = fs::write(&tmp, contents).and_then(|_| fs::rename(&tmp, path));
+    if result.is_err() {
+        fs::remove_file(&tmp).ok();
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use super::super::mock::MockTransport;
+
+    // a baseline JPEG whose APP1 segment holds `app1`, with a scan containing stuffed bytes and
+    // a restart marker
+    fn jpeg(app1: &[u8]) -> Vec<u8> {
+        let mut data = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        data.extend_from_slice(&(app1.len() as u16 + 2).to_be_bytes());
+        data.extend_from_slice(app1);
+        data.extend_from_slice(&[0xFF, 0xDA, 0x00, 0x03, 0x01, 0x12, 0xFF, 0x00, 0x34, 0xFF, 0xD0, 0x56, 0xFF, 0xD9]);
+        data
+    }
+
+    #[test]
+    fn nested_thumbnail() {
+        // the EXIF data of a preview holds a thumbnail, whose end of image comes first
+        let thumb = jpeg(b"Exif");
+        let preview = jpeg(&thumb);
+        let mut buf = b"II*\0header".to_vec();
+        buf.extend_from_slice(&preview);
+        buf.extend_from_slice(&[0; 16]);
+
+        assert_eq!(find_jpeg(&buf, false), Some((10, 10 + preview.len())));
+    }
+
+    #[test]
+    fn truncated_preview() {
+        let preview = jpeg(b"Exif");
+        let truncated = &preview[..preview.len() - 4];
+        assert_eq!(find_jpeg(truncated, false), None);
+        assert_eq!(find_jpeg(truncated, true), None);
+
+        // with no more data coming, a complete JPEG inside the truncated one is taken
+        let mut buf = vec![0xFF, 0xD8, 0xFF, 0xE1, 0x10, 0x00];
+        let inner_start = buf.len();
+        buf.extend_from_slice(&jpeg(b""));
+        buf.extend_from_slice(&[0x12, 0x34]);
+        assert_eq!(find_jpeg(&buf, false), None);
+        assert_eq!(find_jpeg(&buf, true), Some((inner_start, buf.len() - 2)));
+    }
+
+    #[test]
+    fn false_start_of_image() {
+        // FF D8 FF followed by something that isn't a marker segment
+        let mut buf = vec![0x00, 0xFF, 0xD8, 0xFF, 0x12, 0x00, 0x00, 0x00];
+        let preview = jpeg(b"Exif");
+        buf.extend_from_slice(&preview);
+        assert!(matches!(jpeg_end(&buf, 1), JpegEnd::Invalid));
+        assert_eq!(find_jpeg(&buf, false), Some((8, 8 + preview.len())));
+
+        // a segment too short to hold its own length
+        let buf = [0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x01, 0xFF, 0xD9];
+        assert!(matches!(jpeg_end(&buf, 0), JpegEnd::Invalid));
+        assert_eq!(find_jpeg(&buf, true), None);
+    }
+
+    #[test]
+    fn progressive_scans() {
+        let mut data = vec![0xFF, 0xD8];
+        // first scan
+        data.extend_from_slice(&[0xFF, 0xDA, 0x00, 0x03, 0x01, 0x11, 0x22]);
+        // a Huffman table between scans, holding what would otherwise be an end of image
+        data.extend_from_slice(&[0xFF, 0xC4, 0x00, 0x05, 0xFF, 0xD9, 0x00]);
+        // second scan and the real end
+        data.extend_from_slice(&[0xFF, 0xDA, 0x00, 0x03, 0x02, 0x33, 0xFF, 0x00, 0xFF, 0xD9]);
+
+        match jpeg_end(&data, 0) {
+            JpegEnd::Complete(end) => assert_eq!(end, data.len()),
+            _ => panic!("not complete"),
+        }
+        // cut inside the table's length
+        assert!(matches!(jpeg_end(&data[..11], 0), JpegEnd::Truncated));
+    }
+
+    fn cache_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("ptp-thumb-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn cache_files() {
+        let dir = cache_dir("cache");
+        let info = PtpObjectInfo {
+            StorageID: 0x00010001,
+            ObjectCompressedSize: 1000,
+            Filename: "DSC00042.ARW".to_owned(),
+            CaptureDate: "20240301T120000".to_owned(),
+            ..Default::default()
+        };
+        let path = dir.join(cache_name(&info));
+        let thumb = Thumbnail { format: StandardObjectFormatCode::EXIF_JPEG, data: b"\xFF\xD8\n\nthumb".to_vec() };
+
+        store_cached(&path, &info, &thumb).unwrap();
+        assert_eq!(load_cached(&path, &info), Some(thumb.clone()));
+        // no temporary file left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        // another object with the same name
+        let other = PtpObjectInfo { ObjectCompressedSize: 1001, ..info.clone() };
+        assert_eq!(load_cached(&path, &other), None);
+
+        // corrupt files
+        for contents in [&b""[..], b"no newline", b"key only\n", &format!("{}\nzz\ndata", cache_key(&info)).into_bytes()] {
+            fs::write(&path, contents).unwrap();
+            assert_eq!(load_cached(&path, &info), None);
+        }
+        assert_eq!(load_cached(&dir.join("missing.thumb"), &info), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn preview_when_get_thumb_fails() {
+        let preview = jpeg(b"Exif");
+        let mut raw = b"II*\0".to_vec();
+        raw.extend_from_slice(&preview);
+        raw.extend_from_slice(&[0; 64]);
+        let info = PtpObjectInfo {
+            ObjectFormat: StandardObjectFormatCode::Undefined,
+            ObjectCompressedSize: raw.len() as u32,
+            ThumbFormat: StandardObjectFormatCode::EXIF_JPEG,
+            ThumbCompressedSize: 100,
+            Filename: "IMG_0001.CR2".to_owned(),
+            ..Default::default()
+        };
+
+        let mut mock = MockTransport::new();
+        mock.expect(StandardCommandCode::GetObjectInfo, &[7]).reply_data(&info.encode());
+        mock.expect_device_info(&[StandardCommandCode::GetThumb, StandardCommandCode::GetPartialObject]);
+        // the object info claims a thumbnail the camera can't produce
+        mock.expect(StandardCommandCode::GetThumb, &[7]).respond(StandardResponseCode::NoThumbnailPresent, &[]);
+        mock.expect(StandardCommandCode::GetPartialObject, &[7, 0, 16]).reply_data(&raw[..16]);
+        mock.expect(StandardCommandCode::GetPartialObject, &[7, 16, 16]).reply_data(&raw[16..32]);
+        let mut camera = PtpCamera::with_transport(mock);
+
+        let thumb = ThumbnailFetcher::new().chunk_size(16).fetch(&mut camera, 7, None).unwrap();
+        assert_eq!(thumb, Thumbnail { format: StandardObjectFormatCode::EXIF_JPEG, data: preview });
+        camera.transport().verify();
+    }
+}
@@ -0,0 +1,428 @@
+//! Progress reporting and cancellation for long data phases.
+//!
+//! A `TransferMonitor` carries an optional progress observer and an optional `CancelHandle`.
+//! The `*_monitored` methods on `PtpCamera` report progress after every chunk that moves
+//! through the transport, and check the handle between chunks. The handle is `Send`, so a UI
+//! thread can abort a transfer running on another:
+//!
+//! ```no_run
+//! # fn example<T: ptp::PtpTransport>(camera: &mut ptp::PtpCamera<T>) -> Result<(), ptp::Error> {
+//! use ptp::transfer::{CancelHandle, TransferMonitor};
+//!
+//! let cancel = CancelHandle::new();
+//! // hand a clone of `cancel` to whoever may abort the download
+//! let mut monitor = TransferMonitor::new()
+//!     .on_progress(|p| println!("{} bytes, {:.0} bytes/s", p.done, p.bytes_per_second()))
+//!     .cancel_handle(cancel.clone());
+//!
+//! let mut file = std::fs::File::create("clip.mp4")?;
+//! camera.get_object_monitored(0x1234, &mut file, &mut monitor, None)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! A cancelled transfer returns `Error::Cancelled` once the responder has been sent the Cancel
+//! request and the rest of the data phase has been discarded, so the session remains usable.
+
+use std::cmp::min;
+use std::io::prelude::*;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{Error, PtpCamera, PtpTransport, PtpContainerType, PtpObjectInfo, PtpResponse, DataSink, CommandCode,
+            StandardCommandCode, StandardResponseCode};
+
+// how long the responder may stay silent before a cancelled data phase counts as drained
+const DRAIN_TIMEOUT_MS: u64 = 200;
+
+// how often, and how many times, to ask a responder that is still busy after a cancel
+const BUSY_POLL_INTERVAL_MS: u64 = 50;
+const BUSY_POLL_ATTEMPTS: usize = 40;
+
+/// How far a data phase has got
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    /// bytes transferred so far
+    pub done: u64,
+    /// size of the data phase, from the container length. `None` if the responder didn't
+    /// say, or before the data phase has started. A data phase split over several containers
+    /// grows as each of them starts.
+    pub total: Option<u64>,
+    /// time since the transaction started
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    /// average throughput since the transaction started
+    pub fn bytes_per_second(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 { self.done as f64 / secs } else { 0.0 }
+    }
+
+    /// fraction of the data phase done, between 0 and 1
+    pub fn fraction(&self) -> Option<f64> {
+        match self.total {
+            Some(0) => Some(1.0),
+            Some(total) => Some(self.done as f64 / total as f64),
+            None => None,
+        }
+    }
+}
+
+/// Shared flag to abort a monitored transfer, possibly from another thread
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    pub fn new() -> CancelHandle {
+        CancelHandle::default()
+    }
+
+    /// Request cancellation. Takes effect at the next chunk boundary of any transfer
+    /// monitored with this handle, or a clone of it, and stays in effect.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+type Observer<'a> = Box<dyn FnMut(&Progress) + 'a>;
+
+/// Progress observer and cancellation handle for a transfer
+#[derive(Default)]
+pub struct TransferMonitor<'a> {
+    observer: Option<Observer<'a>>,
+    cancel: Option<CancelHandle>,
+}
+
+impl<'a> TransferMonitor<'a> {
+    pub fn new() -> TransferMonitor<'a> {
+        TransferMonitor::default()
+    }
+
+    /// call `observer` each time more data has been transferred
+    pub fn on_progress<F: FnMut(&Progress) + 'a>(mut self, observer: F) -> TransferMonitor<'a> {
+        self.observer = Some(Box::new(observer));
+        self
+    }
+
+    /// abort the transfer once `handle` is cancelled
+    pub fn cancel_handle(mut self, handle: CancelHandle) -> TransferMonitor<'a> {
+        self.cancel = Some(handle);
+        self
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(|c| c.is_cancelled())
+    }
+}
+
+// per-transaction state shared by the sink and source wrappers
+struct Tracker<'m, 'a> {
+    monitor: &'m mut TransferMonitor<'a>,
+    start: Instant,
+    done: u64,
+    total: Option<u64>,
+    // set once the wrapper stopped the transfer because of the cancel handle
+    tripped: bool,
+}
+
+impl<'m, 'a> Tracker<'m, 'a> {
+    fn new(monitor: &'m mut TransferMonitor<'a>, total: Option<u64>) -> Tracker<'m, 'a> {
+        Tracker { monitor, start: Instant::now(), done: 0, total, tripped: false }
+    }
+
+    fn check(&mut self) -> bool {
+        if self.monitor.is_cancelled() {
+            self.tripped = true;
+        }
+        !self.tripped
+    }
+
+    fn advance(&mut self, n: usize) {
+        self.done += n as u64;
+        let progress = Progress { done: self.done, total: self.total, elapsed: self.start.elapsed() };
+        if let Some(ref mut observer) = self.monitor.observer {
+            observer(&progress);
+        }
+    }
+}
+
+struct MonitoredSink<'s, 'm, 'a> {
+    inner: &'s mut dyn DataSink,
+    tracker: Tracker<'m, 'a>,
+    // whether a data container has started
+    begun: bool,
+}
+
+impl<'s, 'm, 'a> DataSink for MonitoredSink<'s, 'm, 'a> {
+    fn begin(&mut self, len: u64) -> Result<(), Error> {
+        // PTP/IP responders that don't know the size up front send all ones, and a size
+        // unknown for one part of the data phase is unknown for the whole
+        self.tracker.total = match self.tracker.total {
+            _ if len == u64::MAX => None,
+            Some(total) => Some(total + len),
+            None if !self.begun => Some(len),
+            None => None,
+        };
+        self.begun = true;
+        self.inner.begin(len)?;
+        self.tracker.advance(0);
+        Ok(())
+    }
+
+    fn write_chunk(&mut self, chunk: &[u8]) -> Result<(), Error> {
+        if !self.tracker.check() {
+            return Err(Error::Cancelled);
+        }
+        self.inner.write_chunk(chunk)?;
+        self.tracker.advance(chunk.len());
+        Ok(())
+    }
+}
+
+struct MonitoredSource<'s, 'm, 'a> {
+    inner: &'s mut dyn Read,
+    tracker: Tracker<'m, 'a>,
+}
+
+impl<'s, 'm, 'a> Read for MonitoredSource<'s, 'm, 'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.tracker.check() {
+            return Err(io::Error::other("transfer cancelled"));
+        }
+        let n = self.inner.read(buf)?;
+        self.tracker.advance(n);
+        Ok(n)
+    }
+}
+
+impl<T: PtpTransport> PtpCamera<T> {
+    /// `command_into`, reporting progress of the data-in phase to `monitor` and aborting
+    /// the transaction with `Error::Cancelled` if its cancel handle fires.
+    pub fn command_into_monitored(&mut self,
+                                  code: CommandCode,
+                                  params: &[u32],
+                                  sink: &mut dyn DataSink,
+                                  monitor: &mut TransferMonitor,
+                                  timeout: Option<Duration>)
+                                  -> Result<PtpResponse, Error> {
+        if monitor.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        let timeout = timeout.unwrap_or(Duration::new(0, 0));
+
+        let tid = self.begin_transaction(code, params, timeout)?;
+        let mut sink = MonitoredSink { inner: sink, tracker: Tracker::new(monitor, None), begun: false };
+        match self.finish_transaction_into(tid, &mut sink, timeout) {
+            Err(_) if sink.tracker.tripped => Err(self.abort_transaction(tid, timeout)),
+            result => result,
+        }
+    }
+
+    /// `command_from`, reporting progress of the data-out phase to `monitor` and aborting
+    /// the transaction with `Error::Cancelled` if its cancel handle fires.
+    pub fn command_from_monitored(&mut self,
+                                  code: CommandCode,
+                                  params: &[u32],
+                                  source: &mut dyn Read,
+                                  len: u64,
+                                  monitor: &mut TransferMonitor,
+                                  timeout: Option<Duration>)
+                                  -> Result<PtpResponse, Error> {
+        if monitor.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        let timeout = timeout.unwrap_or(Duration::new(0, 0));
+
+        let tid = self.begin_transaction(code, params, timeout)?;
+        let mut source = MonitoredSource { inner: source, tracker: Tracker::new(monitor, Some(len)) };
+        source.tracker.advance(0);
+        match self.transport.write_container_from(PtpContainerType::Data, code, tid, len, &mut source, timeout) {
+            // the transport has already cancelled the transaction when its source failed
+            Err(_) if source.tracker.tripped => return Err(self.settle_transaction(tid, timeout)),
+            result => result?,
+        }
+        self.finish_transaction(tid, timeout)
+    }
+
+    /// `get_object_to` with progress reporting and cancellation
+    pub fn get_object_monitored<W: Write>(&mut self,
+                                          handle: u32,
+                                          sink: &mut W,
+                                          monitor: &mut TransferMonitor,
+                                          timeout: Option<Duration>)
+                                          -> Result<(), Error> {
+        self.command_into_monitored(StandardCommandCode::GetObject, &[handle], sink, monitor, timeout)?.check()?;
+        Ok(())
+    }
+
+    /// `send_object_from` with progress reporting and cancellation. If the upload is
+    /// cancelled, the responder may be left holding the announced but empty object.
+    #[allow(clippy::too_many_arguments)]
+    pub fn send_object_monitored<R: Read>(&mut self,
+                                          storage_id: u32,
+                                          parent: u32,
+                                          info: &PtpObjectInfo,
+                                          source: &mut R,
+                                          len: u64,
+                                          monitor: &mut TransferMonitor,
+                                          timeout: Option<Duration>)
+                                          -> Result<u32, Error> {
+        if monitor.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+
+        let mut info = info.clone();
+        info.ObjectCompressedSize = min(len, 0xFFFFFFFF) as u32;
+
+        let (_, _, handle) = self.send_object_info(storage_id, parent, &info, timeout)?;
+        self.command_from_monitored(StandardCommandCode::SendObject, &[], source, len, monitor, timeout)?.check()?;
+        Ok(handle)
+    }
+
+    // Cancel transaction `tid` part way through its data phase: send the Cancel request,
+    // discard what is left of the data phase, and wait for the responder to stop reporting
+    // DeviceBusy. Returns the error to report, which is `Error::Cancelled` unless the
+    // clean-up itself failed.
+    fn abort_transaction(&mut self, tid: u32, timeout: Duration) -> Error {
+        debug!("cancelling transaction {}", tid);
+        if let Err(e) = self.transport.cancel(tid, timeout) {
+            warn!("failed to clean up after cancelling transaction {}: {}", tid, e);
+            return e;
+        }
+        self.settle_transaction(tid, timeout)
+    }
+
+    // The rest of `abort_transaction`, once the Cancel request has been sent
+    fn settle_transaction(&mut self, tid: u32, timeout: Duration) -> Error {
+        let result = self.transport.drain(Duration::from_millis(DRAIN_TIMEOUT_MS))
+            .and_then(|_| {
+                for _ in 0..BUSY_POLL_ATTEMPTS {
+                    let (code, _) = self.transport.status(timeout)?;
+                    if code != StandardResponseCode::DeviceBusy {
+                        return Ok(());
+                    }
+                    thread::sleep(Duration::from_millis(BUSY_POLL_INTERVAL_MS));
+                }
+                Err(Error::Response(StandardResponseCode::DeviceBusy))
+            });
+
+        match result {
+            Ok(()) => Error::Cancelled,
+            Err(e) => {
+                warn!("failed to clean up after cancelling transaction {}: {}", tid, e);
+                e
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::mock::MockTransport;
+
+    // a camera whose GetObject of handle 5 has its data phase split over two containers, with
+    // a GetStorageIDs scripted after it to check the session is still usable
+    fn camera() -> PtpCamera<MockTransport> {
+        let mut mock = MockTransport::new();
+        mock.expect(StandardCommandCode::GetObject, &[5]).reply_data(b"hello ").reply_data(b"world");
+        mock.expect(StandardCommandCode::GetStorageIDs, &[]).reply_data(&[1, 0, 0, 0, 1, 0, 1, 0]);
+        PtpCamera::with_transport(mock)
+    }
+
+    // cancel as soon as the first chunk has arrived
+    fn cancel_after_first_chunk(cancel: &CancelHandle) -> TransferMonitor<'static> {
+        let trigger = cancel.clone();
+        TransferMonitor::new()
+            .on_progress(move |p| if p.done > 0 { trigger.cancel() })
+            .cancel_handle(cancel.clone())
+    }
+
+    #[test]
+    fn progress_over_split_data_phase() {
+        let mut camera = camera();
+        let mut reports = vec![];
+        {
+            let mut monitor = TransferMonitor::new().on_progress(|p| reports.push((p.done, p.total)));
+            let mut out = vec![];
+            camera.get_object_monitored(5, &mut out, &mut monitor, None).unwrap();
+            assert_eq!(out, b"hello world");
+        }
+        // the second container adds to the total rather than restarting it
+        assert_eq!(reports, vec![(0, Some(6)), (6, Some(6)), (6, Some(11)), (11, Some(11))]);
+    }
+
+    #[test]
+    fn cancel_data_in() {
+        let mut camera = camera();
+        let cancel = CancelHandle::new();
+        let mut monitor = cancel_after_first_chunk(&cancel);
+
+        let mut out = vec![];
+        match camera.get_object_monitored(5, &mut out, &mut monitor, None) {
+            Err(Error::Cancelled) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        assert_eq!(out, b"hello ");
+        assert_eq!(camera.transport().cancelled(), &[0]);
+
+        // the drain discarded the second data container and the response, so the next
+        // transaction reads its own
+        assert_eq!(camera.get_storageids(None).unwrap(), vec![0x00010001]);
+        camera.transport().verify();
+    }
+
+    #[test]
+    fn cancel_data_out() {
+        let mut mock = MockTransport::new();
+        mock.expect(StandardCommandCode::SendObject, &[]);
+        let mut camera = PtpCamera::with_transport(mock);
+        let cancel = CancelHandle::new();
+        let mut monitor = cancel_after_first_chunk(&cancel);
+
+        // the source is read in two parts, the second is refused
+        let mut source = (&b"hello "[..]).chain(&b"world"[..]);
+        match camera.command_from_monitored(StandardCommandCode::SendObject, &[], &mut source, 11, &mut monitor, None) {
+            Err(Error::Cancelled) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        // cancelled once, by the transport
+        assert_eq!(camera.transport().cancelled(), &[0]);
+    }
+
+    #[test]
+    fn cancel_waits_while_busy() {
+        let mut camera = camera();
+        camera.transport().push_status(StandardResponseCode::DeviceBusy, &[]);
+        camera.transport().push_status(StandardResponseCode::DeviceBusy, &[]);
+        let cancel = CancelHandle::new();
+        let mut monitor = cancel_after_first_chunk(&cancel);
+
+        match camera.get_object_monitored(5, &mut vec![], &mut monitor, None) {
+            Err(Error::Cancelled) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        // both busy reports were polled through before the Ok
+        assert_eq!(camera.transport().status(Duration::new(0, 0)).unwrap().0, StandardResponseCode::Ok);
+        assert_eq!(camera.get_storageids(None).unwrap(), vec![0x00010001]);
+    }
+
+    #[test]
+    fn cancelled_before_start() {
+        let mut camera = camera();
+        let cancel = CancelHandle::new();
+        cancel.cancel();
+        let mut monitor = TransferMonitor::new().cancel_handle(cancel);
+
+        assert!(matches!(camera.get_object_monitored(5, &mut vec![], &mut monitor, None), Err(Error::Cancelled)));
+        assert!(camera.transport().cancelled().is_empty());
+    }
+}
@@ -0,0 +1,562 @@
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+use std::io::prelude::*;
+use std::io::Cursor;
+use std::time::Duration;
+use std::slice;
+use std::cmp::min;
+use rusb::UsbContext;
+
+use super::{Error, PtpContainerInfo, PtpContainerType, PtpTransport, DataSink, CommandCode, ResponseCode,
+            StandardCommandCode, PTP_CONTAINER_INFO_SIZE};
+
+// Still Image class-specific requests, see the PTP USB transport spec, section 5.2
+const PTP_CLASS_CANCEL_REQUEST: u8 = 0x64;
+const PTP_CLASS_DEVICE_RESET: u8 = 0x66;
+const PTP_CLASS_GET_DEVICE_STATUS: u8 = 0x67;
+
+// cancellation code carried in the Cancel Request data
+const PTP_CANCELLATION_CODE: u16 = 0x4001;
+
+// largest bulk read while streaming a data phase, must be a multiple of the endpoint packet size
+const STREAM_CHUNK_SIZE: usize = 1024 * 1024;
+
+// size of the first read of a container, enough for most command and control data (ie, not
+// media) without allocating. larger payloads are read separately.
+const FIRST_READ_SIZE: usize = 8 * 1024;
+
+// how long to wait for string descriptors while identifying devices
+const STRING_TIMEOUT_SECS: u64 = 1;
+
+/// A USB device with a PTP interface, as found by `UsbDeviceInfo::list`
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsbDeviceInfo {
+    pub bus: u8,
+    pub address: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// the strings are `None` if the device could not be opened to read them, typically
+    /// for lack of permissions, or doesn't provide them
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial: Option<String>,
+    /// number of the PTP interface
+    pub interface: u8,
+    /// whether the interface is an MTP one, advertised with a vendor-specific class
+    pub mtp: bool,
+}
+
+impl UsbDeviceInfo {
+    /// Find every attached device with a PTP or MTP interface.
+    pub fn list(context: &rusb::Context) -> Result<Vec<UsbDeviceInfo>, Error> {
+        let mut found = vec![];
+        for device in context.devices()?.iter() {
+            match UsbDeviceInfo::identify(&device) {
+                Ok(Some(info)) => found.push(info),
+                Ok(None) => {}
+                Err(e) => debug!("skipping device {}:{}: {}", device.bus_number(), device.address(), e),
+            }
+        }
+        Ok(found)
+    }
+
+    /// Describe `device`, or return `None` if it has no PTP interface. Only devices whose
+    /// configuration descriptor shows a possible PTP interface are opened to read their strings.
+    ///
+    /// A device that can't be opened, typically for lack of permissions, is described without
+    /// its strings, and an MTP device that doesn't also advertise the PTP class isn't recognised.
+    pub fn identify(device: &rusb::Device<rusb::Context>) -> Result<Option<UsbDeviceInfo>, Error> {
+        let identity = UsbDeviceInfo::probe(device)?;
+        if let Some(e) = identity.open_error {
+            debug!("can't open device {}:{} to identify it: {}", device.bus_number(), device.address(), e);
+        }
+        Ok(identity.info)
+    }
+
+    // `identify`, also returning why the device couldn't be opened, if it couldn't
+    pub(crate) fn probe(device: &rusb::Device<rusb::Context>) -> Result<Identity, Error> {
+        let desc = device.device_descriptor()?;
+        if !may_have_interface(device)? {
+            return Ok(Identity { info: None, open_error: None });
+        }
+        let (handle, open_error) = match device.open() {
+            Ok(handle) => (Some(handle), None),
+            Err(e) => (None, Some(e)),
+        };
+        let iface = match find_interface(device, handle.as_ref())? {
+            Some(iface) => iface,
+            None => return Ok(Identity { info: None, open_error }),
+        };
+
+        let timeout = Duration::from_secs(STRING_TIMEOUT_SECS);
+        let language = handle.as_ref()
+            .and_then(|h| h.read_languages(timeout).ok())
+            .and_then(|l| l.first().cloned());
+        let string = |read: &dyn Fn(&rusb::DeviceHandle<rusb::Context>, rusb::Language) -> rusb::Result<String>| {
+            match (handle.as_ref(), language) {
+                (Some(h), Some(lang)) => read(h, lang).ok(),
+                _ => None,
+            }
+        };
+
+        let info = UsbDeviceInfo {
+            bus: device.bus_number(),
+            address: device.address(),
+            vendor_id: desc.vendor_id(),
+            product_id: desc.product_id(),
+            manufacturer: string(&|h, lang| h.read_manufacturer_string(lang, &desc, timeout)),
+            product: string(&|h, lang| h.read_product_string(lang, &desc, timeout)),
+            serial: string(&|h, lang| h.read_serial_number_string(lang, &desc, timeout)),
+            interface: iface.number,
+            mtp: iface.mtp,
+        };
+        Ok(Identity { info: Some(info), open_error })
+    }
+
+    /// Open this device, which must still be attached at the same bus and address.
+    pub fn open(&self, context: &rusb::Context) -> Result<UsbTransport, Error> {
+        UsbTransport::open(context, &UsbDeviceSelector::BusAddress(self.bus, self.address))
+    }
+}
+
+// What `UsbDeviceInfo::probe` learnt about a device. Without an open handle the description is
+// provisional: the strings are missing, and an MTP interface can't be recognised at all.
+#[derive(Debug)]
+pub(crate) struct Identity {
+    pub info: Option<UsbDeviceInfo>,
+    pub open_error: Option<rusb::Error>,
+}
+
+/// Which device `UsbTransport::open` should pick
+#[derive(Debug, Clone, PartialEq)]
+pub enum UsbDeviceSelector {
+    /// the first PTP device found
+    Any,
+    /// the device with this serial number string
+    Serial(String),
+    /// the first device with this vendor and product ID
+    VidPid(u16, u16),
+    /// the device at this bus number and address
+    BusAddress(u8, u8),
+}
+
+impl UsbDeviceSelector {
+    pub fn matches(&self, info: &UsbDeviceInfo) -> bool {
+        match *self {
+            UsbDeviceSelector::Any => true,
+            UsbDeviceSelector::Serial(ref serial) => info.serial.as_ref() == Some(serial),
+            UsbDeviceSelector::VidPid(vid, pid) => info.vendor_id == vid && info.product_id == pid,
+            UsbDeviceSelector::BusAddress(bus, address) => info.bus == bus && info.address == address,
+        }
+    }
+}
+
+/// PTP over USB, using the bulk endpoints for transactions and the interrupt endpoint for events
+pub struct UsbTransport {
+    iface: u8,
+    ep_in: u8,
+    ep_out: u8,
+    ep_int: u8,
+    // wMaxPacketSize of the bulk out endpoint
+    out_packet_size: u16,
+    handle: rusb::DeviceHandle<rusb::Context>,
+}
+
+impl UsbTransport {
+    /// Open the PTP interface of `device`: the first with the still image class, or failing
+    /// that, a vendor-specific one named "MTP".
+    pub fn new(device: &rusb::Device<rusb::Context>) -> Result<UsbTransport, Error> {
+        let handle = device.open()?;
+        let iface = find_interface(device, Some(&handle))?.ok_or(rusb::Error::NotFound)?;
+
+        debug!("Found interface {}{}", iface.number, if iface.mtp { " (MTP)" } else { "" });
+
+        handle.claim_interface(iface.number)?;
+        handle.set_alternate_setting(iface.number, iface.setting)?;
+
+        Ok(UsbTransport {
+            iface: iface.number,
+            ep_in: iface.ep_in,
+            ep_out: iface.ep_out,
+            ep_int: iface.ep_int,
+            out_packet_size: iface.out_packet_size,
+            handle,
+        })
+    }
+
+    /// Open the first attached PTP device matching `selector`.
+    pub fn open(context: &rusb::Context, selector: &UsbDeviceSelector) -> Result<UsbTransport, Error> {
+        for device in context.devices()?.iter() {
+            // skip reading strings unless the selector needs them
+            let matched = match *selector {
+                UsbDeviceSelector::BusAddress(bus, address) => device.bus_number() == bus && device.address() == address,
+                UsbDeviceSelector::VidPid(vid, pid) => {
+                    let desc = device.device_descriptor()?;
+                    desc.vendor_id() == vid && desc.product_id() == pid
+                }
+                _ => true,
+            };
+            if !matched {
+                continue;
+            }
+
+            match UsbDeviceInfo::identify(&device) {
+                Ok(Some(ref info)) if selector.matches(info) => return UsbTransport::new(&device),
+                Ok(_) => {}
+                Err(e) => debug!("skipping device {}:{}: {}", device.bus_number(), device.address(), e),
+            }
+        }
+        Err(Error::Usb(rusb::Error::NoDevice))
+    }
+
+    // collect the payload of a container whose first transfer was `first`. `filled` means
+    // that transfer used the whole buffer, so there may be more to read.
+    fn read_payload(&mut self, cinfo: &PtpContainerInfo, first: &[u8], filled: bool, timeout: Duration) -> Result<Vec<u8>, Error> {
+        // no payload? we're done
+        if cinfo.payload_len == 0 {
+            return Ok(vec![]);
+        }
+
+        // allocate one extra to avoid a separate read for trailing short packet
+        let mut payload = Vec::with_capacity(cinfo.payload_len + 1);
+        payload.extend_from_slice(first);
+
+        // response didn't fit into our original buf? read the rest
+        // or if our original read were satisfied exactly, so there is still a ZLP to read
+        if payload.len() < cinfo.payload_len || filled {
+            unsafe {
+                let p = payload.as_mut_ptr().offset(payload.len() as isize);
+                let pslice = slice::from_raw_parts_mut(p, payload.capacity() - payload.len());
+                let n = self.handle.read_bulk(self.ep_in, pslice, timeout)?;
+                let sz = payload.len();
+                payload.set_len(sz + n);
+                trace!("  bulk rx {}, ({}/{})", n, payload.len(), payload.capacity());
+            }
+        }
+
+        Ok(payload)
+    }
+
+    // read the first transfer of a container into `buf` and parse its header, returning the
+    // header and the number of bytes read
+    fn read_header(&self, buf: &mut [u8], timeout: Duration) -> Result<(PtpContainerInfo, usize), Error> {
+        let n = self.handle.read_bulk(self.ep_in, buf, timeout)?;
+        let cinfo = PtpContainerInfo::parse(&buf[..n])?;
+        trace!("container {:?}", cinfo);
+        Ok((cinfo, n))
+    }
+
+    fn class_request_type(direction: rusb::Direction) -> u8 {
+        rusb::request_type(direction, rusb::RequestType::Class, rusb::Recipient::Interface)
+    }
+}
+
+impl PtpTransport for UsbTransport {
+    fn write_container(&mut self, kind: PtpContainerType, code: CommandCode, tid: u32, payload: &[u8], timeout: Duration) -> Result<(), Error> {
+        trace!("Write {:?} - 0x{:04x} ({}), tid:{}", kind, code, StandardCommandCode::name(code).unwrap_or("unknown"), tid);
+
+        const CHUNK_SIZE: usize = 1024 * 1024; // 1MB, must be a multiple of the endpoint packet size
+
+        let total = (payload.len() + PTP_CONTAINER_INFO_SIZE) as u64;
+        let mut writes = bulk_writes(total, CHUNK_SIZE, self.out_packet_size);
+
+        // The first chunk contains the header, and its payload must be copied into the temporary buffer
+        let first_chunk_payload_bytes = writes.next().unwrap_or(0) - PTP_CONTAINER_INFO_SIZE;
+        let mut buf = Vec::with_capacity(first_chunk_payload_bytes + PTP_CONTAINER_INFO_SIZE);
+        buf.write_u32::<LittleEndian>(container_len(payload.len() as u64)).ok();
+        buf.write_u16::<LittleEndian>(kind as u16).ok();
+        buf.write_u16::<LittleEndian>(code).ok();
+        buf.write_u32::<LittleEndian>(tid).ok();
+        buf.extend_from_slice(&payload[..first_chunk_payload_bytes]);
+        self.handle.write_bulk(self.ep_out, &buf, timeout)?;
+
+        // Write any subsequent chunks, and the zero-length packet if needed, straight from the source slice
+        let mut offset = first_chunk_payload_bytes;
+        for n in writes {
+            self.handle.write_bulk(self.ep_out, &payload[offset..offset + n], timeout)?;
+            offset += n;
+        }
+
+        Ok(())
+    }
+
+    fn write_container_from(&mut self,
+                            kind: PtpContainerType,
+                            code: CommandCode,
+                            tid: u32,
+                            len: u64,
+                            source: &mut dyn Read,
+                            timeout: Duration)
+                            -> Result<(), Error> {
+        trace!("Write {:?} - 0x{:04x} ({}), tid:{}, {} bytes streamed", kind, code,
+               StandardCommandCode::name(code).unwrap_or("unknown"), tid, len);
+
+        // every write but the last is a full chunk, the first one starting with the header
+        let mut buf = Vec::with_capacity(min(len + PTP_CONTAINER_INFO_SIZE as u64, STREAM_CHUNK_SIZE as u64) as usize);
+        buf.write_u32::<LittleEndian>(container_len(len)).ok();
+        buf.write_u16::<LittleEndian>(kind as u16).ok();
+        buf.write_u16::<LittleEndian>(code).ok();
+        buf.write_u32::<LittleEndian>(tid).ok();
+
+        for n in bulk_writes(len + PTP_CONTAINER_INFO_SIZE as u64, STREAM_CHUNK_SIZE, self.out_packet_size) {
+            let header_len = buf.len();
+            buf.resize(n, 0);
+            if let Err(e) = source.read_exact(&mut buf[header_len..]) {
+                // the responder would otherwise wait for the rest of the data phase
+                if let Err(e) = self.cancel(tid, timeout) {
+                    warn!("failed to cancel transaction {} after its data source failed: {}", tid, e);
+                }
+                return Err(e.into());
+            }
+
+            self.handle.write_bulk(self.ep_out, &buf, timeout)?;
+            buf.clear();
+        }
+        Ok(())
+    }
+
+    // retrieve container info and payload for the current phase
+    fn read_container(&mut self, timeout: Duration) -> Result<(PtpContainerInfo, Vec<u8>), Error> {
+        let mut buf = [0u8; FIRST_READ_SIZE];
+        let (cinfo, n) = self.read_header(&mut buf, timeout)?;
+        let filled = n == buf.len();
+        let buf = &buf[..n];
+
+        let payload = self.read_payload(&cinfo, &buf[PTP_CONTAINER_INFO_SIZE..], filled, timeout)?;
+        Ok((cinfo, payload))
+    }
+
+    fn read_container_into(&mut self, sink: &mut dyn DataSink, timeout: Duration) -> Result<(PtpContainerInfo, Vec<u8>), Error> {
+        let mut buf = [0u8; FIRST_READ_SIZE];
+        let (cinfo, n) = self.read_header(&mut buf, timeout)?;
+        let filled = n == buf.len();
+        let buf = &buf[..n];
+
+        if cinfo.kind != PtpContainerType::Data {
+            let payload = self.read_payload(&cinfo, &buf[PTP_CONTAINER_INFO_SIZE..], filled, timeout)?;
+            return Ok((cinfo, payload));
+        }
+
+        let total = cinfo.payload_len;
+        let first = &buf[PTP_CONTAINER_INFO_SIZE..];
+        let first = &first[..min(first.len(), total)];
+        sink.begin(total as u64)?;
+        sink.write_chunk(first)?;
+
+        // stream the rest in chunks. like in read_container, ask for one byte more than remains,
+        // so the final read also consumes the trailing short packet or ZLP.
+        let mut chunk = vec![0u8; min(total - first.len() + 1, STREAM_CHUNK_SIZE)];
+        let mut received = first.len();
+        let mut zlp_pending = filled;
+        while received < total || zlp_pending {
+            zlp_pending = false;
+            let want = min(total - received + 1, STREAM_CHUNK_SIZE);
+            let n = self.handle.read_bulk(self.ep_in, &mut chunk[..want], timeout)?;
+            trace!("  bulk rx {}, ({}/{})", n, received + n, total);
+
+            if n == 0 && received < total {
+                return Err(Error::Malformed(format!("Data phase ended after {} of {} bytes", received, total)));
+            }
+            sink.write_chunk(&chunk[..min(n, total - received)])?;
+            received += n;
+        }
+
+        Ok((cinfo, vec![]))
+    }
+
+    fn read_event(&mut self, timeout: Duration) -> Result<Option<(PtpContainerInfo, Vec<u8>)>, Error> {
+        // an event container is at most a header plus three parameters, but leave room for
+        // a full-speed packet in case the device pads it
+        let mut buf = [0u8; 64];
+        let n = match self.handle.read_interrupt(self.ep_int, &mut buf[..], timeout) {
+            Ok(n) => n,
+            Err(rusb::Error::Timeout) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let cinfo = PtpContainerInfo::parse(&buf[..n])?;
+        trace!("event container {:?}", cinfo);
+
+        let end = min(n, PTP_CONTAINER_INFO_SIZE + cinfo.payload_len);
+        Ok(Some((cinfo, buf[PTP_CONTAINER_INFO_SIZE..end].to_vec())))
+    }
+
+    fn cancel(&mut self, tid: u32, timeout: Duration) -> Result<(), Error> {
+        let mut data = Vec::with_capacity(6);
+        data.write_u16::<LittleEndian>(PTP_CANCELLATION_CODE).ok();
+        data.write_u32::<LittleEndian>(tid).ok();
+
+        let request_type = UsbTransport::class_request_type(rusb::Direction::Out);
+        self.handle.write_control(request_type, PTP_CLASS_CANCEL_REQUEST, 0, self.iface as u16, &data, timeout)?;
+        Ok(())
+    }
+
+    fn reset(&mut self, timeout: Duration) -> Result<(), Error> {
+        let request_type = UsbTransport::class_request_type(rusb::Direction::Out);
+        self.handle.write_control(request_type, PTP_CLASS_DEVICE_RESET, 0, self.iface as u16, &[], timeout)?;
+        Ok(())
+    }
+
+    fn status(&mut self, timeout: Duration) -> Result<(ResponseCode, Vec<u32>), Error> {
+        let mut buf = [0u8; 64];
+        let request_type = UsbTransport::class_request_type(rusb::Direction::In);
+        let n = self.handle.read_control(request_type, PTP_CLASS_GET_DEVICE_STATUS, 0, self.iface as u16, &mut buf[..], timeout)?;
+
+        // wLength, then the status code, then any parameters
+        let mut cur = Cursor::new(&buf[..n]);
+        let len = cur.read_u16::<LittleEndian>()? as usize;
+        let code = cur.read_u16::<LittleEndian>()?;
+        let count = min(len, n).saturating_sub(4) / 4;
+        let params = (0..count)
+            .map(|_| cur.read_u32::<LittleEndian>())
+            .collect::<Result<_, _>>()?;
+
+        Ok((code, params))
+    }
+
+    fn close(&mut self) -> Result<(), Error> {
+        self.handle.release_interface(self.iface)?;
+        Ok(())
+    }
+
+    fn drain(&mut self, timeout: Duration) -> Result<(), Error> {
+        let mut buf = vec![0u8; STREAM_CHUNK_SIZE];
+        loop {
+            match self.handle.read_bulk(self.ep_in, &mut buf[..], timeout) {
+                Ok(n) => trace!("  drained {}", n),
+                Err(rusb::Error::Timeout) => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+// the length field of a container with `payload_len` bytes of payload. containers of 4GB and over
+// carry 0xFFFFFFFF, the responder relies on the short packet at the end of the transfer instead
+fn container_len(payload_len: u64) -> u32 {
+    min(payload_len + PTP_CONTAINER_INFO_SIZE as u64, 0xFFFFFFFF) as u32
+}
+
+// the PTP interface of a device and its endpoints
+struct PtpInterface {
+    number: u8,
+    setting: u8,
+    ep_in: u8,
+    ep_out: u8,
+    ep_int: u8,
+    out_packet_size: u16,
+    mtp: bool,
+}
+
+// find the still image class interface of `device`, or an MTP one. MTP devices often use the
+// vendor-specific class with an interface string of "MTP", which can only be read with `handle`.
+fn find_interface(device: &rusb::Device<rusb::Context>, handle: Option<&rusb::DeviceHandle<rusb::Context>>) -> Result<Option<PtpInterface>, Error> {
+    let config_desc = device.active_config_descriptor()?;
+    let timeout = Duration::from_secs(STRING_TIMEOUT_SECS);
+    let language = handle.and_then(|h| h.read_languages(timeout).ok()).and_then(|l| l.first().cloned());
+
+    let mut mtp = None;
+    for desc in config_desc.interfaces().flat_map(|i| i.descriptors()) {
+        let (ep_in, ep_out, ep_int, out_packet_size) = match endpoints(&desc) {
+            Some(endpoints) => endpoints,
+            None => continue,
+        };
+        let iface = |mtp| PtpInterface {
+            number: desc.interface_number(),
+            setting: desc.setting_number(),
+            ep_in,
+            ep_out,
+            ep_int,
+            out_packet_size,
+            mtp,
+        };
+
+        if desc.class_code() == 6 {
+            return Ok(Some(iface(false)));
+        }
+        if desc.class_code() == 0xFF && mtp.is_none() {
+            if let (Some(handle), Some(lang)) = (handle, language) {
+                if handle.read_interface_string(lang, &desc, timeout).ok().as_deref() == Some("MTP") {
+                    mtp = Some(iface(true));
+                }
+            }
+        }
+    }
+    Ok(mtp)
+}
+
+// whether `device` may have a PTP interface, judging by its configuration descriptor alone:
+// one of the still image class, or a vendor-specific one that might turn out to be MTP. this
+// spares opening every other device on the bus.
+fn may_have_interface(device: &rusb::Device<rusb::Context>) -> Result<bool, Error> {
+    let config_desc = device.active_config_descriptor()?;
+    let found = config_desc.interfaces()
+        .flat_map(|i| i.descriptors())
+        .any(|desc| (desc.class_code() == 6 || desc.class_code() == 0xFF) && endpoints(&desc).is_some());
+    Ok(found)
+}
+
+// the bulk in, bulk out and interrupt in endpoints of an interface, if it has all three, along
+// with the bulk out endpoint's maximum packet size
+fn endpoints(desc: &rusb::InterfaceDescriptor) -> Option<(u8, u8, u8, u16)> {
+    let find_endpoint = |direction, transfer_type| {
+        desc.endpoint_descriptors()
+            .find(|ep| ep.direction() == direction && ep.transfer_type() == transfer_type)
+    };
+    match (find_endpoint(rusb::Direction::In, rusb::TransferType::Bulk),
+           find_endpoint(rusb::Direction::Out, rusb::TransferType::Bulk),
+           find_endpoint(rusb::Direction::In, rusb::TransferType::Interrupt)) {
+        (Some(ep_in), Some(ep_out), Some(ep_int)) => {
+            Some((ep_in.address(), ep_out.address(), ep_int.address(), ep_out.max_packet_size()))
+        }
+        _ => None,
+    }
+}
+
+// The sizes of the bulk writes carrying a container of `total` bytes, header included: full
+// chunks of `chunk` bytes, then whatever is left. The responder takes a short packet as the end
+// of the transfer, so a transfer that ends on a packet boundary is followed by a zero-length
+// write; a container of 4GB or more can't say its length, so it relies on this entirely.
+fn bulk_writes(total: u64, chunk: usize, packet_size: u16) -> impl Iterator<Item = usize> {
+    let full = total / chunk as u64;
+    let rest = (total % chunk as u64) as usize;
+    let zlp = match total.checked_rem(packet_size as u64) {
+        Some(partial) => partial == 0,
+        None => false,
+    };
+    (0..full).map(move |_| chunk)
+        .chain(if rest > 0 { Some(rest) } else { None })
+        .chain(if zlp { Some(0) } else { None })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bulk_write_sizes() {
+        let writes = |total, chunk, packet| bulk_writes(total, chunk, packet).collect::<Vec<_>>();
+
+        // a short last packet ends the transfer by itself
+        assert_eq!(writes(12, 1024, 512), vec![12]);
+        assert_eq!(writes(2500, 1024, 512), vec![1024, 1024, 452]);
+        // ending on a packet boundary needs a zero-length packet, even within a chunk
+        assert_eq!(writes(512, 1024, 512), vec![512, 0]);
+        assert_eq!(writes(2048, 1024, 512), vec![1024, 1024, 0]);
+        assert_eq!(writes(1536, 1024, 64), vec![1024, 512, 0]);
+        // past 4GB
+        let total = 0x1_0000_0000u64 + 500;
+        let sizes = writes(total, 1024 * 1024, 512);
+        assert_eq!(sizes.iter().map(|&n| n as u64).sum::<u64>(), total);
+        assert_ne!(sizes.last(), Some(&0));
+        assert_eq!(writes(0x1_0000_0000, 1024 * 1024, 512).last(), Some(&0));
+    }
+
+    #[test]
+    fn container_lengths() {
+        assert_eq!(container_len(0), 12);
+        assert_eq!(container_len(0xFFFF_FFF3), 0xFFFF_FFFF);
+        // too long for the field, rather than wrapping to a small length
+        assert_eq!(container_len(0xFFFF_FFF4), 0xFFFF_FFFF);
+        assert_eq!(container_len(0x1_0000_0000 + 500), 0xFFFF_FFFF);
+    }
+}